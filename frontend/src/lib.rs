@@ -1,8 +1,8 @@
-use gloo_net::http::Request;
+use gloo_net::http::{Request, Response};
 use serde::Deserialize;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_futures::spawn_local;
-use web_sys::{HtmlInputElement, Event}; 
+use web_sys::{Element, HtmlInputElement, Event}; 
 use yew::prelude::*;
 use gloo::utils::window;
 use gloo_storage::{Storage, LocalStorage};
@@ -13,33 +13,262 @@ use web_sys::MessageEvent;
 use wasm_bindgen::closure::Closure;
 use gloo::timers::future::TimeoutFuture;
 use chrono;
+use std::collections::BTreeMap;
 
 #[derive(PartialEq, Clone, Debug, Deserialize)] 
 struct Channel {
     id: u32,
     name: String,
     owner: String,
+    #[serde(default)]
+    private: bool,
+    #[serde(default)]
+    topic: String,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    archived: bool,
+}
+
+/// Channel settings that can change while the room is open, see `ChannelInfo` in `src/channel.rs`
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+struct ChannelInfo {
+    name: String,
+    topic: String,
+    description: String,
+    archived: bool,
+    #[serde(default)]
+    retention_days: Option<i64>,
+    #[serde(default)]
+    retention_count: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct ChannelEnterResponse {
+    owner: String,
+    #[serde(default)]
+    private: bool,
+    #[serde(default)]
+    topic: String,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    archived: bool,
+    #[serde(default)]
+    retention_days: Option<i64>,
+    #[serde(default)]
+    retention_count: Option<i64>,
+    /// The other participant, for direct conversations
+    #[serde(default)]
+    with: Option<String>,
+}
+
+/// The current user's role in a channel, as returned by `/channel/permissions`
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+struct ChannelPermissions {
+    role: String,
+    /// e.g. "delete_messages", "kick", "mute", "invite"
+    permissions: Vec<String>,
+}
+
+impl ChannelPermissions {
+    fn allows(&self, permission: &str) -> bool {
+        self.permissions.iter().any(|allowed| allowed == permission)
+    }
+}
+
+/// A pending invitation to a private channel, as returned by `/channel/invitations`
+#[derive(PartialEq, Clone, Debug, Deserialize)]
+struct Invitation {
+    channel: String,
+    owner: String,
+}
+
+/// A direct conversation, as returned by `/direct/list` and `/direct/open`
+#[derive(PartialEq, Clone, Debug, Deserialize)]
+struct Conversation {
+    id: i64,
+    channel: String,
+    with: String,
+}
+
+/// A message found by `/channel/search`
+#[derive(PartialEq, Clone, Debug, Deserialize)]
+struct SearchResult {
+    channel: String,
+    id: String,
+    timestamp: String,
+    username: String,
+    message: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
 struct ChatMessage {
+    /// Server-assigned message ID, empty for local system notices
+    #[serde(default)]
+    id: String,
     username: String,
     message: String,
     timestamp: String,
+    #[serde(default)]
+    edited_at: Option<String>,
+    #[serde(default)]
+    reply_to: Option<String>,
+    #[serde(default)]
+    reply_count: usize,
+    #[serde(default)]
+    last_reply_at: Option<String>,
+    /// Emoji -> users who reacted with it
+    #[serde(default)]
+    reactions: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    system: bool,
+}
+
+/// A message with the replies in its thread, as returned by `/channel/thread`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+struct Thread {
+    parent: ChatMessage,
+    replies: Vec<ChatMessage>,
 }
 
+/// Number of messages fetched per `/channel/history` page
+const HISTORY_PAGE_SIZE: usize = 50;
+
+/// Reactions offered under every message, in addition to those already used
+const QUICK_REACTIONS: [&str; 4] = ["👍", "❤️", "😂", "🎉"];
+
 #[derive(Serialize, Deserialize, Debug)]
 struct MessageRequest {
     content: String,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct UserStatus {
     pub username: String,
     pub status: String,
     pub timestamp: String,
 }
 
+/// Mirrors `PROTOCOL_VERSION` in the server's `src/protocol.rs`
+const PROTOCOL_VERSION: u8 = 1;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Envelope<T> {
+    v: u8,
+    #[serde(flatten)]
+    event: T,
+}
+
+impl<T> Envelope<T> {
+    fn new(event: T) -> Self {
+        Self { v: PROTOCOL_VERSION, event }
+    }
+}
+
+/// Events received from the server, see `ServerEvent` in `src/protocol.rs`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerEvent {
+    Message(ChatMessage),
+    Edited(ChatMessage),
+    Deleted { id: String, deleted_by: String, reply_to: Option<String> },
+    Reactions { id: String, reactions: BTreeMap<String, Vec<String>> },
+    ChannelUpdated(ChannelInfo),
+    ChannelDeleted { name: String },
+    Join { username: String },
+    Leave { username: String },
+    Presence { users: Vec<UserStatus> },
+    Typing { username: String },
+    Ack { client_id: Option<String>, id: String, timestamp: String },
+    Error { message: String },
+    Pong,
+}
+
+/// Events sent to the server, see `ClientEvent` in `src/protocol.rs`
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientEvent {
+    Message { message: String, client_id: Option<String>, reply_to: Option<String> },
+    Edit { id: String, message: String },
+    Delete { id: String },
+    AddReaction { id: String, emoji: String },
+    RemoveReaction { id: String, emoji: String },
+    Kick { username: String },
+    Mute { username: String, muted: bool },
+    Typing,
+    Ping,
+}
+
+/// Tokens of a JWT login, see `/user/login` in api.md. Kept in local storage so the
+/// frontend works without cookies when it runs on another origin.
+#[derive(Serialize, Deserialize)]
+struct AuthTokens {
+    access_token: String,
+    refresh_token: String,
+}
+
+const AUTH_TOKENS_KEY: &str = "auth_tokens";
+
+fn auth_tokens() -> Option<AuthTokens> {
+    LocalStorage::get(AUTH_TOKENS_KEY).ok()
+}
+
+/// Trade the stored refresh token for a new pair. Returns false if there is none or it was refused.
+async fn refresh_tokens() -> bool {
+    let tokens = match auth_tokens() {
+        Some(tokens) => tokens,
+        None => return false,
+    };
+    let response = Request::post("http://localhost:8080/user/token/refresh")
+        .header("Content-Type", "application/json")
+        .json(&serde_json::json!({ "refresh_token": tokens.refresh_token }))
+        .unwrap()
+        .send()
+        .await;
+
+    match response {
+        Ok(resp) if resp.ok() => match resp.json::<AuthTokens>().await {
+            Ok(tokens) => LocalStorage::set(AUTH_TOKENS_KEY, tokens).is_ok(),
+            Err(_) => false,
+        },
+        _ => {
+            LocalStorage::delete(AUTH_TOKENS_KEY);
+            false
+        }
+    }
+}
+
+/// Send the request built by `build` with the stored access token. If it is refused,
+/// usually because it expired, refresh the tokens and send it once more.
+async fn send_authorized(build: impl Fn() -> Result<Request, gloo_net::Error>) -> Result<Response, gloo_net::Error> {
+    let send = || async {
+        let request = build()?;
+        if let Some(tokens) = auth_tokens() {
+            request.headers().set("Authorization", &format!("Bearer {}", tokens.access_token));
+        }
+        request.send().await
+    };
+
+    let response = send().await?;
+    if response.status() == 401 && refresh_tokens().await {
+        return send().await;
+    }
+    Ok(response)
+}
+
+/// Revoke the stored tokens and forget them
+async fn log_out() -> Result<Response, gloo_net::Error> {
+    let refresh_token = auth_tokens().map(|tokens| tokens.refresh_token);
+    let response = send_authorized(|| Request::post("http://localhost:8080/user/token/revoke")
+        .header("Content-Type", "application/json")
+        .json(&serde_json::json!({ "refresh_token": refresh_token })))
+        .await;
+    LocalStorage::delete(AUTH_TOKENS_KEY);
+    response
+}
+
 #[function_component(Welcome)]
 fn welcome() -> Html {
     let on_login_click = Callback::from(move |_| {
@@ -86,7 +315,7 @@ fn login() -> Html {
             spawn_local(async move {
                 let response = Request::post("http://localhost:8080/user/login")
                     .header("Content-Type", "application/json")
-                    .json(&serde_json::json!({ "username": username, "password": password }))
+                    .json(&serde_json::json!({ "username": username, "password": password, "mode": "jwt" }))
                     .unwrap()
                     .send()
                     .await;
@@ -94,12 +323,19 @@ fn login() -> Html {
                 match response {
                     Ok(resp) if resp.ok() => {
                         gloo::console::log!("Login successful!");
+                        match resp.json::<AuthTokens>().await {
+                            Ok(tokens) if LocalStorage::set(AUTH_TOKENS_KEY, &tokens).is_ok() => {}
+                            _ => {
+                                error.set("Error Occurred.".to_string());
+                                return;
+                            }
+                        }
+                        if LocalStorage::set("username", username.clone()).is_err() {
+                            gloo::console::log!("Error saving to local storage:");
+                        }
                         error.set(String::new());
                         window().location().set_href("/channel_list").unwrap();
                     }
-                    Ok(resp) if resp.status() == 400 => {
-                        error.set("Already logged in!".to_string());
-                    }
                     Ok(resp) if resp.status() == 401 => {
                         error.set("Invalid username or password.".to_string());
                     }
@@ -250,15 +486,27 @@ fn register() -> Html {
 fn channel_list() -> Html {
     let error = use_state(|| String::new());
     let channels = use_state(|| Vec::new());
+    let conversations = use_state(Vec::<Conversation>::new);
+    let invitations = use_state(Vec::<Invitation>::new);
+    let direct_username = use_state(String::new);
+    let show_archived = use_state(|| false);
+    let search_query = use_state(String::new);
+    let search_results = use_state(|| None::<Vec<SearchResult>>);
 
     use_effect_with_deps({
         let channels = channels.clone();
+        let conversations = conversations.clone();
+        let invitations = invitations.clone();
         let error = error.clone();
 
-        move |_| {
+        move |show_archived: &bool| {
+            let url = if *show_archived {
+                "http://localhost:8080/channel/list?archived=true"
+            } else {
+                "http://localhost:8080/channel/list"
+            };
             spawn_local(async move {
-                let response = Request::get("http://localhost:8080/channel/list")
-                    .send()
+                let response = send_authorized(|| Request::get(url).build())
                     .await;
 
                 match response {
@@ -272,13 +520,30 @@ fn channel_list() -> Html {
                         error.set("Unauthorized!".to_string());
                     }
                 }
+
+                if let Ok(resp) = send_authorized(|| Request::get("http://localhost:8080/direct/list").build()).await {
+                    if let Ok(conversations_data) = resp.json::<Vec<Conversation>>().await {
+                        conversations.set(conversations_data);
+                    }
+                }
+
+                if let Ok(resp) = send_authorized(|| Request::get("http://localhost:8080/channel/invitations").build()).await {
+                    if let Ok(invitations_data) = resp.json::<Vec<Invitation>>().await {
+                        invitations.set(invitations_data);
+                    }
+                }
             });
             || ()
         }
-    }, ());
+    }, *show_archived);
 
     let selected_channel = use_state(|| None::<String>);
 
+    let on_toggle_archived = {
+        let show_archived = show_archived.clone();
+        Callback::from(move |_| show_archived.set(!*show_archived))
+    };
+
     let on_channel_select = {
         let selected_channel = selected_channel.clone();
         Callback::from(move |name: String| {
@@ -303,6 +568,120 @@ fn channel_list() -> Html {
         })
     };
 
+    // Accepting an invitation makes the private channel show up in the list
+    let on_accept = {
+        let channels = channels.clone();
+        let invitations = invitations.clone();
+        let error = error.clone();
+
+        Callback::from(move |invitation: Invitation| {
+            let channels = channels.clone();
+            let invitations = invitations.clone();
+            let error = error.clone();
+
+            spawn_local(async move {
+                let response = send_authorized(|| Request::post(&format!("http://localhost:8080/channel/accept/{}", invitation.channel)).build())
+                    .await;
+
+                match response {
+                    Ok(resp) if resp.ok() => {
+                        let mut updated_channels = (*channels).clone();
+                        updated_channels.push(Channel {
+                            id: 0,
+                            name: invitation.channel.clone(),
+                            owner: invitation.owner.clone(),
+                            private: true,
+                            topic: String::new(),
+                            description: String::new(),
+                            archived: false,
+                        });
+                        channels.set(updated_channels);
+                        let remaining = invitations.iter().filter(|pending| **pending != invitation).cloned().collect();
+                        invitations.set(remaining);
+                    }
+                    _ => {
+                        error.set("Failed to accept invitation.".to_string());
+                    }
+                }
+            })
+        })
+    };
+
+    // Open (or start) a direct conversation and enter it like a channel
+    let on_direct_message = {
+        let direct_username = direct_username.clone();
+        let error = error.clone();
+
+        Callback::from(move |e: SubmitEvent| {
+            e.prevent_default();
+            let username = (*direct_username).clone();
+            let error = error.clone();
+
+            spawn_local(async move {
+                let response = send_authorized(|| Request::post("http://localhost:8080/direct/open")
+                    .header("Content-Type", "application/json")
+                    .json(&serde_json::json!({ "username": username })))
+                    .await;
+
+                match response {
+                    Ok(resp) if resp.ok() => match resp.json::<Conversation>().await {
+                        Ok(conversation) => {
+                            if LocalStorage::set("selected_channel", conversation.channel).is_err() {
+                                gloo::console::log!("Error saving to local storage:");
+                            }
+                            window().location().set_href("/channel_room").unwrap();
+                        }
+                        Err(_) => error.set("Error".to_string()),
+                    },
+                    Ok(resp) if resp.status() == 404 => {
+                        error.set("User not found.".to_string());
+                    }
+                    Ok(resp) if resp.status() == 400 => {
+                        error.set("Cannot message yourself.".to_string());
+                    }
+                    _ => {
+                        error.set("Error occurred.".to_string());
+                    }
+                }
+            })
+        })
+    };
+
+    // Search messages in every channel the user can read
+    let on_search = {
+        let search_query = search_query.clone();
+        let search_results = search_results.clone();
+        let error = error.clone();
+
+        Callback::from(move |e: SubmitEvent| {
+            e.prevent_default();
+            let query = (*search_query).clone();
+            let search_results = search_results.clone();
+            let error = error.clone();
+            if query.trim().is_empty() {
+                search_results.set(None);
+                return;
+            }
+
+            spawn_local(async move {
+                let response = send_authorized(|| Request::get("http://localhost:8080/channel/search")
+                    .query([("q", query.as_str())])
+                    .build())
+                    .await;
+
+                match response {
+                    Ok(resp) if resp.ok() => match resp.json::<Vec<SearchResult>>().await {
+                        Ok(results) => search_results.set(Some(results)),
+                        Err(_) => error.set("Error".to_string()),
+                    },
+                    _ => {
+                        error.set("Search failed.".to_string());
+                    }
+                }
+            })
+        })
+    };
+
     let logout = {
         let error = error.clone();
         
@@ -311,9 +690,7 @@ fn channel_list() -> Html {
             let error = error.clone();
 
             spawn_local(async move {
-                let response = Request::post("http://localhost:8080/user/logout")
-                    .send()
-                    .await;
+                let response = log_out().await;
 
                 match response {
                     Ok(resp) if resp.ok() => {
@@ -342,6 +719,53 @@ fn channel_list() -> Html {
             } else {
                 html! {}
             }}
+            <form onsubmit={on_search}>
+                <input
+                    type="text"
+                    placeholder="Search messages"
+                    value={(*search_query).clone()}
+                    onchange={
+                        let search_query = search_query.clone();
+                        Callback::from(move |e: Event| {
+                            if let Some(input) = e.target_dyn_into::<HtmlInputElement>() {
+                                search_query.set(input.value());
+                            }
+                        })
+                    }
+                    class="input"
+                />
+                <button type="submit" class="button func-btn">{"Search"}</button>
+            </form>
+            {match &*search_results {
+                Some(results) if results.is_empty() => html! { <div class="search-results">{"No messages found."}</div> },
+                Some(results) => html! {
+                    <div class="search-results channel-list">
+                        { for results.iter().map(|result| {
+                            let is_selected = *selected_channel == Some(result.channel.clone());
+                            let channel_name = result.channel.clone();
+                            let on_select = {
+                                let on_channel_select = on_channel_select.clone();
+                                Callback::from(move |_| on_channel_select.emit(channel_name.clone()))
+                            };
+
+                            html! {
+                                <div key={result.id.clone()} class={classes!("channel-item", is_selected.then_some("selected"))} onclick={on_select}>
+                                    <div class="channel-info">
+                                        <span class="channel-name">{&result.channel}</span>
+                                        <span class="channel-topic">{format!("{}: {}", result.username, result.message)}</span>
+                                        <span class="channel-owner">{local_time(&result.timestamp)}</span>
+                                    </div>
+                                </div>
+                            }
+                        })}
+                    </div>
+                },
+                None => html! {},
+            }}
+            <label class="archived-toggle">
+                <input type="checkbox" checked={*show_archived} onclick={on_toggle_archived} />
+                {"Show archived channels"}
+            </label>
             <div class="channel-list">
                 { for channels.iter().map(|channel| {
                     let is_selected = *selected_channel == Some(channel.name.clone());
@@ -357,13 +781,89 @@ fn channel_list() -> Html {
                                 <div class={classes!("radio-circle", is_selected.then(|| "checked"))} />
                             </div>
                             <div class="channel-info">
-                                <span class="channel-name">{&channel.name}</span>
+                                <span class="channel-name">
+                                    {&channel.name}
+                                    {if channel.private { " (private)" } else { "" }}
+                                    {if channel.archived { " (archived)" } else { "" }}
+                                </span>
+                                {if !channel.topic.is_empty() {
+                                    html! { <span class="channel-topic">{&channel.topic}</span> }
+                                } else {
+                                    html! {}
+                                }}
                                 <span class="channel-owner">{format!("Owner: {}", &channel.owner)}</span>
                             </div>
                         </div>
                     }
                 })}
             </div>
+            {if !invitations.is_empty() {
+                html! {
+                    <>
+                        <h2>{"Invitations"}</h2>
+                        <div class="channel-list">
+                            { for invitations.iter().map(|invitation| {
+                                let on_accept = {
+                                    let on_accept = on_accept.clone();
+                                    let invitation = invitation.clone();
+                                    Callback::from(move |_| on_accept.emit(invitation.clone()))
+                                };
+
+                                html! {
+                                    <div class="channel-item">
+                                        <div class="channel-info">
+                                            <span class="channel-name">{&invitation.channel}</span>
+                                            <span class="channel-owner">{format!("Owner: {}", &invitation.owner)}</span>
+                                        </div>
+                                        <button onclick={on_accept} class="message-action">{"Accept"}</button>
+                                    </div>
+                                }
+                            })}
+                        </div>
+                    </>
+                }
+            } else {
+                html! {}
+            }}
+            <h2>{"Direct Messages"}</h2>
+            <div class="channel-list">
+                { for conversations.iter().map(|conversation| {
+                    let is_selected = *selected_channel == Some(conversation.channel.clone());
+                    let channel_name = conversation.channel.clone();
+                    let on_select = {
+                        let on_channel_select = on_channel_select.clone();
+                        Callback::from(move |_| on_channel_select.emit(channel_name.clone()))
+                    };
+
+                    html! {
+                        <div class={classes!("channel-item", is_selected.then_some("selected"))}>
+                            <div class="channel-selector" onclick={on_select}>
+                                <div class={classes!("radio-circle", is_selected.then_some("checked"))} />
+                            </div>
+                            <div class="channel-info">
+                                <span class="channel-name">{&conversation.with}</span>
+                            </div>
+                        </div>
+                    }
+                })}
+            </div>
+            <form onsubmit={on_direct_message}>
+                <input
+                    type="text"
+                    placeholder="Username"
+                    value={(*direct_username).clone()}
+                    onchange={
+                        let direct_username = direct_username.clone();
+                        Callback::from(move |e: Event| {
+                            if let Some(input) = e.target_dyn_into::<HtmlInputElement>() {
+                                direct_username.set(input.value());
+                            }
+                        })
+                    }
+                    class="input"
+                />
+                <button type="submit" class="button func-btn">{"Send A Direct Message"}</button>
+            </form>
             <button onclick={on_enter} class="button enter-button">
                 {"Enter Channel"}
             </button>
@@ -380,23 +880,24 @@ fn channel_list() -> Html {
 #[function_component(CreateChannel)]
 fn channel_create() -> Html {
     let name = use_state(String::new);
+    let private = use_state(|| false);
     let error = use_state(String::new);
 
     let onsubmit = {
         let name = name.clone();
+        let private = private.clone();
         let error = error.clone();
 
         Callback::from(move |e: SubmitEvent| {
             e.prevent_default();
             let name = (*name).clone();
+            let private = *private;
             let error = error.clone();
 
             spawn_local(async move {
-                let response = Request::post("http://localhost:8080/channel/create")
+                let response = send_authorized(|| Request::post("http://localhost:8080/channel/create")
                     .header("Content-Type", "application/json")
-                    .json(&serde_json::json!({ "name": name}))
-                    .unwrap()
-                    .send()
+                    .json(&serde_json::json!({ "name": name, "private": private })))
                     .await;
 
                 match response {
@@ -427,9 +928,7 @@ fn channel_create() -> Html {
             let error = error.clone();
 
             spawn_local(async move {
-                let response = Request::post("http://localhost:8080/user/logout")
-                    .send()
-                    .await;
+                let response = log_out().await;
 
                 match response {
                     Ok(resp) if resp.ok() => {
@@ -475,6 +974,21 @@ fn channel_create() -> Html {
                         }
                         class="input"
                     />
+                    <label class="private-toggle">
+                        <input
+                            type="checkbox"
+                            checked={*private}
+                            onchange={
+                                let private = private.clone();
+                                Callback::from(move |e: Event| {
+                                    if let Some(input) = e.target_dyn_into::<HtmlInputElement>() {
+                                        private.set(input.checked());
+                                    }
+                                })
+                            }
+                        />
+                        {"Private (invite only)"}
+                    </label>
                     <button type="submit" class="button">{"Create"}</button>
                     <button onclick={navigate} class="button">{"Available Channel List"}</button>
                     <button onclick={logout} class="button">{"Log Out"}</button>
@@ -484,12 +998,203 @@ fn channel_create() -> Html {
     }
 }
 
+fn send_client_event(websocket: &WebSocket, event: ClientEvent) -> bool {
+    match serde_json::to_string(&Envelope::new(event)) {
+        Ok(frame) => websocket.send_with_str(&frame).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Show a timestamp from the server, which stores UTC, in the browser's time zone.
+/// Messages stored before that keep their original text.
+fn local_time(timestamp: &str) -> String {
+    match chrono::DateTime::parse_from_rfc3339(timestamp) {
+        Ok(time) => time.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S").to_string(),
+        Err(_) => timestamp.to_string(),
+    }
+}
+
+fn system_message(message: String) -> ChatMessage {
+    ChatMessage {
+        id: String::new(),
+        username: "System".to_string(),
+        message,
+        timestamp: chrono::Utc::now().to_rfc3339(),
+        edited_at: None,
+        reply_to: None,
+        reply_count: 0,
+        last_reply_at: None,
+        reactions: BTreeMap::new(),
+        system: true,
+    }
+}
+
+/// State handles of the chat room that incoming frames update
+#[derive(Clone)]
+struct RoomState {
+    messages: UseStateHandle<Vec<ChatMessage>>,
+    user_statuses: UseStateHandle<Vec<UserStatus>>,
+    typing: UseStateHandle<Option<String>>,
+    thread: UseStateHandle<Option<Thread>>,
+    settings: UseStateHandle<ChannelInfo>,
+}
+
+/// Apply a change to the message with the given ID, wherever it is shown
+fn update_message(room: &RoomState, id: &str, update: impl Fn(&mut ChatMessage)) {
+    let mut messages = (*room.messages).clone();
+    if let Some(msg) = messages.iter_mut().find(|msg| msg.id == id) {
+        update(msg);
+        room.messages.set(messages);
+    }
+
+    if let Some(mut thread) = (*room.thread).clone() {
+        if thread.parent.id == id {
+            update(&mut thread.parent);
+        }
+        if let Some(reply) = thread.replies.iter_mut().find(|reply| reply.id == id) {
+            update(reply);
+        }
+        room.thread.set(Some(thread));
+    }
+}
+
+fn handle_server_frame(text: &str, room: &RoomState) {
+    let envelope = match serde_json::from_str::<Envelope<ServerEvent>>(text) {
+        Ok(envelope) => envelope,
+        Err(e) => {
+            gloo::console::log!("Failed to parse frame:", e.to_string());
+            return;
+        }
+    };
+
+    if envelope.v != PROTOCOL_VERSION {
+        gloo::console::log!("Unsupported protocol version:", envelope.v);
+        return;
+    }
+
+    let new_message = match envelope.event {
+        ServerEvent::Message(chat_message) => {
+            if let Some(parent_id) = chat_message.reply_to.clone() {
+                // Replies only update their thread and the parent's summary
+                let mut thread = (*room.thread).clone();
+                if let Some(open) = thread.as_mut().filter(|open| open.parent.id == parent_id) {
+                    if !open.replies.iter().any(|reply| reply.id == chat_message.id) {
+                        open.replies.push(chat_message.clone());
+                    }
+                    open.parent.reply_count = open.replies.len();
+                    open.parent.last_reply_at = Some(chat_message.timestamp.clone());
+                    room.thread.set(thread);
+                }
+
+                let mut messages = (*room.messages).clone();
+                if let Some(parent) = messages.iter_mut().find(|msg| msg.id == parent_id) {
+                    parent.reply_count += 1;
+                    parent.last_reply_at = Some(chat_message.timestamp.clone());
+                    room.messages.set(messages);
+                }
+                return;
+            }
+            // Skip messages we already have, e.g. from the history fetch
+            if room.messages.iter().any(|msg| msg.id == chat_message.id) {
+                return;
+            }
+            chat_message
+        }
+        ServerEvent::Edited(chat_message) => {
+            update_message(room, &chat_message.id.clone(), |msg| {
+                msg.message = chat_message.message.clone();
+                msg.edited_at = chat_message.edited_at.clone();
+            });
+            return;
+        }
+        ServerEvent::Deleted { id, deleted_by, reply_to } => {
+            gloo::console::log!("Message deleted by", deleted_by);
+            if let Some(parent_id) = reply_to {
+                update_message(room, &parent_id, |parent| {
+                    parent.reply_count = parent.reply_count.saturating_sub(1);
+                });
+            }
+            if let Some(mut thread) = (*room.thread).clone() {
+                if thread.parent.id == id {
+                    room.thread.set(None);
+                } else {
+                    thread.replies.retain(|reply| reply.id != id);
+                    room.thread.set(Some(thread));
+                }
+            }
+            let remaining = room.messages.iter().filter(|msg| msg.id != id).cloned().collect();
+            room.messages.set(remaining);
+            return;
+        }
+        ServerEvent::Reactions { id, reactions } => {
+            update_message(room, &id, |msg| msg.reactions = reactions.clone());
+            return;
+        }
+        ServerEvent::ChannelUpdated(info) => {
+            // Every request of the room uses the channel name, so start over under the new one
+            if !room.settings.name.is_empty() && room.settings.name != info.name {
+                if LocalStorage::set("selected_channel", info.name.clone()).is_err() {
+                    gloo::console::log!("Error saving to local storage:");
+                }
+                window().location().reload().unwrap();
+                return;
+            }
+            room.settings.set(info);
+            return;
+        }
+        ServerEvent::ChannelDeleted { name } => {
+            gloo::console::log!(format!("Channel {} was deleted", name));
+            if LocalStorage::set("selected_channel", "").is_err() {
+                gloo::console::log!("Error saving to local storage:");
+            }
+            window().location().set_href("/channel_list").unwrap();
+            return;
+        }
+        ServerEvent::Join { username } => system_message(format!("{} joined the chat", username)),
+        ServerEvent::Leave { username } => system_message(format!("{} left the chat", username)),
+        ServerEvent::Presence { users } => {
+            room.user_statuses.set(users);
+            return;
+        }
+        ServerEvent::Typing { username } => {
+            room.typing.set(Some(username));
+            let typing = room.typing.clone();
+            spawn_local(async move {
+                TimeoutFuture::new(3_000).await;
+                typing.set(None);
+            });
+            return;
+        }
+        ServerEvent::Ack { client_id, id, timestamp } => {
+            gloo::console::log!("Message acknowledged:", format!("{:?} -> {} at {}", client_id, id, timestamp));
+            return;
+        }
+        ServerEvent::Error { message } => {
+            gloo::console::log!("Server error:", message);
+            return;
+        }
+        ServerEvent::Pong => return,
+    };
+
+    let mut current_messages = (*room.messages).clone();
+    current_messages.push(new_message);
+    room.messages.set(current_messages);
+}
+
+/// Browsers can't set headers on WebSocket connections, so the access token goes in the query string
+fn channel_ws_url(channel_name: &str) -> String {
+    match auth_tokens() {
+        Some(tokens) => format!("ws://localhost:8080/channel/ws/{}?access_token={}", channel_name, tokens.access_token),
+        None => format!("ws://localhost:8080/channel/ws/{}", channel_name),
+    }
+}
+
 fn setup_websocket(
     channel_name: String,
-    messages: UseStateHandle<Vec<ChatMessage>>,
+    room: RoomState,
     ws_state: UseStateHandle<Option<WebSocket>>,
 ) -> Option<WebSocket> {
-    let ws_url = format!("ws://localhost:8080/channel/ws/{}", channel_name);
+    let ws_url = channel_ws_url(&channel_name);
     
     match WebSocket::new(&ws_url) {
         Ok(websocket) => {
@@ -501,18 +1206,19 @@ fn setup_websocket(
             onopen.forget();
 
             // Set up close handler
-            let ws_url = ws_url.clone();
             let ws_state_reconnect = ws_state.clone();
             
             let onclose = Closure::wrap(Box::new(move |_| {
                 gloo::console::log!("WebSocket closed, attempting to reconnect...");
                 
-                let ws_url = ws_url.clone();  // Clone inside closure to make it FnMut
+                let channel_name = channel_name.clone();  // Clone inside closure to make it FnMut
                 let ws_state = ws_state_reconnect.clone();  // Clone inside closure
                 
                 spawn_local(async move {
                     TimeoutFuture::new(3_000).await;
-                    if let Ok(new_ws) = WebSocket::new(&ws_url) {
+                    // The access token may have expired since the connection was opened
+                    refresh_tokens().await;
+                    if let Ok(new_ws) = WebSocket::new(&channel_ws_url(&channel_name)) {
                         ws_state.set(Some(new_ws));
                     }
                 });
@@ -521,48 +1227,7 @@ fn setup_websocket(
             onclose.forget();
 
             // Set up message handler
-            // Only clone messages once for the message handler
-            let messages_handler = messages.clone();
-            let onmessage = Closure::wrap(Box::new(move |event: MessageEvent| {
-                gloo::console::log!("onmessage triggered");
-                if let Some(text) = event.data().as_string() {
-                    if text == "ping" {
-                        return;
-                    }
-
-                    let mut current_messages = (*messages_handler).clone();
-                    gloo::console::log!("Current messages before update:", current_messages.len());
-
-                    gloo::console::log!("Received message (raw):", &text);
-
-                    
-                    let new_message = if text.trim().contains(" left the chat") || text.trim().contains(" joined the chat"){
-                        
-                        ChatMessage {
-                            username: "System".to_string(),
-                            message: text,
-                            timestamp: chrono::Local::now()
-                                .format("%Y-%m-%d %H:%M")
-                                .to_string(),
-                        }
-                    } else if let Some((username, msg)) = text.split_once(':') {
-                        ChatMessage {
-                            username: username.to_string(),
-                            message: msg.trim().to_string(),
-                            timestamp: chrono::Local::now()
-                                .format("%Y-%m-%d %H:%M:%S%.3f")
-                                .to_string(),
-                        }
-                    } else {
-                        return;
-                    };
-                    
-                    // gloo::console::log!("set up 1");
-                    current_messages.push(new_message);
-                    // gloo::console::log!("Messages after update:", current_messages.len());
-                    messages_handler.set(current_messages);
-                }
-            }) as Box<dyn FnMut(MessageEvent)>);
+            let onmessage = set_onmessage(room);
             websocket.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
             onmessage.forget();
 
@@ -575,60 +1240,52 @@ fn setup_websocket(
     }
 }
 
-fn set_onmessage(messages: UseStateHandle<Vec<ChatMessage>>) -> Option<Closure<dyn FnMut(MessageEvent)>> {
-    let messages_handler = messages.clone();
-    let onmessage = Closure::wrap(Box::new(move |event: MessageEvent| {
+fn set_onmessage(room: RoomState) -> Closure<dyn FnMut(MessageEvent)> {
+    Closure::wrap(Box::new(move |event: MessageEvent| {
         if let Some(text) = event.data().as_string() {
-            if text == "ping" {
-                return;
-            }
-
-            let mut current_messages = (*messages_handler).clone();
-            // gloo::console::log!("Current messages before update:", current_messages.len());
-            
-            let new_message = if text.contains(" joined the chat") || text.contains(" left the chat"){
-                ChatMessage {
-                    username: "System".to_string(),
-                    message: text,
-                    timestamp: chrono::Local::now()
-                        .format("%Y-%m-%d %H:%M")
-                        .to_string(),
-                }
-            } else if let Some((username, msg)) = text.split_once(':') {
-                ChatMessage {
-                    username: username.to_string(),
-                    message: msg.trim().to_string(),
-                    timestamp: chrono::Local::now()
-                        .format("%Y-%m-%d %H:%M:%S%.3f")
-                        .to_string(),
-                }
-            } else {
-                return;
-            };
-
-            // gloo::console::log!("Adding new message");
-            current_messages.push(new_message);
-            // gloo::console::log!("Messages after update:", current_messages.len());
-            messages_handler.set(current_messages);
+            handle_server_frame(&text, &room);
         }
-    }) as Box<dyn FnMut(MessageEvent)>);
-    Some(onmessage)
+    }) as Box<dyn FnMut(MessageEvent)>)
 }
 
 #[function_component(ChatRoom)]
 fn chat_room() -> Html {
     let error = use_state(|| String::new());
     let current_channel = use_state(|| None::<Channel>);
+    // The other participant when the room is a direct conversation
+    let direct_with = use_state(|| None::<String>);
     let message = use_state(String::new);
     let ws = use_state(|| None::<WebSocket>);
     let messages = use_state(|| Vec::<ChatMessage>::new());
     let history_fetch = use_state(|| false);
     let ws_setup = use_state(|| false);
     let user_statuses = use_state(|| Vec::<UserStatus>::new());
+    let typing = use_state(|| None::<String>);
+    let thread = use_state(|| None::<Thread>);
+    // Kept apart from `current_channel`, which the history and WebSocket effects depend on
+    let settings = use_state(ChannelInfo::default);
+    let room = RoomState {
+        messages: messages.clone(),
+        user_statuses: user_statuses.clone(),
+        typing: typing.clone(),
+        thread: thread.clone(),
+        settings: settings.clone(),
+    };
+    let last_typing_sent = use_mut_ref(|| None::<chrono::DateTime<chrono::Utc>>);
+    let messages_ref = use_node_ref();
+    let current_user = use_state(|| LocalStorage::get::<String>("username").ok());
+    // ID of the message being edited in the input box
+    let editing = use_state(|| None::<String>);
+    let has_more_history = use_state(|| true);
+    let loading_history = use_mut_ref(|| false);
+    // Scroll height before the last history update, so the view can be kept in place
+    let scroll_restore = use_mut_ref(|| None::<i32>);
 
     // Initial channel setup
     {
         let current_channel = current_channel.clone();
+        let direct_with = direct_with.clone();
+        let settings = settings.clone();
         let error = error.clone();
 
         use_effect_with_deps(
@@ -637,24 +1294,46 @@ fn chat_room() -> Html {
                     let stored_channel_name: Option<String> = LocalStorage::get("selected_channel").ok();
                     
                     if let Some(channel_name) = stored_channel_name {
-                        let response = Request::get(&format!("http://localhost:8080/channel/enter/{}", channel_name))
-                            .send()
+                        let response = send_authorized(|| Request::get(&format!("http://localhost:8080/channel/enter/{}", channel_name)).build())
                             .await;
 
                         match response {
                             Ok(resp) => {
                                 match resp.status() {
                                     200 => {
+                                        let entered = match resp.json::<ChannelEnterResponse>().await {
+                                            Ok(entered) => entered,
+                                            Err(_) => {
+                                                error.set("Error connecting to channel".to_string());
+                                                return;
+                                            }
+                                        };
+                                        direct_with.set(entered.with);
+                                        settings.set(ChannelInfo {
+                                            name: channel_name.clone(),
+                                            topic: entered.topic.clone(),
+                                            description: entered.description.clone(),
+                                            archived: entered.archived,
+                                            retention_days: entered.retention_days,
+                                            retention_count: entered.retention_count,
+                                        });
                                         current_channel.set(Some(Channel {
                                             id: 0,
                                             name: channel_name,
-                                            owner: String::new()
+                                            owner: entered.owner,
+                                            private: entered.private,
+                                            topic: entered.topic,
+                                            description: entered.description,
+                                            archived: entered.archived,
                                         }));
                                     },
                                     401 => {
                                         error.set("Please log in first".to_string());
                                         window().location().set_href("/login").unwrap();
                                     },
+                                    403 => {
+                                        error.set(format!("You are not a member of channel '{}'", channel_name));
+                                    },
                                     404 => {
                                         error.set(format!("Channel '{}' not found", channel_name));
                                     },
@@ -677,10 +1356,36 @@ fn chat_room() -> Html {
         );
     }
 
+    // Fetch the current user's permissions, which decide the moderation controls shown
+    let permissions = use_state(ChannelPermissions::default);
+    {
+        let permissions = permissions.clone();
+        let channel_state = current_channel.clone();
+
+        use_effect_with_deps(
+            move |_| {
+                if let Some(channel) = (*channel_state).clone() {
+                    spawn_local(async move {
+                        let url = format!("http://localhost:8080/channel/permissions/{}", channel.name);
+                        if let Ok(resp) = send_authorized(|| Request::get(&url).build()).await {
+                            if let Ok(fetched) = resp.json::<ChannelPermissions>().await {
+                                permissions.set(fetched);
+                            }
+                        }
+                    });
+                }
+                || ()
+            },
+            current_channel.clone(),
+        );
+    }
+
     // Fetch chat history
     {
         let history_fetch = history_fetch.clone();
         let messages = messages.clone();
+        let has_more_history = has_more_history.clone();
+        let scroll_restore = scroll_restore.clone();
         let error = error.clone();
         let channel_state = current_channel.clone();
 
@@ -692,8 +1397,7 @@ fn chat_room() -> Html {
                         // gloo::console::log!("=== FETCHING CHAT HISTORY ===");
                         // gloo::console::log!("Channel name:", &channel.name);
                         
-                        let response = Request::get(&format!("http://localhost:8080/channel/history/{}", channel.name))
-                            .send()
+                        let response = send_authorized(|| Request::get(&format!("http://localhost:8080/channel/history/{}?limit={}", channel.name, HISTORY_PAGE_SIZE)).build())
                             .await;
 
                         match response {
@@ -706,6 +1410,9 @@ fn chat_room() -> Html {
                                         //     gloo::console::log!("History message:", 
                                         //         format!("User: {}, Content: {}", msg.username, msg.message));
                                         // }
+                                        has_more_history.set(history.len() >= HISTORY_PAGE_SIZE);
+                                        // Start at the newest message
+                                        *scroll_restore.borrow_mut() = Some(0);
                                         messages.set(history);
                                         history_fetch.set(true);
                                         gloo::console::log!("History set complete");
@@ -729,9 +1436,85 @@ fn chat_room() -> Html {
         );
     }
 
+    // Keep the scroll position after history pages are loaded
+    {
+        let messages_ref = messages_ref.clone();
+        let scroll_restore = scroll_restore.clone();
+
+        use_effect_with_deps(
+            move |_| {
+                if let Some(previous_height) = scroll_restore.borrow_mut().take() {
+                    if let Some(element) = messages_ref.cast::<Element>() {
+                        element.set_scroll_top(element.scroll_height() - previous_height);
+                    }
+                }
+                || ()
+            },
+            messages.len(),
+        );
+    }
+
+    // Load older messages when scrolled to the top
+    let on_messages_scroll = {
+        let messages = messages.clone();
+        let messages_ref = messages_ref.clone();
+        let has_more_history = has_more_history.clone();
+        let channel_state = current_channel.clone();
+
+        Callback::from(move |_: Event| {
+            let element = match messages_ref.cast::<Element>() {
+                Some(element) => element,
+                None => return,
+            };
+            if element.scroll_top() > 20 || !*has_more_history || *loading_history.borrow() {
+                return;
+            }
+            let channel = match (*channel_state).clone() {
+                Some(channel) => channel,
+                None => return,
+            };
+            let oldest_id = match messages.iter().find(|msg| !msg.system) {
+                Some(msg) => msg.id.clone(),
+                None => return,
+            };
+
+            *loading_history.borrow_mut() = true;
+            let previous_height = element.scroll_height();
+            let messages = messages.clone();
+            let has_more_history = has_more_history.clone();
+            let loading_history = loading_history.clone();
+            let scroll_restore = scroll_restore.clone();
+
+            spawn_local(async move {
+                let url = format!(
+                    "http://localhost:8080/channel/history/{}?before={}&limit={}",
+                    channel.name, oldest_id, HISTORY_PAGE_SIZE
+                );
+                match send_authorized(|| Request::get(&url).build()).await {
+                    Ok(resp) if resp.ok() => match resp.json::<Vec<ChatMessage>>().await {
+                        Ok(mut page) => {
+                            if page.len() < HISTORY_PAGE_SIZE {
+                                has_more_history.set(false);
+                            }
+                            page.retain(|older| !messages.iter().any(|msg| msg.id == older.id));
+                            if !page.is_empty() {
+                                page.extend((*messages).iter().cloned());
+                                *scroll_restore.borrow_mut() = Some(previous_height);
+                                messages.set(page);
+                            }
+                        }
+                        Err(e) => gloo::console::log!("Failed to parse history:", e.to_string()),
+                    },
+                    _ => gloo::console::log!("Failed to fetch older messages"),
+                }
+                *loading_history.borrow_mut() = false;
+            });
+        })
+    };
+
     // WebSocket setup
     {
-        let messages_c1 = messages.clone();
+        let room = room.clone();
         let history_fetch_clone = history_fetch.clone();
         let ws = ws.clone();
         let channel_state = current_channel.clone();
@@ -741,14 +1524,14 @@ fn chat_room() -> Html {
             move |_| {
                 if *history_fetch_clone {
                     if let Some(channel) = (*channel_state).clone() {
-                        if let Some(websocket) = setup_websocket(channel.name, messages_c1.clone(), ws.clone()) {
-                            // Setup ping
+                        if let Some(websocket) = setup_websocket(channel.name, room.clone(), ws.clone()) {
+                            // Keep the connection alive with protocol pings, which are not stored
                             let ws_clone = websocket.clone();
                             ws_setup_clone.set(true);
                             spawn_local(async move {
                                 loop {
                                     TimeoutFuture::new(30_000).await;
-                                    if ws_clone.send_with_str("ping").is_err() {
+                                    if !send_client_event(&ws_clone, ClientEvent::Ping) {
                                         break;
                                     }
                                 }
@@ -766,23 +1549,22 @@ fn chat_room() -> Html {
     }
 
     {
-        let messages_c1 = messages.clone();
+        let room = room.clone();
         let ws_setup_clone = ws_setup.clone();
         let ws_clone = ws.clone();
 
         use_effect_with_deps(
             move |_| {
                 if *ws_setup_clone {
-                    if let Some(ws_onmessage) = set_onmessage(messages_c1.clone()) {
-                        if let Some(webs) = &*ws_clone {
-                            webs.set_onmessage(Some(ws_onmessage.as_ref().unchecked_ref()));
-                            ws_onmessage.forget();
-                        }
+                    if let Some(webs) = &*ws_clone {
+                        let ws_onmessage = set_onmessage(room.clone());
+                        webs.set_onmessage(Some(ws_onmessage.as_ref().unchecked_ref()));
+                        ws_onmessage.forget();
                     }
                 }
                 || ()
             },
-            (ws_setup.clone(), messages.clone()), // Dependencies
+            (ws_setup.clone(), messages.clone(), thread.clone()), // Dependencies
         );
     }
 
@@ -800,7 +1582,7 @@ fn chat_room() -> Html {
                             let user_statuses = user_statuses.clone();
                             let url = format!("http://localhost:8080/user/status/{}", channel_name);
 
-                            match Request::get(&url).send().await {
+                            match send_authorized(|| Request::get(&url).build()).await {
                                 Ok(response) => {
                                     if let Ok(status_list) = response.json::<Vec<UserStatus>>().await {
                                         user_statuses.set(status_list);
@@ -827,26 +1609,60 @@ fn chat_room() -> Html {
     // Message sending
     let send_message = {
         let message = message.clone();
+        let editing = editing.clone();
+        let thread = thread.clone();
         let ws = ws.clone();
         move || {
             let msg = (*message).clone();
             if !msg.is_empty() {
                 if let Some(websocket) = &*ws {
                     // gloo::console::log!("Sending message:", &msg);
-                    if websocket.send_with_str(&msg).is_ok() {
+                    let event = match (*editing).clone() {
+                        Some(id) => ClientEvent::Edit { id, message: msg },
+                        None => ClientEvent::Message {
+                            message: msg,
+                            client_id: None,
+                            // While a thread is open, messages are posted as replies
+                            reply_to: thread.as_ref().map(|open| open.parent.id.clone()),
+                        },
+                    };
+                    if send_client_event(websocket, event) {
                         message.set(String::new());
+                        editing.set(None);
                     }
                 }
             }
         }
     };
 
+    let on_cancel_edit = {
+        let message = message.clone();
+        let editing = editing.clone();
+        Callback::from(move |_| {
+            editing.set(None);
+            message.set(String::new());
+        })
+    };
+
     let on_message_change = {
         let message = message.clone();
+        let ws = ws.clone();
         Callback::from(move |e: InputEvent| {
             if let Some(input) = e.target_dyn_into::<HtmlInputElement>() {
                 message.set(input.value());
             }
+
+            // Let the other participants know we are typing, at most every 3 seconds
+            let now = chrono::Utc::now();
+            let mut last_sent = last_typing_sent.borrow_mut();
+            let due = last_sent.is_none_or(|last| now - last > chrono::Duration::seconds(3));
+            if due {
+                if let Some(websocket) = &*ws {
+                    if send_client_event(websocket, ClientEvent::Typing) {
+                        *last_sent = Some(now);
+                    }
+                }
+            }
         })
     };
 
@@ -865,6 +1681,170 @@ fn chat_room() -> Html {
         Callback::from(move |_| send_message())
     };
 
+    let open_thread = {
+        let thread = thread.clone();
+        let error = error.clone();
+        let channel_state = current_channel.clone();
+        Callback::from(move |parent_id: String| {
+            let channel = match (*channel_state).clone() {
+                Some(channel) => channel,
+                None => return,
+            };
+            let thread = thread.clone();
+            let error = error.clone();
+            spawn_local(async move {
+                let url = format!("http://localhost:8080/channel/thread/{}/{}", channel.name, parent_id);
+                match send_authorized(|| Request::get(&url).build()).await {
+                    Ok(resp) if resp.ok() => match resp.json::<Thread>().await {
+                        Ok(opened) => thread.set(Some(opened)),
+                        Err(e) => error.set(format!("Failed to parse thread: {}", e)),
+                    },
+                    _ => error.set("Failed to load thread".to_string()),
+                }
+            });
+        })
+    };
+
+    let on_close_thread = {
+        let thread = thread.clone();
+        Callback::from(move |_| thread.set(None))
+    };
+
+    let invite_username = use_state(String::new);
+
+    // Admins of private channels can invite users
+    let invite_member = {
+        let error = error.clone();
+        let channel_state = current_channel.clone();
+        Callback::from(move |username: String| {
+            let channel = match (*channel_state).clone() {
+                Some(channel) => channel,
+                None => return,
+            };
+            let error = error.clone();
+            spawn_local(async move {
+                let url = format!("http://localhost:8080/channel/invite/{}", channel.name);
+                let response = send_authorized(|| Request::post(&url)
+                    .header("Content-Type", "application/json")
+                    .json(&serde_json::json!({ "username": username })))
+                    .await;
+
+                match response {
+                    Ok(resp) if resp.ok() => error.set(String::new()),
+                    Ok(resp) if resp.status() == 404 => error.set(format!("User '{}' not found", username)),
+                    Ok(resp) if resp.status() == 409 => error.set(format!("'{}' is already invited", username)),
+                    _ => error.set(format!("Failed to invite '{}'", username)),
+                }
+            });
+        })
+    };
+
+    let on_invite = {
+        let invite_username = invite_username.clone();
+        let invite_member = invite_member.clone();
+        Callback::from(move |e: SubmitEvent| {
+            e.prevent_default();
+            invite_member.emit((*invite_username).clone());
+            invite_username.set(String::new());
+        })
+    };
+
+    let new_name = use_state(String::new);
+    let new_topic = use_state(String::new);
+    let new_description = use_state(String::new);
+    let retention_days = use_state(String::new);
+    let retention_count = use_state(String::new);
+
+    // Channel settings are applied when the server broadcasts `channel_updated`
+    let update_channel = {
+        let error = error.clone();
+        let channel_state = current_channel.clone();
+        Callback::from(move |(action, body): (&'static str, serde_json::Value)| {
+            let channel = match (*channel_state).clone() {
+                Some(channel) => channel,
+                None => return,
+            };
+            let error = error.clone();
+            spawn_local(async move {
+                let url = format!("http://localhost:8080/channel/{}/{}", action, channel.name);
+                let response = send_authorized(|| Request::post(&url)
+                    .header("Content-Type", "application/json")
+                    .json(&body))
+                    .await;
+
+                match response {
+                    Ok(resp) if resp.ok() => error.set(String::new()),
+                    Ok(resp) => error.set(resp.json::<String>().await.unwrap_or_else(|_| "Failed to update channel".to_string())),
+                    Err(_) => error.set("Failed to update channel".to_string()),
+                }
+            });
+        })
+    };
+
+    let on_rename = {
+        let new_name = new_name.clone();
+        let update_channel = update_channel.clone();
+        Callback::from(move |e: SubmitEvent| {
+            e.prevent_default();
+            update_channel.emit(("rename", serde_json::json!({ "name": *new_name })));
+            new_name.set(String::new());
+        })
+    };
+
+    let on_topic = {
+        let new_topic = new_topic.clone();
+        let new_description = new_description.clone();
+        let update_channel = update_channel.clone();
+        Callback::from(move |e: SubmitEvent| {
+            e.prevent_default();
+            update_channel.emit(("topic", serde_json::json!({
+                "topic": *new_topic,
+                "description": *new_description,
+            })));
+        })
+    };
+
+    // An empty limit keeps messages forever
+    let on_retention = {
+        let retention_days = retention_days.clone();
+        let retention_count = retention_count.clone();
+        let update_channel = update_channel.clone();
+        Callback::from(move |e: SubmitEvent| {
+            e.prevent_default();
+            update_channel.emit(("retention", serde_json::json!({
+                "retention_days": retention_days.trim().parse::<i64>().ok(),
+                "retention_count": retention_count.trim().parse::<i64>().ok(),
+            })));
+        })
+    };
+
+    let on_archive = {
+        let settings = settings.clone();
+        let update_channel = update_channel.clone();
+        Callback::from(move |_| {
+            update_channel.emit(("archive", serde_json::json!({ "archived": !settings.archived })));
+        })
+    };
+
+    // Sessions are closed with a `channel_deleted` event, which takes everyone back to the list
+    let on_delete_channel = {
+        let update_channel = update_channel.clone();
+        Callback::from(move |_| {
+            if gloo::dialogs::confirm("Delete this channel and all of its messages?") {
+                update_channel.emit(("delete", serde_json::json!({})));
+            }
+        })
+    };
+
+    let text_input = |state: &UseStateHandle<String>| {
+        let state = state.clone();
+        Callback::from(move |e: Event| {
+            if let Some(input) = e.target_dyn_into::<HtmlInputElement>() {
+                state.set(input.value());
+            }
+        })
+    };
+
     let cur_channel = current_channel.clone();
 
     let on_exit = Callback::from(move |_| {
@@ -878,12 +1858,155 @@ fn chat_room() -> Html {
     });
 
     match &*current_channel {
-        Some(channel) => html! {
+        Some(channel) => {
+            let can_invite = channel.private && permissions.allows("invite");
+            let can_configure = direct_with.is_none()
+                && ["rename", "change_topic", "archive", "delete_channel", "set_retention"].iter().any(|permission| permissions.allows(permission));
+            // Moderation commands go over the WebSocket
+            let moderate = |event: ClientEvent| {
+                let ws = ws.clone();
+                Callback::from(move |_| {
+                    if let Some(websocket) = &*ws {
+                        send_client_event(websocket, event.clone());
+                    }
+                })
+            };
+
+            let toggle_reaction = |id: &str, emoji: &str, reacted: bool| {
+                let ws = ws.clone();
+                let id = id.to_string();
+                let emoji = emoji.to_string();
+                Callback::from(move |_| {
+                    if let Some(websocket) = &*ws {
+                        let event = if reacted {
+                            ClientEvent::RemoveReaction { id: id.clone(), emoji: emoji.clone() }
+                        } else {
+                            ClientEvent::AddReaction { id: id.clone(), emoji: emoji.clone() }
+                        };
+                        send_client_event(websocket, event);
+                    }
+                })
+            };
+
+            let render_message = |msg: &ChatMessage, in_thread: bool| -> Html {
+                if msg.system {
+                    return html! {
+                        <div class="message system-message">
+                            <div class="content">{&msg.message}</div>
+                            <span class="timestamp">{local_time(&msg.timestamp)}</span>
+                        </div>
+                    };
+                }
+
+                let is_own = current_user.as_deref() == Some(msg.username.as_str());
+                let can_delete = permissions.allows("delete_messages");
+                let on_delete = {
+                    let ws = ws.clone();
+                    let id = msg.id.clone();
+                    Callback::from(move |_| {
+                        if let Some(websocket) = &*ws {
+                            send_client_event(websocket, ClientEvent::Delete { id: id.clone() });
+                        }
+                    })
+                };
+                let on_edit = {
+                    let message = message.clone();
+                    let editing = editing.clone();
+                    let msg = msg.clone();
+                    Callback::from(move |_| {
+                        editing.set(Some(msg.id.clone()));
+                        message.set(msg.message.clone());
+                    })
+                };
+                let on_reply = {
+                    let open_thread = open_thread.clone();
+                    let id = msg.id.clone();
+                    Callback::from(move |_| open_thread.emit(id.clone()))
+                };
+
+                html! {
+                    <div class="message" key={msg.id.clone()}>
+                        <div class="message-header">
+                            <span class="username">{&msg.username}</span>
+                            <span class="timestamp">{local_time(&msg.timestamp)}</span>
+                            {if msg.edited_at.is_some() {
+                                html! { <span class="edited-marker">{"(edited)"}</span> }
+                            } else {
+                                html! {}
+                            }}
+                            {if is_own {
+                                html! { <button onclick={on_edit} class="message-action">{"Edit"}</button> }
+                            } else {
+                                html! {}
+                            }}
+                            {if is_own || can_delete {
+                                html! { <button onclick={on_delete} class="message-action">{"Delete"}</button> }
+                            } else {
+                                html! {}
+                            }}
+                            {if !in_thread {
+                                html! { <button onclick={on_reply.clone()} class="message-action">{"Reply"}</button> }
+                            } else {
+                                html! {}
+                            }}
+                        </div>
+                        <div class="content">{&msg.message}</div>
+                        <div class="reactions">
+                            {for msg.reactions.iter().map(|(emoji, users)| {
+                                let reacted = current_user.as_ref().is_some_and(|user| users.contains(user));
+                                let on_toggle = toggle_reaction(&msg.id, emoji, reacted);
+                                html! {
+                                    <button onclick={on_toggle} title={users.join(", ")}
+                                        class={classes!("reaction", reacted.then_some("reacted"))}>
+                                        {format!("{} {}", emoji, users.len())}
+                                    </button>
+                                }
+                            })}
+                            {for QUICK_REACTIONS.iter()
+                                .filter(|emoji| !msg.reactions.contains_key(**emoji))
+                                .map(|emoji| html! {
+                                    <button onclick={toggle_reaction(&msg.id, emoji, false)} class="reaction-add">{*emoji}</button>
+                                })
+                            }
+                        </div>
+                        {match (&msg.last_reply_at, in_thread) {
+                            (Some(last_reply_at), false) if msg.reply_count > 0 => html! {
+                                <div class="thread-summary" onclick={on_reply}>
+                                    {format!("{} replies, last reply {}", msg.reply_count, local_time(last_reply_at))}
+                                </div>
+                            },
+                            _ => html! {},
+                        }}
+                    </div>
+                }
+            };
+
+            html! {
             <div class="chat-container">
                 <div class="chat-header">
                     <button onclick={on_exit} class="exit-button">{"Exit"}</button>
-                    <h2 class="channel-title">{format!("Channel: {}", channel.name)}</h2>
+                    <h2 class="channel-title">
+                        {match &*direct_with {
+                            Some(with) => format!("Direct message: {}", with),
+                            None => format!("Channel: {}", channel.name),
+                        }}
+                    </h2>
+                    {if !settings.topic.is_empty() {
+                        html! { <span class="channel-topic">{&settings.topic}</span> }
+                    } else {
+                        html! {}
+                    }}
                 </div>
+                {if !settings.description.is_empty() {
+                    html! { <div class="channel-description">{&settings.description}</div> }
+                } else {
+                    html! {}
+                }}
+                {if settings.archived {
+                    html! { <div class="archived-banner">{"This channel is archived and read-only."}</div> }
+                } else {
+                    html! {}
+                }}
                 <div class="chat-layout">
                     <div class="chat-main">
                         {if !error.is_empty() {
@@ -891,31 +2014,38 @@ fn chat_room() -> Html {
                         } else {
                             html! {}
                         }}
-                        <div class="chat-messages">
-                            {for (*messages).iter()
-                                .filter(|msg| !msg.message.contains("ping"))
-                                .map(|msg| {
-                                    if msg.username == "System" {
-                                        html! {
-                                            <div class="message system-message">
-                                                <div class="content">{&msg.message}</div>
-                                                <span class="timestamp">{&msg.timestamp}</span>
-                                            </div>
-                                        }
-                                    } else {
-                                        html! {
-                                            <div class="message" key={format!("{}-{}", msg.timestamp, msg.username)}>
-                                                <div class="message-header">
-                                                    <span class="username">{&msg.username}</span>
-                                                    <span class="timestamp">{&msg.timestamp}</span>
-                                                </div>
-                                                <div class="content">{&msg.message}</div>
-                                            </div>
-                                        }
-                                    }
-                                })
-                            }
+                        <div class="chat-messages" ref={messages_ref} onscroll={on_messages_scroll}>
+                            {for (*messages).iter().map(|msg| render_message(msg, false))}
                         </div>
+                        {if let Some(open) = &*thread {
+                            html! {
+                                <div class="thread-panel">
+                                    <div class="thread-header">
+                                        {"Thread"}
+                                        <button onclick={on_close_thread} class="message-action">{"Close"}</button>
+                                    </div>
+                                    {render_message(&open.parent, true)}
+                                    {for open.replies.iter().map(|reply| render_message(reply, true))}
+                                </div>
+                            }
+                        } else {
+                            html! {}
+                        }}
+                        {if let Some(username) = &*typing {
+                            html! { <div class="typing-indicator">{format!("{} is typing...", username)}</div> }
+                        } else {
+                            html! {}
+                        }}
+                        {if editing.is_some() {
+                            html! {
+                                <div class="editing-banner">
+                                    {"Editing message"}
+                                    <button onclick={on_cancel_edit} class="message-action">{"Cancel"}</button>
+                                </div>
+                            }
+                        } else {
+                            html! {}
+                        }}
                         <div class="chat-input">
                             <input
                                 type="text"
@@ -923,26 +2053,131 @@ fn chat_room() -> Html {
                                 value={(*message).clone()}
                                 oninput={on_message_change}
                                 onkeypress={on_keypress}
+                                disabled={settings.archived}
                                 class="message-input"
                             />
-                            <button onclick={on_send} class="send-button">{"Send"}</button>
+                            <button onclick={on_send} disabled={settings.archived} class="send-button">{"Send"}</button>
                         </div>
                     </div>
                     <div class="user-list">
                         <h3>{"User Status"}</h3>
                         <div class="user-list-content">
                             {for (*user_statuses).iter().map(|user| {
+                                // The server also checks that the target has a lower role
+                                let is_other = current_user.as_deref() != Some(user.username.as_str()) && user.username != channel.owner;
+                                let username = user.username.clone();
                                 html! {
                                     <div class="user-item">
                                         <span class={classes!("status-indicator", if user.status=="Online" { "online" } else { "offline" })}></span>
                                         <span class="username">{&user.username}</span>
+                                        {if is_other && permissions.allows("mute") {
+                                            html! {
+                                                <>
+                                                    <button onclick={moderate(ClientEvent::Mute { username: username.clone(), muted: true })} class="message-action">{"Mute"}</button>
+                                                    <button onclick={moderate(ClientEvent::Mute { username: username.clone(), muted: false })} class="message-action">{"Unmute"}</button>
+                                                </>
+                                            }
+                                        } else {
+                                            html! {}
+                                        }}
+                                        {if is_other && permissions.allows("kick") {
+                                            html! { <button onclick={moderate(ClientEvent::Kick { username })} class="message-action">{"Kick"}</button> }
+                                        } else {
+                                            html! {}
+                                        }}
                                     </div>
                                 }
                             })}
                         </div>
+                        {if can_invite {
+                            html! {
+                                <form class="invite-form" onsubmit={on_invite}>
+                                    <input
+                                        type="text"
+                                        placeholder="Invite user"
+                                        value={(*invite_username).clone()}
+                                        onchange={
+                                            let invite_username = invite_username.clone();
+                                            Callback::from(move |e: Event| {
+                                                if let Some(input) = e.target_dyn_into::<HtmlInputElement>() {
+                                                    invite_username.set(input.value());
+                                                }
+                                            })
+                                        }
+                                        class="input"
+                                    />
+                                    <button type="submit" class="message-action">{"Invite"}</button>
+                                </form>
+                            }
+                        } else {
+                            html! {}
+                        }}
+                        {if can_configure {
+                            html! {
+                                <div class="channel-settings">
+                                    <h3>{"Channel Settings"}</h3>
+                                    {if permissions.allows("rename") {
+                                        html! {
+                                            <form class="settings-form" onsubmit={on_rename}>
+                                                <input type="text" placeholder="New name" value={(*new_name).clone()} onchange={text_input(&new_name)} class="input" />
+                                                <button type="submit" class="message-action">{"Rename"}</button>
+                                            </form>
+                                        }
+                                    } else {
+                                        html! {}
+                                    }}
+                                    {if permissions.allows("change_topic") {
+                                        html! {
+                                            <form class="settings-form" onsubmit={on_topic}>
+                                                <input type="text" placeholder="Topic" value={(*new_topic).clone()} onchange={text_input(&new_topic)} class="input" />
+                                                <input type="text" placeholder="Description" value={(*new_description).clone()} onchange={text_input(&new_description)} class="input" />
+                                                <button type="submit" class="message-action">{"Set topic"}</button>
+                                            </form>
+                                        }
+                                    } else {
+                                        html! {}
+                                    }}
+                                    {if permissions.allows("set_retention") {
+                                        let retention = match (settings.retention_days, settings.retention_count) {
+                                            (None, None) => "Messages are kept forever".to_string(),
+                                            (Some(days), None) => format!("Messages are kept for {} days", days),
+                                            (None, Some(count)) => format!("The newest {} messages are kept", count),
+                                            (Some(days), Some(count)) => format!("The newest {} messages are kept, for {} days", count, days),
+                                        };
+                                        html! {
+                                            <form class="settings-form" onsubmit={on_retention}>
+                                                <span class="channel-owner">{retention}</span>
+                                                <input type="number" min="1" placeholder="Days to keep" value={(*retention_days).clone()} onchange={text_input(&retention_days)} class="input" />
+                                                <input type="number" min="1" placeholder="Messages to keep" value={(*retention_count).clone()} onchange={text_input(&retention_count)} class="input" />
+                                                <button type="submit" class="message-action">{"Set retention"}</button>
+                                            </form>
+                                        }
+                                    } else {
+                                        html! {}
+                                    }}
+                                    {if permissions.allows("archive") {
+                                        html! {
+                                            <button onclick={on_archive} class="message-action">
+                                                {if settings.archived { "Unarchive" } else { "Archive" }}
+                                            </button>
+                                        }
+                                    } else {
+                                        html! {}
+                                    }}
+                                    {if permissions.allows("delete_channel") {
+                                        html! { <button onclick={on_delete_channel} class="message-action">{"Delete channel"}</button> }
+                                    } else {
+                                        html! {}
+                                    }}
+                                </div>
+                            }
+                        } else {
+                            html! {}
+                        }}
                     </div>
                 </div>
             </div>
+            }
         },
         None => html! {
             <div class="loading-container">
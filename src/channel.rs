@@ -1,22 +1,53 @@
-use actix_session::Session;
-use actix_web::{web, Responder, HttpResponse};
+use actix_web::{web, Error, Responder, HttpRequest, HttpResponse};
+use actix_web::error::{ErrorBadRequest, ErrorInternalServerError, ErrorNotFound};
 use sqlx::{Pool, Sqlite};
 use serde::{Deserialize, Serialize};
-use crate::user::check_auth;
-use crate::database::get_chat_history_sled;
+use std::sync::Arc;
+use crate::user::{authorize, check_auth, Permission};
+use crate::database::{delete_channel_sled, find_message_key_sled, find_storage_key, get_chat_history_sled};
+use crate::protocol::ServerEvent;
+use crate::search::unindex_channel;
+use crate::record::Attachment;
+use crate::websocket::ChatState;
+use crate::direct::{get_other_participant, is_direct, DIRECT_PREFIX};
+use crate::member::{can_access_channel, check_channel_access, MEMBER};
 use serde_json::json;
+use std::collections::BTreeMap;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct ChatMessage {
+    pub id: String,
     pub timestamp: String,
+    /// `Users.id` of the author, used to check who may change the message. Not sent to clients.
+    #[serde(skip)]
+    pub author_id: Option<u32>,
     pub username: String,
     pub message: String,
+    /// Time of the latest edit, if the message was ever edited
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub edited_at: Option<String>,
+    /// ID of the thread's parent message, if this message is a reply
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub reply_to: Option<String>,
+    /// Number of replies in this message's thread
+    #[serde(default)]
+    pub reply_count: usize,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub last_reply_at: Option<String>,
+    /// Emoji -> users who reacted with it
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    pub reactions: BTreeMap<String, Vec<String>>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub attachments: Vec<Attachment>,
 }
 
 #[derive(Deserialize)]
 pub struct ChannelRequest {
     name: String,
+    /// Private channels are only visible to their owner and invited members
+    #[serde(default)]
+    private: bool,
 }
 
 #[derive(Deserialize, sqlx::FromRow, Serialize, Debug)]
@@ -24,6 +55,61 @@ struct Channel {
     id: i32,
     name: String,
     owner: String,
+    private: bool,
+    topic: String,
+    description: String,
+    archived: bool,
+}
+
+/// The settings of a channel that can change while users are connected
+#[derive(Serialize, sqlx::FromRow, Debug, Clone)]
+pub struct ChannelInfo {
+    pub name: String,
+    pub topic: String,
+    pub description: String,
+    /// Archived channels are read-only and hidden from `/channel/list` by default
+    pub archived: bool,
+    /// Messages older than this many days are removed
+    pub retention_days: Option<i64>,
+    /// Only this many of the newest messages are kept
+    pub retention_count: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct ListQuery {
+    /// Also list archived channels
+    #[serde(default)]
+    archived: bool,
+}
+
+#[derive(Deserialize)]
+pub struct RenameRequest {
+    name: String,
+}
+
+#[derive(Deserialize)]
+pub struct TopicRequest {
+    topic: String,
+    #[serde(default)]
+    description: String,
+}
+
+#[derive(Deserialize)]
+pub struct ArchiveRequest {
+    /// `false` to restore the channel
+    #[serde(default = "default_archived")]
+    archived: bool,
+}
+
+fn default_archived() -> bool {
+    true
+}
+
+/// Retention limits of a channel. Leave a limit out to keep messages forever.
+#[derive(Deserialize)]
+pub struct RetentionRequest {
+    retention_days: Option<i64>,
+    retention_count: Option<i64>,
 }
 
 #[derive(Deserialize)]
@@ -31,21 +117,109 @@ pub struct ChannelPath {
     name: String,
 }
 
-pub async fn channel_create(db: web::Data<Pool<Sqlite>>, session: Session, info: web::Json<ChannelRequest>) -> impl Responder {
-    if !check_auth(&session).is_ok() {
-        return HttpResponse::Unauthorized().json("User not logged in.")
+/// Number of messages returned by `/channel/history` when no `limit` is given
+const DEFAULT_HISTORY_LIMIT: usize = 50;
+const MAX_HISTORY_LIMIT: usize = 200;
+
+/// Upper bounds of the retention limits, so the cutoff time can always be computed
+const MAX_RETENTION_DAYS: i64 = 36500;
+const MAX_RETENTION_COUNT: i64 = 1_000_000_000;
+
+/// Cursor parameters of `/channel/history`; `before` and `after` are message IDs
+#[derive(Deserialize)]
+pub struct HistoryQuery {
+    before: Option<String>,
+    after: Option<String>,
+    limit: Option<usize>,
+}
+
+/// Get the current settings of a channel
+async fn get_channel_info(db: &Pool<Sqlite>, channel_name: &str) -> Result<Option<ChannelInfo>, sqlx::Error> {
+    sqlx::query_as::<_, ChannelInfo>("SELECT Name AS name, Topic AS topic, Description AS description, Archived AS archived,
+        RetentionDays AS retention_days, RetentionCount AS retention_count
+        FROM Channel WHERE Name = ?;")
+        .bind(channel_name)
+        .fetch_optional(db)
+        .await
+}
+
+/// Whether a channel is archived, and therefore read-only
+pub async fn is_archived(db: &Pool<Sqlite>, channel_name: &str) -> Result<bool, sqlx::Error> {
+    let archived: Option<bool> = sqlx::query_scalar("SELECT Archived FROM Channel WHERE Name = ?;")
+        .bind(channel_name)
+        .fetch_optional(db)
+        .await?;
+    Ok(archived.unwrap_or(false))
+}
+
+/// Tell the channel's connected clients about its saved settings
+async fn announce_channel_update(db: &Pool<Sqlite>, state: &ChatState, channel_name: &str) -> HttpResponse {
+    match get_channel_info(db, channel_name).await {
+        Ok(Some(info)) => {
+            state.update_channel(channel_name, &info);
+            state.broadcast(channel_name, &ServerEvent::ChannelUpdated(info.clone()));
+            HttpResponse::Ok().json(info)
+        }
+        Ok(None) => HttpResponse::NotFound().json("Channel not found."),
+        Err(e) => {
+            println!("Error querying database: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
     }
+}
+
+/// A channel's name together with the key its trees are stored under in sled
+#[derive(Clone, Debug)]
+pub struct ChannelRef {
+    pub name: String,
+    pub key: String,
+}
 
-    let user_username: String = match session.get::<String>("user_username").unwrap() {
-        Some(username) => username,
-        None => return HttpResponse::Unauthorized().json("User not logged in.")
+/// Look up where the messages of a channel or direct conversation are stored
+pub async fn resolve_channel(db: &Pool<Sqlite>, channel_name: &str) -> Result<ChannelRef, Error> {
+    match find_storage_key(db, channel_name).await {
+        Ok(Some(key)) => Ok(ChannelRef { name: channel_name.to_string(), key }),
+        Ok(None) => Err(ErrorNotFound("Channel not found.")),
+        Err(e) => {
+            println!("Error querying database: {}", e);
+            Err(ErrorInternalServerError("Failed to look up channel."))
+        }
+    }
+}
+
+/// Resolve a message ID cursor into its sled key
+fn resolve_cursor(sled_db: &sled::Db, channel_key: &str, cursor: Option<&str>) -> Result<Option<sled::IVec>, Error> {
+    let message_id = match cursor {
+        Some(message_id) => message_id,
+        None => return Ok(None),
+    };
+
+    match find_message_key_sled(sled_db, channel_key, message_id) {
+        Ok(Some(key)) => Ok(Some(key)),
+        Ok(None) => Err(ErrorBadRequest("Unknown message cursor.")),
+        Err(err) => {
+            println!("Error resolving history cursor: {:?}", err);
+            Err(ErrorInternalServerError("Failed to retrieve chat history"))
+        }
+    }
+}
+
+pub async fn channel_create(db: web::Data<Pool<Sqlite>>, req: HttpRequest, info: web::Json<ChannelRequest>) -> impl Responder {
+    let user_username: String = match check_auth(&req) {
+        Ok((_user_id, username)) => username,
+        Err(_) => return HttpResponse::Unauthorized().json("User not logged in.")
     };
 
-    let query: &str = "INSERT INTO Channel (Name, Owner) VALUES (?, ?);";
+    if is_direct(&info.name) {
+        return HttpResponse::BadRequest().json(format!("Channel names may not start with \"{}\".", DIRECT_PREFIX));
+    }
+
+    let query: &str = "INSERT INTO Channel (Name, Owner, Private) VALUES (?, ?, ?);";
 
     let result = sqlx::query(query)
         .bind(&info.name)
         .bind(user_username)
+        .bind(info.private)
         .execute(db.get_ref())
         .await;
 
@@ -60,7 +234,7 @@ pub async fn channel_create(db: web::Data<Pool<Sqlite>>, session: Session, info:
 //     sled_db: web::Data<sled::Db>,
 //     info: web::Path<ChannelRequest>,
 //     state: web::Data<Arc<ChatState>>,
-//     session: Session,
+//     req: HttpRequest,
 //     req: HttpRequest,
 //     stream: web::Payload,
 // ) -> impl Responder {
@@ -68,7 +242,7 @@ pub async fn channel_create(db: web::Data<Pool<Sqlite>>, session: Session, info:
 
 //     let channel_name = &info.name;
 
-//     let (_user_id, user_name) = match check_auth(&session) {
+//     let (_user_id, user_name) = match check_auth(&req) {
 //         Ok((id, username)) => (id, username),
 //         Err(err) => return HttpResponse::Unauthorized().body(err.to_string()),
 //     };
@@ -101,26 +275,62 @@ pub async fn channel_create(db: web::Data<Pool<Sqlite>>, session: Session, info:
 pub async fn channel_enter(
     db: web::Data<Pool<Sqlite>>,
     info: web::Path<ChannelRequest>,
-    session: Session,
+    req: HttpRequest,
 ) -> impl Responder {
     
-    if !check_auth(&session).is_ok() {
-        return HttpResponse::Unauthorized().json("User not logged in.");
-    }
+    let (_user_id, username) = match check_auth(&req) {
+        Ok(user) => user,
+        Err(_) => return HttpResponse::Unauthorized().json("User not logged in."),
+    };
 
     let channel_name = &info.name;
 
+    // Direct conversations have no owner, only the other participant
+    if is_direct(channel_name) {
+        return match get_other_participant(db.get_ref(), channel_name, &username).await {
+            Ok(Some(with)) => HttpResponse::Ok().json(json!({
+                "status": "success",
+                "channel": channel_name,
+                "owner": "",
+                "with": with,
+                "ws_url": format!("/ws/{}", channel_name)
+            })),
+            Ok(None) => HttpResponse::NotFound().json("Channel not found."),
+            Err(e) => {
+                println!("Error querying database: {}", e);
+                HttpResponse::InternalServerError().finish()
+            }
+        };
+    }
+
+    // Private channels are only open to their owner and members
+    match can_access_channel(db.get_ref(), channel_name, &username).await {
+        Ok(Some(false)) => return HttpResponse::Forbidden().json("Not a member of this channel."),
+        Err(e) => {
+            println!("Error querying database: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+        _ => {}
+    }
+
     // Check if channel exists
-    match sqlx::query("SELECT * FROM Channel WHERE Name = ?")
+    match sqlx::query_as::<_, (String, bool, String, String, bool, Option<i64>, Option<i64>)>("SELECT Owner, Private, Topic, Description, Archived, RetentionDays, RetentionCount FROM Channel WHERE Name = ?")
         .bind(channel_name)
         .fetch_optional(db.get_ref())
         .await
     {
-        Ok(Some(_)) => {
+        Ok(Some((owner, private, topic, description, archived, retention_days, retention_count))) => {
             // Return success with WebSocket connection details
             HttpResponse::Ok().json(json!({
                 "status": "success",
                 "channel": channel_name,
+                "owner": owner,
+                "private": private,
+                "topic": topic,
+                "description": description,
+                "archived": archived,
+                "retention_days": retention_days,
+                "retention_count": retention_count,
                 "ws_url": format!("/ws/{}", channel_name)
             }))
         }
@@ -142,13 +352,32 @@ pub async fn channel_enter(
 // }
 
 pub async fn channel_history(
+    db: web::Data<Pool<Sqlite>>,
     sled_db: web::Data<sled::Db>,
+    req: HttpRequest,
     info: web::Path<ChannelPath>,
+    query: web::Query<HistoryQuery>,
 ) -> impl Responder {
     // println!("Accessing channel history for: {}", info.name);
-    let channel_name = &info.name;
+    if let Err(err) = check_channel_access(&db, &req, &info.name).await {
+        return err.error_response();
+    }
+    let channel = match resolve_channel(&db, &info.name).await {
+        Ok(channel) => channel,
+        Err(err) => return err.error_response(),
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_HISTORY_LIMIT).clamp(1, MAX_HISTORY_LIMIT);
+
+    let before = match resolve_cursor(&sled_db, &channel.key, query.before.as_deref()) {
+        Ok(key) => key,
+        Err(err) => return err.error_response(),
+    };
+    let after = match resolve_cursor(&sled_db, &channel.key, query.after.as_deref()) {
+        Ok(key) => key,
+        Err(err) => return err.error_response(),
+    };
     
-    match get_chat_history_sled(&sled_db, channel_name) {
+    match get_chat_history_sled(&sled_db, &channel.key, before, after, limit) {
         Ok(messages) => {
             // println!("Found {} messages", messages.len());
             // // Print each message individually
@@ -165,14 +394,24 @@ pub async fn channel_history(
     }
 }
 
-pub async fn channel_list(db: web::Data<Pool<Sqlite>>, session: Session) -> impl Responder {
-    if !check_auth(&session).is_ok() {
-        return HttpResponse::Unauthorized().json("User not logged in.")
-    }
+pub async fn channel_list(db: web::Data<Pool<Sqlite>>, req: HttpRequest, query: web::Query<ListQuery>) -> impl Responder {
+    let (_user_id, username) = match check_auth(&req) {
+        Ok(user) => user,
+        Err(_) => return HttpResponse::Unauthorized().json("User not logged in."),
+    };
 
-    let query = "SELECT id, Name AS name, Owner AS owner FROM Channel;";
+    // Private channels are only listed for their owner and members
+    let sql = "SELECT id, Name AS name, Owner AS owner, Private AS private, Topic AS topic, Description AS description, Archived AS archived
+        FROM Channel c
+        WHERE (Private = 0 OR Owner = ?
+            OR EXISTS (SELECT 1 FROM ChannelMembers m WHERE m.ChannelId = c.id AND m.Username = ? AND m.Status = ?))
+            AND (Archived = 0 OR ?);";
 
-    let result: Result<Vec<Channel>, sqlx::Error> = sqlx::query_as::<_, Channel>(query)
+    let result: Result<Vec<Channel>, sqlx::Error> = sqlx::query_as::<_, Channel>(sql)
+        .bind(&username)
+        .bind(&username)
+        .bind(MEMBER)
+        .bind(query.archived)
         .fetch_all(db.get_ref())
         .await;
 
@@ -182,4 +421,189 @@ pub async fn channel_list(db: web::Data<Pool<Sqlite>>, session: Session) -> impl
             HttpResponse::InternalServerError().json(e.to_string())
         }
     }
-}
\ No newline at end of file
+}
+
+/// Rename a channel, moving its connected users along
+pub async fn channel_rename(
+    db: web::Data<Pool<Sqlite>>,
+    state: web::Data<Arc<ChatState>>,
+    req: HttpRequest,
+    info: web::Path<ChannelPath>,
+    form: web::Json<RenameRequest>,
+) -> impl Responder {
+    let (_user_id, username) = match check_auth(&req) {
+        Ok(user) => user,
+        Err(_) => return HttpResponse::Unauthorized().json("User not logged in."),
+    };
+    if let Err(err) = authorize(&db, &info.name, &username, Permission::Rename, None).await {
+        return err.error_response();
+    }
+
+    let new_name = form.name.trim();
+    if new_name.is_empty() {
+        return HttpResponse::BadRequest().json("Channel name is empty.");
+    }
+    if is_direct(new_name) {
+        return HttpResponse::BadRequest().json(format!("Channel names may not start with \"{}\".", DIRECT_PREFIX));
+    }
+
+    let result = sqlx::query("UPDATE Channel SET Name = ? WHERE Name = ?;")
+        .bind(new_name)
+        .bind(&info.name)
+        .execute(db.get_ref())
+        .await;
+    if result.is_err() {
+        return HttpResponse::Conflict().json("Channel name already exists!");
+    }
+
+    let channel_info = match get_channel_info(&db, new_name).await {
+        Ok(Some(channel_info)) => channel_info,
+        Ok(None) => return HttpResponse::NotFound().json("Channel not found."),
+        Err(e) => {
+            println!("Error querying database: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    // Messages are stored by channel ID, so only the connected sessions need to move
+    state.update_channel(&info.name, &channel_info);
+    state.broadcast(new_name, &ServerEvent::ChannelUpdated(channel_info.clone()));
+    HttpResponse::Ok().json(channel_info)
+}
+
+/// Set the topic and description shown in the channel list and the chat room header
+pub async fn channel_topic(
+    db: web::Data<Pool<Sqlite>>,
+    state: web::Data<Arc<ChatState>>,
+    req: HttpRequest,
+    info: web::Path<ChannelPath>,
+    form: web::Json<TopicRequest>,
+) -> impl Responder {
+    let (_user_id, username) = match check_auth(&req) {
+        Ok(user) => user,
+        Err(_) => return HttpResponse::Unauthorized().json("User not logged in."),
+    };
+    if let Err(err) = authorize(&db, &info.name, &username, Permission::ChangeTopic, None).await {
+        return err.error_response();
+    }
+
+    let result = sqlx::query("UPDATE Channel SET Topic = ?, Description = ? WHERE Name = ?;")
+        .bind(form.topic.trim())
+        .bind(form.description.trim())
+        .bind(&info.name)
+        .execute(db.get_ref())
+        .await;
+    if let Err(e) = result {
+        println!("Error updating channel: {}", e);
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    announce_channel_update(&db, &state, &info.name).await
+}
+
+/// Archive a channel, making it read-only and hiding it from the channel list, or restore it
+pub async fn channel_archive(
+    db: web::Data<Pool<Sqlite>>,
+    state: web::Data<Arc<ChatState>>,
+    req: HttpRequest,
+    info: web::Path<ChannelPath>,
+    form: web::Json<ArchiveRequest>,
+) -> impl Responder {
+    let (_user_id, username) = match check_auth(&req) {
+        Ok(user) => user,
+        Err(_) => return HttpResponse::Unauthorized().json("User not logged in."),
+    };
+    if let Err(err) = authorize(&db, &info.name, &username, Permission::Archive, None).await {
+        return err.error_response();
+    }
+
+    let result = sqlx::query("UPDATE Channel SET Archived = ? WHERE Name = ?;")
+        .bind(form.archived)
+        .bind(&info.name)
+        .execute(db.get_ref())
+        .await;
+    if let Err(e) = result {
+        println!("Error updating channel: {}", e);
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    announce_channel_update(&db, &state, &info.name).await
+}
+
+/// Set how long a channel's messages are kept. Older messages are removed in the background.
+pub async fn channel_retention(
+    db: web::Data<Pool<Sqlite>>,
+    state: web::Data<Arc<ChatState>>,
+    req: HttpRequest,
+    info: web::Path<ChannelPath>,
+    form: web::Json<RetentionRequest>,
+) -> impl Responder {
+    let (_user_id, username) = match check_auth(&req) {
+        Ok(user) => user,
+        Err(_) => return HttpResponse::Unauthorized().json("User not logged in."),
+    };
+    if let Err(err) = authorize(&db, &info.name, &username, Permission::SetRetention, None).await {
+        return err.error_response();
+    }
+    if form.retention_days.is_some_and(|days| !(1..=MAX_RETENTION_DAYS).contains(&days)) {
+        return HttpResponse::BadRequest().json(format!("Retention days must be between 1 and {}.", MAX_RETENTION_DAYS));
+    }
+    if form.retention_count.is_some_and(|count| !(1..=MAX_RETENTION_COUNT).contains(&count)) {
+        return HttpResponse::BadRequest().json(format!("Retention count must be between 1 and {}.", MAX_RETENTION_COUNT));
+    }
+
+    let result = sqlx::query("UPDATE Channel SET RetentionDays = ?, RetentionCount = ? WHERE Name = ?;")
+        .bind(form.retention_days)
+        .bind(form.retention_count)
+        .bind(&info.name)
+        .execute(db.get_ref())
+        .await;
+    if let Err(e) = result {
+        println!("Error updating channel: {}", e);
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    announce_channel_update(&db, &state, &info.name).await
+}
+
+/// Delete a channel with its members, roles and messages, and close its open connections
+pub async fn channel_delete(
+    db: web::Data<Pool<Sqlite>>,
+    sled_db: web::Data<sled::Db>,
+    state: web::Data<Arc<ChatState>>,
+    req: HttpRequest,
+    info: web::Path<ChannelPath>,
+) -> impl Responder {
+    let (_user_id, username) = match check_auth(&req) {
+        Ok(user) => user,
+        Err(_) => return HttpResponse::Unauthorized().json("User not logged in."),
+    };
+    if let Err(err) = authorize(&db, &info.name, &username, Permission::DeleteChannel, None).await {
+        return err.error_response();
+    }
+    let channel = match resolve_channel(&db, &info.name).await {
+        Ok(channel) => channel,
+        Err(err) => return err.error_response(),
+    };
+
+    // Members, roles and mutes go with the channel row through ON DELETE CASCADE
+    let result = sqlx::query("DELETE FROM Channel WHERE Name = ?;")
+        .bind(&info.name)
+        .execute(db.get_ref())
+        .await;
+    if let Err(e) = result {
+        println!("Error deleting channel: {}", e);
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    state.close_channel(&info.name).await;
+    if let Err(e) = unindex_channel(&db, &channel.key).await {
+        println!("Error removing channel messages from the search index: {}", e);
+    }
+    if let Err(e) = delete_channel_sled(&sled_db, &channel.key) {
+        println!("Error deleting channel messages: {}", e);
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    HttpResponse::Ok().json("Channel deleted.")
+}
@@ -0,0 +1,272 @@
+use actix_cors::Cors;
+use actix_session::{SessionLength, SessionMiddleware};
+use actix_web::http::header;
+use actix_web::cookie::{time::Duration, Key, SameSite};
+use argon2::{Algorithm, Argon2, Params, Version};
+use jsonwebtoken::{DecodingKey, EncodingKey};
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Sqlite};
+use std::io::Write;
+use std::path::Path;
+use crate::session::SqliteSessionStore;
+
+/// Where the session signing key is kept unless `CHAT_SESSION_KEY_FILE` says otherwise
+const DEFAULT_SESSION_KEY_FILE: &str = "./session_key";
+
+/// Length of the session signing key in bytes
+const SESSION_KEY_LEN: usize = 64;
+
+/// How long an unused session stays valid unless `CHAT_SESSION_IDLE_TIMEOUT` says otherwise
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::days(7);
+
+/// How long the server keeps a login that lasts for the browser session
+const BROWSER_SESSION_TTL: Duration = Duration::days(30);
+
+/// How long JWT access tokens and refresh tokens last unless configured otherwise
+const DEFAULT_ACCESS_TOKEN_TTL: Duration = Duration::minutes(15);
+const DEFAULT_REFRESH_TOKEN_TTL: Duration = Duration::days(30);
+
+/// Where the frontend is served from unless `CHAT_CORS_ORIGINS` says otherwise
+const DEFAULT_CORS_ORIGIN: &str = "http://localhost:8081";
+
+/// How sessions are kept and the session cookie is signed and sent, read from the environment:
+///
+/// | variable                    | default              |                                                |
+/// |-----------------------------|----------------------|------------------------------------------------|
+/// | `CHAT_SESSION_KEY`          |                      | the signing key as 128 hex digits              |
+/// | `CHAT_SESSION_KEY_FILE`     | `./session_key`      | file holding the key, generated on first start |
+/// | `CHAT_COOKIE_SECURE`        | `false`              | only send the cookie over HTTPS                |
+/// | `CHAT_COOKIE_SAME_SITE`     | `lax`                | `strict`, `lax` or `none`                      |
+/// | `CHAT_COOKIE_MAX_AGE`       | browser session      | seconds a login lasts                          |
+/// | `CHAT_COOKIE_DOMAIN`        | the server's host    | domain the cookie is sent to                   |
+/// | `CHAT_SESSION_IDLE_TIMEOUT` | 7 days               | seconds an unused session stays valid          |
+#[derive(Clone)]
+pub struct SessionConfig {
+    pub key: Key,
+    pub secure: bool,
+    pub same_site: SameSite,
+    pub max_age: Option<Duration>,
+    pub domain: Option<String>,
+    pub idle_timeout: Duration,
+}
+
+impl SessionConfig {
+    /// Read the settings, panicking on invalid values so a misconfigured server doesn't start
+    pub fn from_env() -> Self {
+        let secure = match env_var("CHAT_COOKIE_SECURE").as_deref() {
+            None | Some("false") | Some("0") => false,
+            Some("true") | Some("1") => true,
+            Some(value) => panic!("CHAT_COOKIE_SECURE must be true or false, not {}", value),
+        };
+        let same_site = match env_var("CHAT_COOKIE_SAME_SITE").map(|value| value.to_lowercase()).as_deref() {
+            None | Some("lax") => SameSite::Lax,
+            Some("strict") => SameSite::Strict,
+            Some("none") => SameSite::None,
+            Some(value) => panic!("CHAT_COOKIE_SAME_SITE must be strict, lax or none, not {}", value),
+        };
+        // Browsers drop SameSite=None cookies that aren't secure
+        if same_site == SameSite::None && !secure {
+            panic!("CHAT_COOKIE_SAME_SITE=none requires CHAT_COOKIE_SECURE=true");
+        }
+        let max_age = env_seconds("CHAT_COOKIE_MAX_AGE");
+        let idle_timeout = env_seconds("CHAT_SESSION_IDLE_TIMEOUT").unwrap_or(DEFAULT_IDLE_TIMEOUT);
+
+        SessionConfig {
+            key: load_session_key(),
+            secure,
+            same_site,
+            max_age,
+            domain: env_var("CHAT_COOKIE_DOMAIN"),
+            idle_timeout,
+        }
+    }
+
+    pub fn session_store(&self, db: Pool<Sqlite>) -> SqliteSessionStore {
+        SqliteSessionStore::new(db, self.idle_timeout)
+    }
+
+    pub fn session_middleware(&self, store: SqliteSessionStore) -> SessionMiddleware<SqliteSessionStore> {
+        let session_length = match self.max_age {
+            Some(max_age) => SessionLength::Predetermined { max_session_length: Some(max_age) },
+            None => SessionLength::BrowserSession { state_ttl: Some(BROWSER_SESSION_TTL) },
+        };
+        SessionMiddleware::builder(store, self.key.clone())
+            .cookie_secure(self.secure)
+            .cookie_same_site(self.same_site)
+            .cookie_domain(self.domain.clone())
+            .session_length(session_length)
+            .build()
+    }
+}
+
+/// How JWT access and refresh tokens are signed and how long they last, read from the environment:
+///
+/// | variable                | default    |                                    |
+/// |-------------------------|------------|------------------------------------|
+/// | `CHAT_JWT_ACCESS_TTL`   | 15 minutes | seconds an access token is valid   |
+/// | `CHAT_JWT_REFRESH_TTL`  | 30 days    | seconds a refresh token is valid   |
+///
+/// Tokens are signed with a key derived from the session key, so they survive restarts too.
+#[derive(Clone)]
+pub struct JwtConfig {
+    pub encoding_key: EncodingKey,
+    pub decoding_key: DecodingKey,
+    pub access_ttl: Duration,
+    pub refresh_ttl: Duration,
+}
+
+impl JwtConfig {
+    pub fn from_env(session_config: &SessionConfig) -> Self {
+        // Separate from the key cookies are signed with, so one can't stand in for the other
+        let secret = Sha256::new()
+            .chain_update(b"chat jwt signing key")
+            .chain_update(session_config.key.master())
+            .finalize();
+        JwtConfig {
+            encoding_key: EncodingKey::from_secret(&secret),
+            decoding_key: DecodingKey::from_secret(&secret),
+            access_ttl: env_seconds("CHAT_JWT_ACCESS_TTL").unwrap_or(DEFAULT_ACCESS_TOKEN_TTL),
+            refresh_ttl: env_seconds("CHAT_JWT_REFRESH_TTL").unwrap_or(DEFAULT_REFRESH_TOKEN_TTL),
+        }
+    }
+}
+
+/// Which sites may call the API from a browser, read from the environment:
+///
+/// | variable            | default                 |                                   |
+/// |---------------------|-------------------------|-----------------------------------|
+/// | `CHAT_CORS_ORIGINS` | `http://localhost:8081` | comma separated list of origins   |
+#[derive(Clone)]
+pub struct CorsConfig {
+    pub origins: Vec<String>,
+}
+
+impl CorsConfig {
+    pub fn from_env() -> Self {
+        let origins: Vec<String> = match env_var("CHAT_CORS_ORIGINS") {
+            Some(value) => value.split(',')
+                .map(|origin| origin.trim().trim_end_matches('/').to_string())
+                .filter(|origin| !origin.is_empty())
+                .collect(),
+            None => vec![DEFAULT_CORS_ORIGIN.to_string()],
+        };
+        // Cookies are sent along, so any origin can't be allowed
+        for origin in &origins {
+            if origin == "*" || origin.parse::<header::HeaderValue>().is_err() || !origin.contains("://") {
+                panic!("CHAT_CORS_ORIGINS must list origins like https://chat.example.com, not {}", origin);
+            }
+        }
+        CorsConfig { origins }
+    }
+
+    pub fn cors(&self) -> Cors {
+        let cors = self.origins.iter().fold(Cors::default(), |cors, origin| cors.allowed_origin(origin));
+        cors.allowed_methods(vec!["GET", "POST"])
+            .allowed_headers(vec![
+                header::AUTHORIZATION,
+                header::ACCEPT,
+                header::CONTENT_TYPE,
+                header::UPGRADE,
+                header::CONNECTION,
+                header::SEC_WEBSOCKET_KEY,
+                header::SEC_WEBSOCKET_VERSION,
+            ])
+            .supports_credentials()
+            .max_age(3600)
+    }
+}
+
+/// The cost of new password hashes, read from the environment. The defaults
+/// follow the OWASP recommendation for Argon2id.
+///
+/// | variable                  | default |                             |
+/// |---------------------------|---------|-----------------------------|
+/// | `CHAT_ARGON2_MEMORY`      | `19456` | memory in KiB               |
+/// | `CHAT_ARGON2_ITERATIONS`  | `2`     | passes over the memory      |
+/// | `CHAT_ARGON2_PARALLELISM` | `1`     | lanes computed in parallel  |
+#[derive(Clone)]
+pub struct PasswordConfig {
+    pub params: Params,
+}
+
+impl PasswordConfig {
+    pub fn from_env() -> Self {
+        let number = |name: &str, default: u32| match env_var(name) {
+            None => default,
+            Some(value) => value.parse::<u32>().unwrap_or_else(|_| panic!("{} must be a number, not {}", name, value)),
+        };
+        let params = Params::new(
+            number("CHAT_ARGON2_MEMORY", Params::DEFAULT_M_COST),
+            number("CHAT_ARGON2_ITERATIONS", Params::DEFAULT_T_COST),
+            number("CHAT_ARGON2_PARALLELISM", Params::DEFAULT_P_COST),
+            None,
+        );
+        match params {
+            Ok(params) => PasswordConfig { params },
+            Err(e) => panic!("Invalid Argon2 parameters: {}", e),
+        }
+    }
+
+    pub fn argon2(&self) -> Argon2<'static> {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone())
+    }
+}
+
+/// A set, non-empty environment variable
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.trim().is_empty())
+}
+
+/// An environment variable holding a positive number of seconds
+fn env_seconds(name: &str) -> Option<Duration> {
+    env_var(name).map(|value| match value.parse::<i64>() {
+        Ok(seconds) if seconds > 0 => Duration::seconds(seconds),
+        _ => panic!("{} must be a positive number of seconds, not {}", name, value),
+    })
+}
+
+/// Load the key sessions are signed with, so logins survive restarts. Without
+/// `CHAT_SESSION_KEY` the key is read from its file, which is created on first start.
+fn load_session_key() -> Key {
+    if let Some(hex) = env_var("CHAT_SESSION_KEY") {
+        return match decode_hex(hex.trim()) {
+            Some(bytes) if bytes.len() >= SESSION_KEY_LEN => Key::from(&bytes),
+            _ => panic!("CHAT_SESSION_KEY must be at least {} hex digits", SESSION_KEY_LEN * 2),
+        };
+    }
+
+    let path = env_var("CHAT_SESSION_KEY_FILE").unwrap_or_else(|| DEFAULT_SESSION_KEY_FILE.to_string());
+    let path = Path::new(&path);
+    match std::fs::read(path) {
+        Ok(bytes) if bytes.len() >= SESSION_KEY_LEN => Key::from(&bytes),
+        Ok(_) => panic!("Session key file {} is shorter than {} bytes", path.display(), SESSION_KEY_LEN),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let key = Key::generate();
+            if let Err(e) = write_session_key(path, key.master()) {
+                panic!("Failed to save the session key to {}: {}", path.display(), e);
+            }
+            println!("Generated a new session key in {}", path.display());
+            key
+        }
+        Err(e) => panic!("Failed to read the session key from {}: {}", path.display(), e),
+    }
+}
+
+/// Write a new key file that only the server's user can read
+fn write_session_key(path: &Path, key: &[u8]) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(key)
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
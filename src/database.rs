@@ -1,33 +1,297 @@
 use sqlx::{sqlite::{self, SqlitePoolOptions}, Pool, Sqlite, migrate::MigrateDatabase};
-use sled;
-use sled::Db;
+use chrono::{DateTime, Local, NaiveDateTime, SecondsFormat, TimeZone, Utc};
+use sled::{Db, IVec};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Bound;
 use uuid::Uuid;
 
 use crate::channel::ChatMessage;
+use crate::record::{MessageKind, MessageRecord, RECORD_VERSION};
+use crate::direct::DIRECT_PREFIX;
+use crate::message::MessageRevision;
 use crate::user::UserStatus;
 
-pub fn append_chat_message_sled(sled_db: &Db, channel_name: &str, username: &str, message: &str) -> Result<(), sled::Error> {
-    let timestamp = chrono::Local::now()
-        .format("%Y-%m-%d %H:%M:%S%.3f")
-        .to_string();
-    
-    let unique_id = Uuid::new_v4();
-    let tree = sled_db.open_tree(channel_name)?;
-    let key = format!("{}:{}", timestamp, unique_id);
-    let value = format!("{}:{}", username, message);
+/// First byte of a deleted message's value before messages were stored as records
+const TOMBSTONE_MARKER: u8 = 0xFF;
+
+/// Suffixes of every sled tree belonging to a channel, appended to its storage key.
+/// `_replies` only exists in data from before messages were stored as records.
+const CHANNEL_TREE_SUFFIXES: [&str; 8] = ["", "_index", "_revisions", "_threads", "_replies", "_reactions", "_sequence", "_user_status"];
+
+/// Tree holding the storage keys of deleted channels, so late writes can't bring their trees back
+const DELETED_CHANNELS_TREE: &str = "deleted_channels";
+
+/// Entry of the `_sequence` tree holding the channel's last message key
+const LAST_KEY: &[u8] = b"last";
+
+/// Format of timestamps written before they were stored in UTC, in the server's local time
+const LOCAL_TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.3f";
+
+/// The sled trees holding one channel's messages and their metadata
+struct ChannelTrees {
+    /// Message key, see `next_message_key` -> encoded `MessageRecord`
+    messages: sled::Tree,
+    /// message ID -> message key
+    index: sled::Tree,
+    /// "{id}:{edited_at}" -> previous body of the message
+    revisions: sled::Tree,
+    /// "{parent id}:{reply key}" -> reply ID
+    threads: sled::Tree,
+    /// `LAST_KEY` -> the newest message key
+    sequence: sled::Tree,
+    /// "{id}:{emoji}:{username}" -> empty
+    reactions: sled::Tree,
+}
+
+/// Open one of a channel's trees, refusing channels that were deleted so their trees aren't recreated
+fn open_channel_tree(sled_db: &Db, channel_key: &str, suffix: &str) -> Result<sled::Tree, sled::Error> {
+    if sled_db.open_tree(DELETED_CHANNELS_TREE)?.contains_key(channel_key)? {
+        return Err(sled::Error::CollectionNotFound(IVec::from(channel_key)));
+    }
+    sled_db.open_tree(format!("{}{}", channel_key, suffix))
+}
+
+impl ChannelTrees {
+    fn open(sled_db: &Db, channel_key: &str) -> Result<Self, sled::Error> {
+        Ok(Self {
+            messages: open_channel_tree(sled_db, channel_key, "")?,
+            index: open_channel_tree(sled_db, channel_key, "_index")?,
+            revisions: open_channel_tree(sled_db, channel_key, "_revisions")?,
+            threads: open_channel_tree(sled_db, channel_key, "_threads")?,
+            sequence: open_channel_tree(sled_db, channel_key, "_sequence")?,
+            reactions: open_channel_tree(sled_db, channel_key, "_reactions")?,
+        })
+    }
+
+    fn flush(&self) -> Result<(), sled::Error> {
+        self.messages.flush()?;
+        self.index.flush()?;
+        self.revisions.flush()?;
+        self.threads.flush()?;
+        self.sequence.flush()?;
+        self.reactions.flush()?;
+        Ok(())
+    }
+
+    /// Group the reactions to a message by emoji
+    fn load_reactions(&self, message_id: &str) -> Result<BTreeMap<String, Vec<String>>, sled::Error> {
+        let prefix = format!("{}:", message_id);
+        let mut reactions: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for item in self.reactions.scan_prefix(prefix.as_bytes()) {
+            let (reaction_key, _) = item?;
+            // Emoji never contain ':', usernames might
+            let reaction = String::from_utf8_lossy(&reaction_key[prefix.len()..]).to_string();
+            if let Some((emoji, username)) = reaction.split_once(':') {
+                reactions.entry(emoji.to_string()).or_default().push(username.to_string());
+            }
+        }
+        Ok(reactions)
+    }
+
+    /// Decode a stored message and attach its thread metadata and reactions.
+    /// Deleted messages and values that can't be decoded give `None`.
+    fn load_message(&self, key: &[u8], value: &[u8]) -> Result<Option<ChatMessage>, sled::Error> {
+        let record = match MessageRecord::decode(value) {
+            Ok(record) => record,
+            Err(err) => {
+                println!("Skipping message {}: {}", String::from_utf8_lossy(key), err);
+                return Ok(None);
+            }
+        };
+        let mut chat_message = match to_chat_message(record) {
+            Some(chat_message) => chat_message,
+            None => return Ok(None),
+        };
+
+        // Thread entries are ordered by reply key, so the last one is the newest reply
+        let prefix = format!("{}:", chat_message.id);
+        let mut last_reply = None;
+        for item in self.threads.scan_prefix(prefix.as_bytes()) {
+            let (_, reply_id) = item?;
+            chat_message.reply_count += 1;
+            last_reply = Some(reply_id);
+        }
+        if let Some(reply_id) = last_reply.and_then(|reply_id| String::from_utf8(reply_id.to_vec()).ok()) {
+            chat_message.last_reply_at = self.get_record(&reply_id)?.map(|(_, reply)| reply.timestamp);
+        }
+
+        chat_message.reactions = self.load_reactions(&chat_message.id)?;
+
+        Ok(Some(chat_message))
+    }
+
+    /// Reserve the key of a new message written at `millis`. Keys are the UTC time in
+    /// milliseconds followed by a per-channel sequence number, both big-endian, so sled
+    /// orders messages as they were stored. The time never goes back within a channel,
+    /// even if the clock does.
+    fn next_message_key(&self, millis: u64) -> Result<[u8; 16], sled::Error> {
+        let (millis, sequence) = self.advance_sequence(millis)?;
+        Ok(message_key(millis, sequence))
+    }
+
+    /// Reserve the key of an imported message written at `millis`. It keeps its own
+    /// time, so it sorts among the messages around it, and takes the next sequence
+    /// number to stay unique.
+    fn imported_message_key(&self, millis: u64) -> Result<[u8; 16], sled::Error> {
+        let (_, sequence) = self.advance_sequence(millis)?;
+        Ok(message_key(millis, sequence))
+    }
+
+    /// Count up the channel's sequence and return the new last key's time and sequence number
+    fn advance_sequence(&self, millis: u64) -> Result<(u64, u64), sled::Error> {
+        let last = self.sequence.update_and_fetch(LAST_KEY, |last| {
+            let (last_millis, last_sequence) = last.and_then(parse_message_key).unwrap_or((0, 0));
+            Some(message_key(millis.max(last_millis), last_sequence + 1).to_vec())
+        })?;
+        Ok(last
+            .as_deref()
+            .and_then(parse_message_key)
+            .expect("The sequence tree holds a message key"))
+    }
+
+    /// Remove a message with its index entry, revisions and reactions, and take it out of its thread
+    fn remove_message(&self, key: &[u8], record: &MessageRecord) -> Result<(), sled::Error> {
+        self.messages.remove(key)?;
+        self.index.remove(record.id.as_bytes())?;
+        if let Some(parent_id) = &record.reply_to {
+            self.threads.remove(thread_key(parent_id, key))?;
+        }
+        let prefix = format!("{}:", record.id);
+        for tree in [&self.revisions, &self.reactions] {
+            for item in tree.scan_prefix(prefix.as_bytes()) {
+                tree.remove(item?.0)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn get_message(&self, message_id: &str) -> Result<Option<ChatMessage>, sled::Error> {
+        let key = match self.index.get(message_id)? {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        match self.messages.get(&key)? {
+            Some(value) => self.load_message(&key, &value),
+            None => Ok(None),
+        }
+    }
+
+    /// Get the stored record of a message that is not deleted, together with its key
+    fn get_record(&self, message_id: &str) -> Result<Option<(IVec, MessageRecord)>, sled::Error> {
+        let key = match self.index.get(message_id)? {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        let value = match self.messages.get(&key)? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        match MessageRecord::decode(&value) {
+            Ok(record) if !record.is_deleted() => Ok(Some((key, record))),
+            Ok(_) => Ok(None),
+            Err(err) => {
+                println!("Skipping message {}: {}", message_id, err);
+                Ok(None)
+            }
+        }
+    }
+}
+
+fn message_key(millis: u64, sequence: u64) -> [u8; 16] {
+    let mut key = [0; 16];
+    key[..8].copy_from_slice(&millis.to_be_bytes());
+    key[8..].copy_from_slice(&sequence.to_be_bytes());
+    key
+}
+
+/// Smallest message key at `time`, to bound history by time
+pub fn time_key(time: DateTime<Utc>) -> IVec {
+    IVec::from(&message_key(time.timestamp_millis().max(0) as u64, 0))
+}
+
+/// Split a message key into its time and sequence number
+fn parse_message_key(key: &[u8]) -> Option<(u64, u64)> {
+    let key: [u8; 16] = key.try_into().ok()?;
+    let (millis, sequence) = key.split_at(8);
+    Some((u64::from_be_bytes(millis.try_into().ok()?), u64::from_be_bytes(sequence.try_into().ok()?)))
+}
+
+/// Key of a reply's entry in the `_threads` tree
+fn thread_key(parent_id: &str, reply_key: &[u8]) -> Vec<u8> {
+    let mut key = format!("{}:", parent_id).into_bytes();
+    key.extend_from_slice(reply_key);
+    key
+}
+
+/// Format a time as stored, e.g. "2024-03-10T14:05:09.123Z"
+pub fn format_timestamp(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// Read a timestamp written in the server's local time before timestamps were stored in UTC
+fn parse_local_timestamp(timestamp: &str) -> Option<DateTime<Utc>> {
+    let naive = NaiveDateTime::parse_from_str(timestamp, LOCAL_TIMESTAMP_FORMAT).ok()?;
+    Local.from_local_datetime(&naive).earliest().map(|time| time.with_timezone(&Utc))
+}
+
+/// Store a chat message, optionally as a reply in the thread of `reply_to`,
+/// and return it with its server-assigned ID and timestamp
+pub fn append_chat_message_sled(
+    sled_db: &Db,
+    channel_key: &str,
+    author_id: u32,
+    username: &str,
+    message: &str,
+    reply_to: Option<&str>,
+) -> Result<ChatMessage, sled::Error> {
+    let now = Utc::now();
+    let unique_id = Uuid::new_v4().to_string();
+    let trees = ChannelTrees::open(sled_db, channel_key)?;
+    let key = trees.next_message_key(now.timestamp_millis() as u64)?;
+    let record = MessageRecord {
+        id: unique_id.clone(),
+        timestamp: format_timestamp(now),
+        author_id: Some(author_id),
+        author: username.to_string(),
+        body: message.to_string(),
+        kind: MessageKind::Text,
+        edited_at: None,
+        deleted_by: None,
+        attachments: Vec::new(),
+        reply_to: reply_to.map(str::to_string),
+    };
     
-    tree.insert(key.as_bytes(), value.as_bytes())?;
-    tree.flush()?;
-    Ok(())
+    trees.messages.insert(key, record.encode())?;
+    trees.index.insert(unique_id.as_bytes(), &key)?;
+    if let Some(parent_id) = reply_to {
+        trees.threads.insert(thread_key(parent_id, &key), unique_id.as_bytes())?;
+    }
+    trees.flush()?;
+
+    Ok(to_chat_message(record).expect("A new message is not deleted"))
 }
 
-pub fn append_user_status_sled(sled_db: &Db, channel_name: &str, username: &str, online: bool) -> Result<(), sled::Error> {
-    let timestamp = chrono::Local::now()
-        .format("%Y-%m-%d %H:%M:%S%.3f")
-        .to_string();
+/// Store a message imported from another chat service at its original time. Returns
+/// false if a message with the record's ID is already stored.
+pub fn import_chat_message_sled(sled_db: &Db, channel_key: &str, record: &MessageRecord, millis: u64) -> Result<bool, sled::Error> {
+    let trees = ChannelTrees::open(sled_db, channel_key)?;
+    if trees.index.contains_key(record.id.as_bytes())? {
+        return Ok(false);
+    }
+
+    let key = trees.imported_message_key(millis)?;
+    trees.messages.insert(key, record.encode())?;
+    trees.index.insert(record.id.as_bytes(), &key)?;
+    if let Some(parent_id) = &record.reply_to {
+        trees.threads.insert(thread_key(parent_id, &key), record.id.as_bytes())?;
+    }
+    Ok(true)
+}
+
+pub fn append_user_status_sled(sled_db: &Db, channel_key: &str, username: &str, online: bool) -> Result<(), sled::Error> {
+    let timestamp = format_timestamp(Utc::now());
     
-    let tree_name = format!("{}_user_status", channel_name);
-    let tree = sled_db.open_tree(&tree_name)?;
+    let tree = open_channel_tree(sled_db, channel_key, "_user_status")?;
 
     let key = username.to_string();
     let status = if online { "Online" } else { "Offline" };
@@ -38,47 +302,266 @@ pub fn append_user_status_sled(sled_db: &Db, channel_name: &str, username: &str,
     Ok(())
 }
 
-pub fn get_chat_history_sled(sled_db: &Db, channel_name: &str) -> Result<Vec<ChatMessage>, sled::Error> {
-    // println!("=== BEGIN CHAT HISTORY FETCH ===");
-    // println!("Getting history for channel: {}", channel_name);
-    
-    let tree = match sled_db.open_tree(channel_name) {
-        Ok(t) => t,
-        Err(e) => return Err(e),
+/// Turn a stored record into the message sent to clients, or `None` if it was deleted
+fn to_chat_message(record: MessageRecord) -> Option<ChatMessage> {
+    if record.is_deleted() {
+        return None;
+    }
+
+    Some(ChatMessage {
+        id: record.id,
+        timestamp: record.timestamp,
+        author_id: record.author_id,
+        username: record.author,
+        message: record.body,
+        edited_at: record.edited_at,
+        reply_to: record.reply_to,
+        reply_count: 0,
+        last_reply_at: None,
+        reactions: BTreeMap::new(),
+        attachments: record.attachments,
+    })
+}
+
+/// Get every message of a channel that is not deleted, replies included, in stored order.
+/// Thread metadata and reactions are not loaded.
+pub fn list_messages_sled(sled_db: &Db, channel_key: &str) -> Result<Vec<ChatMessage>, sled::Error> {
+    let messages = open_channel_tree(sled_db, channel_key, "")?;
+    let mut result = Vec::new();
+    for item in messages.iter() {
+        let (key, value) = item?;
+        match MessageRecord::decode(&value) {
+            Ok(record) => result.extend(to_chat_message(record)),
+            Err(err) => println!("Skipping message {}: {}", String::from_utf8_lossy(&key), err),
+        }
+    }
+    Ok(result)
+}
+
+/// Look up the sled key of a message by its ID
+pub fn find_message_key_sled(sled_db: &Db, channel_key: &str, message_id: &str) -> Result<Option<IVec>, sled::Error> {
+    let index = open_channel_tree(sled_db, channel_key, "_index")?;
+    index.get(message_id)
+}
+
+/// Get a single message by its ID
+pub fn get_chat_message_sled(sled_db: &Db, channel_key: &str, message_id: &str) -> Result<Option<ChatMessage>, sled::Error> {
+    ChannelTrees::open(sled_db, channel_key)?.get_message(message_id)
+}
+
+/// Replace the body of a message, keeping the previous body as a revision
+pub fn edit_chat_message_sled(sled_db: &Db, channel_key: &str, message_id: &str, message: &str) -> Result<Option<ChatMessage>, sled::Error> {
+    let trees = ChannelTrees::open(sled_db, channel_key)?;
+    let (key, mut record) = match trees.get_record(message_id)? {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+
+    let edited_at = format_timestamp(Utc::now());
+    let revision_key = format!("{}:{}", message_id, edited_at);
+    trees.revisions.insert(revision_key.as_bytes(), record.body.as_bytes())?;
+
+    record.body = message.to_string();
+    record.edited_at = Some(edited_at);
+    let value = record.encode();
+    trees.messages.insert(&key, value.as_slice())?;
+    trees.flush()?;
+
+    trees.load_message(&key, &value)
+}
+
+/// Mark a message as deleted, dropping its body, revisions, reactions and its place in
+/// a thread. The record stays, so the message is reported as gone rather than unknown.
+/// Returns the message as it was before deletion.
+pub fn delete_chat_message_sled(sled_db: &Db, channel_key: &str, message_id: &str, deleted_by: &str) -> Result<Option<ChatMessage>, sled::Error> {
+    let trees = ChannelTrees::open(sled_db, channel_key)?;
+    let previous = match trees.get_message(message_id)? {
+        Some(previous) => previous,
+        None => return Ok(None),
+    };
+    let (key, mut record) = match trees.get_record(message_id)? {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+
+    record.body.clear();
+    record.attachments.clear();
+    record.deleted_by = Some(deleted_by.to_string());
+    trees.messages.insert(&key, record.encode())?;
+
+    let prefix = format!("{}:", message_id);
+    for item in trees.revisions.scan_prefix(prefix.as_bytes()) {
+        let (revision_key, _) = item?;
+        trees.revisions.remove(revision_key)?;
+    }
+    for item in trees.reactions.scan_prefix(prefix.as_bytes()) {
+        let (reaction_key, _) = item?;
+        trees.reactions.remove(reaction_key)?;
+    }
+    if let Some(parent_id) = &previous.reply_to {
+        trees.threads.remove(thread_key(parent_id, &key))?;
+    }
+    trees.flush()?;
+    Ok(Some(previous))
+}
+
+/// Add or remove `username`'s `emoji` reaction to a message. Returns the message's
+/// reactions afterwards, or `None` if the message does not exist.
+pub fn set_reaction_sled(
+    sled_db: &Db,
+    channel_key: &str,
+    message_id: &str,
+    emoji: &str,
+    username: &str,
+    add: bool,
+) -> Result<Option<BTreeMap<String, Vec<String>>>, sled::Error> {
+    let trees = ChannelTrees::open(sled_db, channel_key)?;
+    if trees.get_message(message_id)?.is_none() {
+        return Ok(None);
+    }
+
+    let reaction_key = format!("{}:{}:{}", message_id, emoji, username);
+    if add {
+        trees.reactions.insert(reaction_key.as_bytes(), &[])?;
+    } else {
+        trees.reactions.remove(reaction_key.as_bytes())?;
+    }
+    trees.reactions.flush()?;
+
+    trees.load_reactions(message_id).map(Some)
+}
+
+/// Get the previous bodies of a message, oldest first
+pub fn get_message_revisions_sled(sled_db: &Db, channel_key: &str, message_id: &str) -> Result<Vec<MessageRevision>, sled::Error> {
+    let revisions = open_channel_tree(sled_db, channel_key, "_revisions")?;
+    let prefix = format!("{}:", message_id);
+
+    let mut result = Vec::new();
+    for item in revisions.scan_prefix(prefix.as_bytes()) {
+        let (key, value) = item?;
+        if let (Ok(replaced_at), Ok(message)) = (String::from_utf8(key[prefix.len()..].to_vec()), String::from_utf8(value.to_vec())) {
+            result.push(MessageRevision { message, replaced_at });
+        }
+    }
+    Ok(result)
+}
+
+/// Get the replies in the thread of a message, oldest first
+pub fn get_thread_sled(sled_db: &Db, channel_key: &str, parent_id: &str) -> Result<Vec<ChatMessage>, sled::Error> {
+    get_thread_between_sled(sled_db, channel_key, parent_id, None, None)
+}
+
+/// Get the replies in a thread stored between the keys `after` and `before`, both excluded,
+/// like `get_chat_history_sled` bounds top-level messages
+pub fn get_thread_between_sled(
+    sled_db: &Db,
+    channel_key: &str,
+    parent_id: &str,
+    after: Option<&[u8]>,
+    before: Option<&[u8]>,
+) -> Result<Vec<ChatMessage>, sled::Error> {
+    let trees = ChannelTrees::open(sled_db, channel_key)?;
+    let prefix = format!("{}:", parent_id);
+
+    let mut replies = Vec::new();
+    for item in trees.threads.scan_prefix(prefix.as_bytes()) {
+        let (thread_key, reply_id) = item?;
+        let reply_key = &thread_key[prefix.len()..];
+        if after.is_some_and(|after| reply_key <= after) || before.is_some_and(|before| reply_key >= before) {
+            continue;
+        }
+        if let Ok(reply_id) = std::str::from_utf8(&reply_id) {
+            if let Some(reply) = trees.get_message(reply_id)? {
+                replies.push(reply);
+            }
+        }
+    }
+    Ok(replies)
+}
+
+/// Get up to `limit` replies stored between `after` and `before` whose thread's parent
+/// is stored before `after`, grouped by thread. Each reply comes with its threads tree key,
+/// which `start` takes to continue after it.
+pub fn get_replies_to_older_sled(
+    sled_db: &Db,
+    channel_key: &str,
+    after: &[u8],
+    before: Option<&[u8]>,
+    start: Option<IVec>,
+    limit: usize,
+) -> Result<Vec<(IVec, ChatMessage)>, sled::Error> {
+    let trees = ChannelTrees::open(sled_db, channel_key)?;
+    let lower = start.map_or(Bound::Unbounded, Bound::Excluded);
+
+    let mut replies = Vec::new();
+    let mut parent: Option<(Vec<u8>, bool)> = None;
+    for item in trees.threads.range::<IVec, _>((lower, Bound::Unbounded)) {
+        if replies.len() >= limit {
+            break;
+        }
+        let (thread_key, reply_id) = item?;
+        let (parent_id, reply_key) = match thread_key.iter().position(|b| *b == b':') {
+            Some(pos) => (&thread_key[..pos], &thread_key[pos + 1..]),
+            None => continue,
+        };
+        if reply_key <= after || before.is_some_and(|before| reply_key >= before) {
+            continue;
+        }
+
+        // Replies of one thread are next to each other, so look each parent up once
+        let parent_is_older = match &parent {
+            Some((id, older)) if id.as_slice() == parent_id => *older,
+            _ => {
+                let older = trees.index.get(parent_id)?.is_none_or(|parent_key| &*parent_key <= after);
+                parent = Some((parent_id.to_vec(), older));
+                older
+            }
+        };
+        if !parent_is_older {
+            continue;
+        }
+        if let Ok(reply_id) = std::str::from_utf8(&reply_id) {
+            if let Some(reply) = trees.get_message(reply_id)? {
+                replies.push((thread_key.clone(), reply));
+            }
+        }
+    }
+    Ok(replies)
+}
+
+/// Get up to `limit` top-level messages of a channel in chronological order.
+/// With `after`, the page starts right after that key; otherwise it ends
+/// right before `before` (or at the newest message).
+pub fn get_chat_history_sled(
+    sled_db: &Db,
+    channel_key: &str,
+    before: Option<IVec>,
+    after: Option<IVec>,
+    limit: usize,
+) -> Result<Vec<ChatMessage>, sled::Error> {
+    let trees = ChannelTrees::open(sled_db, channel_key)?;
+
+    let forward = after.is_some();
+    let lower = after.map_or(Bound::Unbounded, Bound::Excluded);
+    let upper = before.map_or(Bound::Unbounded, Bound::Excluded);
+    let range = trees.messages.range::<IVec, _>((lower, upper));
+    let items: Box<dyn Iterator<Item = Result<(IVec, IVec), sled::Error>>> = if forward {
+        Box::new(range)
+    } else {
+        Box::new(range.rev())
     };
 
     let mut messages = Vec::new();
-    
-    for item in tree.iter() {
+    for item in items {
+        if messages.len() >= limit {
+            break;
+        }
         match item {
             Ok((key, value)) => {
-                if let (Ok(key_str), Ok(value_str)) = (String::from_utf8(key.to_vec()), String::from_utf8(value.to_vec())) {
-                    // println!("\nProcessing message:");
-                    // println!("Key: {}", key_str);
-                    // println!("Value: {}", value_str);
-                    
-                    // Split the key into timestamp and username
-                    // Format is "YYYY-MM-DD HH:MM:username"
-                    if let Some(last_colon_pos) = key_str.rfind(':') {
-                        let (timestamp, _) = key_str.split_at(last_colon_pos); // Remove the leading ':'
-                        
-                        // Get message from value
-                        // Value format is "username:message"
-                        if let Some((username, message)) = value_str.split_once(':') {
-                            // println!("✓ Parsed successfully:");
-                            // println!("  Timestamp: {}", timestamp);
-                            // println!("  Username: {}", username);
-                            // println!("  Message: {}", message);
-                            
-                            if message != "ping" {
-                                let chat_message = ChatMessage {
-                                    timestamp: timestamp.to_string(),
-                                    username: username.to_string(),
-                                    message: message.to_string(),
-                                };
-                                messages.push(chat_message);
-                            }
-                        }
+                // Replies are only listed in their thread
+                if let Some(chat_message) = trees.load_message(&key, &value)? {
+                    if chat_message.reply_to.is_none() {
+                        messages.push(chat_message);
                     }
                 }
             }
@@ -86,13 +569,438 @@ pub fn get_chat_history_sled(sled_db: &Db, channel_name: &str) -> Result<Vec<Cha
         }
     }
 
-    messages.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+    if !forward {
+        messages.reverse();
+    }
     Ok(messages)
 }
 
-pub fn get_user_status_sled(sled_db: &Db, channel_name: &str) -> Result<Vec<UserStatus>, sled::Error> {
-    let tree_name = format!("{}_user_status", channel_name);
-    let tree = sled_db.open_tree(&tree_name)?;
+/// Drop all sled trees of a deleted channel
+pub fn delete_channel_sled(sled_db: &Db, channel_key: &str) -> Result<(), sled::Error> {
+    // Channel IDs are never reused, so the key stays retired
+    let deleted = sled_db.open_tree(DELETED_CHANNELS_TREE)?;
+    deleted.insert(channel_key, &[])?;
+    deleted.flush()?;
+    for suffix in CHANNEL_TREE_SUFFIXES {
+        sled_db.drop_tree(format!("{}{}", channel_key, suffix))?;
+    }
+    Ok(())
+}
+
+/// Key of the sled trees of the channel with this `Channel.id`
+pub fn channel_storage_key(channel_id: i64) -> String {
+    format!("channel:{}", channel_id)
+}
+
+/// Key of the sled trees of the direct conversation with this `DirectMessage.id`
+pub fn direct_storage_key(direct_id: i64) -> String {
+    format!("direct:{}", direct_id)
+}
+
+/// Look up the key a channel's sled trees are stored under, or `None` if there is
+/// no such channel. Keys never contain user input, so a channel can't be named
+/// after another channel's trees or an internal tree.
+pub async fn find_storage_key(sqlite_db: &Pool<Sqlite>, channel_name: &str) -> Result<Option<String>, sqlx::Error> {
+    if let Some(id) = channel_name.strip_prefix(DIRECT_PREFIX) {
+        let id: i64 = match id.parse() {
+            Ok(id) => id,
+            Err(_) => return Ok(None),
+        };
+        let id: Option<i64> = sqlx::query_scalar("SELECT id FROM DirectMessage WHERE id = ?;")
+            .bind(id)
+            .fetch_optional(sqlite_db)
+            .await?;
+        return Ok(id.map(direct_storage_key));
+    }
+
+    let id: Option<i64> = sqlx::query_scalar("SELECT id FROM Channel WHERE Name = ?;")
+        .bind(channel_name)
+        .fetch_optional(sqlite_db)
+        .await?;
+    Ok(id.map(channel_storage_key))
+}
+
+/// Move all sled trees stored under `old_key` to `new_key`
+fn move_channel_trees(sled_db: &Db, old_key: &str, new_key: &str) -> Result<usize, sled::Error> {
+    let mut count = 0;
+    for suffix in CHANNEL_TREE_SUFFIXES {
+        let old_tree_name = format!("{}{}", old_key, suffix);
+        if !sled_db.tree_names().iter().any(|name| name == old_tree_name.as_bytes()) {
+            continue;
+        }
+
+        let old_tree = sled_db.open_tree(&old_tree_name)?;
+        let new_tree = sled_db.open_tree(format!("{}{}", new_key, suffix))?;
+        for item in old_tree.iter() {
+            let (key, value) = item?;
+            new_tree.insert(key, value)?;
+            count += 1;
+        }
+        new_tree.flush()?;
+        sled_db.drop_tree(&old_tree_name)?;
+    }
+    Ok(count)
+}
+
+/// Move channel data stored under raw channel names to the channels' storage keys once
+pub async fn migrate_storage_keys(sqlite_db: &Pool<Sqlite>, sled_db: &Db) {
+    let meta = sled_db.open_tree("__meta").expect("Failed to open meta tree");
+    if let Ok(Some(_)) = meta.get("storage_keyed_by_id") {
+        return;
+    }
+
+    let channels: Vec<(i64, String)> = match sqlx::query_as("SELECT id, Name FROM Channel;").fetch_all(sqlite_db).await {
+        Ok(channels) => channels,
+        Err(e) => panic!("Failed to list channels: {}", e),
+    };
+    let directs: Vec<i64> = match sqlx::query_scalar("SELECT id FROM DirectMessage;").fetch_all(sqlite_db).await {
+        Ok(directs) => directs,
+        Err(e) => panic!("Failed to list direct conversations: {}", e),
+    };
+
+    let moves = channels
+        .into_iter()
+        .map(|(id, name)| (name, channel_storage_key(id)))
+        .chain(directs.into_iter().map(|id| (format!("{}{}", DIRECT_PREFIX, id), direct_storage_key(id))));
+    for (old_key, new_key) in moves {
+        match move_channel_trees(sled_db, &old_key, &new_key) {
+            Ok(0) => {}
+            Ok(count) => println!("Moved {} entries of channel {} to {}", count, old_key, new_key),
+            Err(e) => panic!("Failed to migrate channel {}: {}", old_key, e),
+        }
+    }
+
+    meta.insert("storage_keyed_by_id", "1").expect("Failed to update meta tree");
+}
+
+/// Convert messages stored as "username:message" strings or tombstones into records once.
+/// Edit times come from the revisions and thread parents from the old `_replies` tree.
+pub async fn migrate_message_records(sqlite_db: &Pool<Sqlite>, sled_db: &Db) {
+    let meta = sled_db.open_tree("__meta").expect("Failed to open meta tree");
+    if let Ok(Some(_)) = meta.get("messages_stored_as_records") {
+        return;
+    }
+
+    let users: HashMap<String, u32> = match sqlx::query_as("SELECT Username, id FROM Users;").fetch_all(sqlite_db).await {
+        Ok(users) => users.into_iter().collect(),
+        Err(e) => panic!("Failed to list users: {}", e),
+    };
+
+    for channel_key in list_storage_keys(sqlite_db).await {
+        match convert_channel_messages(sled_db, &channel_key, &users) {
+            Ok(0) => {}
+            Ok(count) => println!("Converted {} messages in channel {}", count, channel_key),
+            Err(e) => panic!("Failed to convert messages of channel {}: {}", channel_key, e),
+        }
+    }
+
+    meta.insert("messages_stored_as_records", "1").expect("Failed to update meta tree");
+}
+
+fn convert_channel_messages(sled_db: &Db, channel_key: &str, users: &HashMap<String, u32>) -> Result<usize, sled::Error> {
+    let trees = ChannelTrees::open(sled_db, channel_key)?;
+    let replies = open_channel_tree(sled_db, channel_key, "_replies")?;
+
+    let mut count = 0;
+    for item in trees.messages.iter() {
+        let (key, value) = item?;
+        if value.first() == Some(&RECORD_VERSION) {
+            continue;
+        }
+
+        let (timestamp, id) = match std::str::from_utf8(&key).ok().and_then(|key| key.rsplit_once(':')) {
+            Some((timestamp, id)) => (timestamp.to_string(), id.to_string()),
+            None => {
+                println!("Leaving unreadable message key {} in channel {}", String::from_utf8_lossy(&key), channel_key);
+                continue;
+            }
+        };
+
+        // Tombstones only kept who deleted the message
+        let (author, body, deleted_by) = if value.first() == Some(&TOMBSTONE_MARKER) {
+            (String::new(), String::new(), Some(String::from_utf8_lossy(&value[1..]).to_string()))
+        } else {
+            match std::str::from_utf8(&value).ok().and_then(|value| value.split_once(':')) {
+                Some((author, body)) => (author.to_string(), body.to_string(), None),
+                None => {
+                    println!("Leaving unreadable message {} in channel {}", id, channel_key);
+                    continue;
+                }
+            }
+        };
+
+        // The newest revision key holds the time of the latest edit
+        let prefix = format!("{}:", id);
+        let edited_at = match trees.revisions.scan_prefix(prefix.as_bytes()).next_back() {
+            Some(item) => String::from_utf8(item?.0[prefix.len()..].to_vec()).ok(),
+            None => None,
+        };
+        let reply_to = replies.get(&id)?.and_then(|parent_id| String::from_utf8(parent_id.to_vec()).ok());
+
+        let record = MessageRecord {
+            id,
+            timestamp,
+            author_id: users.get(&author).copied(),
+            author,
+            body,
+            kind: MessageKind::Text,
+            edited_at,
+            deleted_by,
+            attachments: Vec::new(),
+            reply_to,
+        };
+        trees.messages.insert(&key, record.encode())?;
+        count += 1;
+    }
+
+    trees.flush()?;
+    sled_db.drop_tree(format!("{}_replies", channel_key))?;
+    Ok(count)
+}
+
+/// Move messages stored under "{local timestamp}:{id}" keys to sequence keys once, in
+/// their stored order, and convert their local timestamps to UTC
+pub async fn migrate_message_keys(sqlite_db: &Pool<Sqlite>, sled_db: &Db) {
+    let meta = sled_db.open_tree("__meta").expect("Failed to open meta tree");
+    if let Ok(Some(_)) = meta.get("messages_keyed_by_sequence") {
+        return;
+    }
+
+    for channel_key in list_storage_keys(sqlite_db).await {
+        match rekey_channel_messages(sled_db, &channel_key) {
+            Ok(0) => {}
+            Ok(count) => println!("Rekeyed {} messages in channel {}", count, channel_key),
+            Err(e) => panic!("Failed to rekey messages of channel {}: {}", channel_key, e),
+        }
+    }
+
+    meta.insert("messages_keyed_by_sequence", "1").expect("Failed to update meta tree");
+}
+
+fn rekey_channel_messages(sled_db: &Db, channel_key: &str) -> Result<usize, sled::Error> {
+    let trees = ChannelTrees::open(sled_db, channel_key)?;
+
+    let mut count = 0;
+    let mut last_millis = 0;
+    let stored = trees.messages.iter().collect::<Result<Vec<_>, _>>()?;
+    for (old_key, value) in stored {
+        if parse_message_key(&old_key).is_some() {
+            continue;
+        }
+        let mut record = match MessageRecord::decode(&value) {
+            Ok(record) => record,
+            Err(err) => {
+                println!("Leaving message {} in channel {}: {}", String::from_utf8_lossy(&old_key), channel_key, err);
+                continue;
+            }
+        };
+
+        // Messages keep their stored order even if the local clock went back, e.g. for DST
+        if let Some(time) = parse_local_timestamp(&record.timestamp) {
+            record.timestamp = format_timestamp(time);
+            last_millis = time.timestamp_millis() as u64;
+        }
+        if let Some(time) = record.edited_at.as_deref().and_then(parse_local_timestamp) {
+            record.edited_at = Some(format_timestamp(time));
+        }
+
+        let key = trees.next_message_key(last_millis)?;
+        trees.messages.insert(key, record.encode())?;
+        trees.messages.remove(&old_key)?;
+        trees.index.insert(record.id.as_bytes(), &key)?;
+        count += 1;
+    }
+
+    // Thread entries embed the reply's key
+    trees.threads.clear()?;
+    for item in trees.messages.iter() {
+        let (key, value) = item?;
+        if let Ok(record) = MessageRecord::decode(&value) {
+            if let (Some(parent_id), false) = (&record.reply_to, record.is_deleted()) {
+                trees.threads.insert(thread_key(parent_id, &key), record.id.as_bytes())?;
+            }
+        }
+    }
+
+    let revisions = trees.revisions.iter().collect::<Result<Vec<_>, _>>()?;
+    for (old_key, body) in revisions {
+        let revision = String::from_utf8_lossy(&old_key).to_string();
+        if let Some((id, Some(time))) = revision.split_once(':').map(|(id, replaced_at)| (id, parse_local_timestamp(replaced_at))) {
+            trees.revisions.insert(format!("{}:{}", id, format_timestamp(time)).as_bytes(), body)?;
+            trees.revisions.remove(&old_key)?;
+        }
+    }
+
+    trees.flush()?;
+    Ok(count)
+}
+
+/// Remove the "ping" messages old clients stored as their keepalive from every channel.
+/// Returns how many were removed.
+pub async fn strip_ping_messages(sqlite_db: &Pool<Sqlite>, sled_db: &Db) -> usize {
+    let mut total = 0;
+    for channel_key in list_storage_keys(sqlite_db).await {
+        match strip_channel_pings(sled_db, &channel_key) {
+            Ok(0) => {}
+            Ok(count) => {
+                println!("Removed {} pings from channel {}", count, channel_key);
+                total += count;
+            }
+            Err(e) => println!("Failed to remove pings from channel {}: {}", channel_key, e),
+        }
+    }
+    total
+}
+
+fn strip_channel_pings(sled_db: &Db, channel_key: &str) -> Result<usize, sled::Error> {
+    let trees = ChannelTrees::open(sled_db, channel_key)?;
+
+    let mut count = 0;
+    let stored = trees.messages.iter().collect::<Result<Vec<_>, _>>()?;
+    for (key, value) in stored {
+        let record = match MessageRecord::decode(&value) {
+            Ok(record) if !record.is_deleted() && record.kind == MessageKind::Text && record.body == "ping" => record,
+            _ => continue,
+        };
+
+        trees.remove_message(&key, &record)?;
+        count += 1;
+    }
+
+    trees.flush()?;
+    Ok(count)
+}
+
+/// Number of messages stored for a channel, counting replies and deleted messages
+pub fn count_messages_sled(sled_db: &Db, channel_key: &str) -> Result<usize, sled::Error> {
+    Ok(open_channel_tree(sled_db, channel_key, "")?.len())
+}
+
+/// Remove up to about `batch_size` of a channel's oldest messages: those stored before
+/// `older_than`, and the oldest `excess` ones. A removed message takes the replies in its
+/// thread along. Returns the IDs of the removed messages, oldest first.
+pub fn prune_channel_sled(
+    sled_db: &Db,
+    channel_key: &str,
+    older_than: Option<DateTime<Utc>>,
+    excess: usize,
+    batch_size: usize,
+) -> Result<Vec<String>, sled::Error> {
+    let trees = ChannelTrees::open(sled_db, channel_key)?;
+    let expired_before = older_than.map(time_key);
+
+    // Pick the batch first, so the trees aren't changed while iterating them
+    let mut selected: Vec<(IVec, MessageRecord)> = Vec::new();
+    let mut selected_ids = HashSet::new();
+    for item in trees.messages.iter() {
+        let (key, value) = item?;
+        let expired = expired_before.as_ref().is_some_and(|expired_before| key < *expired_before);
+        if selected.len() >= batch_size || (!expired && selected.len() >= excess) {
+            break;
+        }
+
+        let record = match MessageRecord::decode(&value) {
+            Ok(record) => record,
+            Err(err) => {
+                println!("Skipping message {}: {}", String::from_utf8_lossy(&key), err);
+                continue;
+            }
+        };
+        if !selected_ids.insert(record.id.clone()) {
+            // Already picked as a reply of its thread's parent
+            continue;
+        }
+
+        let prefix = format!("{}:", record.id);
+        let mut replies = Vec::new();
+        for thread_entry in trees.threads.scan_prefix(prefix.as_bytes()) {
+            // Deleted replies have left the thread, and are pruned when their own turn comes
+            let (_, reply_id) = thread_entry?;
+            let reply_key = match trees.index.get(&reply_id)? {
+                Some(reply_key) => reply_key,
+                None => continue,
+            };
+            if let Some(value) = trees.messages.get(&reply_key)? {
+                if let Ok(reply) = MessageRecord::decode(&value) {
+                    replies.push((reply_key, reply));
+                }
+            }
+        }
+        selected.push((key, record));
+        for (reply_key, reply) in replies {
+            if selected_ids.insert(reply.id.clone()) {
+                selected.push((reply_key, reply));
+            }
+        }
+    }
+
+    let mut removed = Vec::with_capacity(selected.len());
+    for (key, record) in selected {
+        trees.remove_message(&key, &record)?;
+        removed.push(record.id);
+    }
+    trees.flush()?;
+    Ok(removed)
+}
+
+/// Storage keys of every channel and direct conversation
+pub async fn list_storage_keys(sqlite_db: &Pool<Sqlite>) -> Vec<String> {
+    let channels: Vec<i64> = match sqlx::query_scalar("SELECT id FROM Channel;").fetch_all(sqlite_db).await {
+        Ok(channels) => channels,
+        Err(e) => panic!("Failed to list channels: {}", e),
+    };
+    let directs: Vec<i64> = match sqlx::query_scalar("SELECT id FROM DirectMessage;").fetch_all(sqlite_db).await {
+        Ok(directs) => directs,
+        Err(e) => panic!("Failed to list direct conversations: {}", e),
+    };
+
+    channels
+        .into_iter()
+        .map(channel_storage_key)
+        .chain(directs.into_iter().map(direct_storage_key))
+        .collect()
+}
+
+/// Index the messages of every channel by ID once, for data stored before the index existed
+pub async fn backfill_message_index(sqlite_db: &Pool<Sqlite>, sled_db: &Db) {
+    let meta = sled_db.open_tree("__meta").expect("Failed to open meta tree");
+    if let Ok(Some(_)) = meta.get("message_index_backfilled") {
+        return;
+    }
+
+    let channels: Vec<i64> = match sqlx::query_scalar("SELECT id FROM Channel;").fetch_all(sqlite_db).await {
+        Ok(channels) => channels,
+        Err(e) => panic!("Failed to list channels: {}", e),
+    };
+
+    for channel_key in channels.into_iter().map(channel_storage_key) {
+        match index_channel_messages(sled_db, &channel_key) {
+            Ok(count) => println!("Indexed {} messages in channel {}", count, channel_key),
+            Err(e) => panic!("Failed to index channel {}: {}", channel_key, e),
+        }
+    }
+
+    meta.insert("message_index_backfilled", "1").expect("Failed to update meta tree");
+}
+
+fn index_channel_messages(sled_db: &Db, channel_key: &str) -> Result<usize, sled::Error> {
+    let tree = open_channel_tree(sled_db, channel_key, "")?;
+    let index = open_channel_tree(sled_db, channel_key, "_index")?;
+
+    let mut count = 0;
+    for item in tree.iter() {
+        let (key, _) = item?;
+        if let Some(pos) = key.iter().rposition(|b| *b == b':') {
+            index.insert(&key[pos + 1..], key.clone())?;
+            count += 1;
+        }
+    }
+    index.flush()?;
+    Ok(count)
+}
+
+pub fn get_user_status_sled(sled_db: &Db, channel_key: &str) -> Result<Vec<UserStatus>, sled::Error> {
+    let tree = open_channel_tree(sled_db, channel_key, "_user_status")?;
 
     let mut statuses = Vec::new();
 
@@ -122,16 +1030,15 @@ pub async fn init_sqlite_db() -> Pool<Sqlite> {
 
     let init: Result<Pool<Sqlite>, sqlx::Error> = SqlitePoolOptions::new().connect("sqlite:chat_sqlite.db").await;
 
-    let db: Pool<Sqlite>;
-    match init {
+    let db: Pool<Sqlite> = match init {
         Ok(sqlite_db) => {
             println!("Sqlite database initialized successfully.");
-            db = sqlite_db;
+            sqlite_db
         },
         Err(e) => {
             panic!("Failed to initialize Sqlite database: {}", e);
         }
-    }
+    };
 
     let query: Result<sqlite::SqliteQueryResult, sqlx::Error> = sqlx::query("
         CREATE TABLE IF NOT EXISTS Users (
@@ -147,8 +1054,90 @@ pub async fn init_sqlite_db() -> Pool<Sqlite> {
             FOREIGN KEY (Owner) REFERENCES Users(Username) ON DELETE SET NULL
         );
 
+        CREATE TABLE IF NOT EXISTS ChannelMembers (
+            ChannelId INTEGER NOT NULL,
+            Username TEXT NOT NULL,
+            Status TEXT NOT NULL,
+            PRIMARY KEY (ChannelId, Username),
+            FOREIGN KEY (ChannelId) REFERENCES Channel(id) ON DELETE CASCADE,
+            FOREIGN KEY (Username) REFERENCES Users(Username) ON DELETE CASCADE
+        );
+
+        CREATE TABLE IF NOT EXISTS ChannelRoles (
+            ChannelId INTEGER NOT NULL,
+            Username TEXT NOT NULL,
+            Role TEXT NOT NULL,
+            PRIMARY KEY (ChannelId, Username),
+            FOREIGN KEY (ChannelId) REFERENCES Channel(id) ON DELETE CASCADE,
+            FOREIGN KEY (Username) REFERENCES Users(Username) ON DELETE CASCADE
+        );
+
+        CREATE TABLE IF NOT EXISTS ChannelMutes (
+            ChannelId INTEGER NOT NULL,
+            Username TEXT NOT NULL,
+            PRIMARY KEY (ChannelId, Username),
+            FOREIGN KEY (ChannelId) REFERENCES Channel(id) ON DELETE CASCADE,
+            FOREIGN KEY (Username) REFERENCES Users(Username) ON DELETE CASCADE
+        );
+
+        CREATE TABLE IF NOT EXISTS DirectMessage (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            UserA TEXT NOT NULL,
+            UserB TEXT NOT NULL,
+            UNIQUE (UserA, UserB),
+            FOREIGN KEY (UserA) REFERENCES Users(Username) ON DELETE CASCADE,
+            FOREIGN KEY (UserB) REFERENCES Users(Username) ON DELETE CASCADE
+        );
+
+        CREATE TABLE IF NOT EXISTS Sessions (
+            Key TEXT PRIMARY KEY,
+            SessionId TEXT UNIQUE,
+            UserId INTEGER,
+            UserAgent TEXT,
+            State TEXT NOT NULL,
+            CreatedAt TEXT NOT NULL,
+            LastSeenAt TEXT NOT NULL,
+            ExpiresAt TEXT NOT NULL,
+            FOREIGN KEY (UserId) REFERENCES Users(id) ON DELETE CASCADE
+        );
+
+        CREATE TABLE IF NOT EXISTS ApiTokens (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            UserId INTEGER NOT NULL,
+            Name TEXT NOT NULL,
+            TokenHash TEXT NOT NULL UNIQUE,
+            CreatedAt TEXT NOT NULL,
+            LastUsedAt TEXT,
+            FOREIGN KEY (UserId) REFERENCES Users(id) ON DELETE CASCADE
+        );
+
+        CREATE TABLE IF NOT EXISTS RefreshTokens (
+            TokenHash TEXT PRIMARY KEY,
+            Family TEXT NOT NULL,
+            UserId INTEGER NOT NULL,
+            CreatedAt TEXT NOT NULL,
+            ExpiresAt TEXT NOT NULL,
+            UsedAt TEXT,
+            FOREIGN KEY (UserId) REFERENCES Users(id) ON DELETE CASCADE
+        );
+
+        CREATE TABLE IF NOT EXISTS RevokedTokens (
+            Jti TEXT PRIMARY KEY,
+            ExpiresAt TEXT NOT NULL
+        );
+
+        CREATE VIRTUAL TABLE IF NOT EXISTS MessageSearch USING fts5(
+            Body,
+            MessageId UNINDEXED,
+            ChannelKey UNINDEXED,
+            Author UNINDEXED,
+            Timestamp UNINDEXED
+        );
+
         CREATE INDEX IF NOT EXISTS idx_users_username ON Users(Username);
-        CREATE INDEX IF NOT EXISTS idx_channel_name ON Channel(Name);").execute(&db).await;
+        CREATE INDEX IF NOT EXISTS idx_channel_name ON Channel(Name);
+        CREATE INDEX IF NOT EXISTS idx_sessions_user ON Sessions(UserId);
+        CREATE INDEX IF NOT EXISTS idx_refresh_tokens_family ON RefreshTokens(Family);").execute(&db).await;
 
     match query {
         Ok(_) => {
@@ -158,7 +1147,39 @@ pub async fn init_sqlite_db() -> Pool<Sqlite> {
             panic!("Failed to initialize the SQLite database: {}", e);
         }
     }
-    return db;
+
+    // Columns added after the first release
+    let columns = [
+        ("Channel", "Private", "INTEGER NOT NULL DEFAULT 0"),
+        ("Channel", "Topic", "TEXT NOT NULL DEFAULT ''"),
+        ("Channel", "Description", "TEXT NOT NULL DEFAULT ''"),
+        ("Channel", "Archived", "INTEGER NOT NULL DEFAULT 0"),
+        // Retention limits, NULL keeps messages forever
+        ("Channel", "RetentionDays", "INTEGER"),
+        ("Channel", "RetentionCount", "INTEGER"),
+        // Unix time before which the user's JWT access tokens are revoked, NULL accepts them all
+        ("Users", "TokensValidAfter", "INTEGER"),
+    ];
+    for (table, column, definition) in columns {
+        if let Err(e) = ensure_column(&db, table, column, definition).await {
+            panic!("Failed to migrate the SQLite database: {}", e);
+        }
+    }
+    db
+}
+
+/// Add a column to a table created by an older version, if it is missing
+async fn ensure_column(db: &Pool<Sqlite>, table: &str, column: &str, definition: &str) -> Result<(), sqlx::Error> {
+    let columns: Vec<String> = sqlx::query_scalar(&format!("SELECT name FROM pragma_table_info('{}');", table))
+        .fetch_all(db)
+        .await?;
+    if !columns.iter().any(|name| name == column) {
+        sqlx::query(&format!("ALTER TABLE {} ADD COLUMN {} {};", table, column, definition))
+            .execute(db)
+            .await?;
+        println!("Added column {}.{}", table, column);
+    }
+    Ok(())
 }
 
 pub async fn init_sled_db() -> sled::Db {
@@ -169,7 +1190,7 @@ pub async fn init_sled_db() -> sled::Db {
             // Ensure the chat history tree exists
             sled_db.open_tree("chat_history").expect("Failed to open chat_history tree");
             println!("Sled database initialized successfully.");
-            return sled_db;
+            sled_db
         }
         Err(e) => {
             panic!("Failed to initialize Sled database: {}", e);
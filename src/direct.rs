@@ -0,0 +1,128 @@
+use actix_web::{web, Responder, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite};
+use crate::user::check_auth;
+
+/// Direct conversations are chatted in like channels named "dm:{id}".
+/// Channel names with this prefix are reserved.
+pub const DIRECT_PREFIX: &str = "dm:";
+
+#[derive(Deserialize)]
+pub struct OpenRequest {
+    username: String,
+}
+
+/// A direct conversation as seen by one of its participants
+#[derive(Serialize, Debug)]
+pub struct Conversation {
+    id: i64,
+    /// Name to use in place of a channel name, e.g. for `/channel/ws/{name}`
+    channel: String,
+    /// The other participant
+    with: String,
+}
+
+impl Conversation {
+    fn new(id: i64, with: String) -> Self {
+        Self { id, channel: format!("{}{}", DIRECT_PREFIX, id), with }
+    }
+}
+
+pub fn is_direct(channel_name: &str) -> bool {
+    channel_name.starts_with(DIRECT_PREFIX)
+}
+
+/// Get the other participant of a direct conversation, or `None` if
+/// `username` is not one of its two participants
+pub async fn get_other_participant(db: &Pool<Sqlite>, channel_name: &str, username: &str) -> Result<Option<String>, sqlx::Error> {
+    let id: i64 = match channel_name.strip_prefix(DIRECT_PREFIX).and_then(|id| id.parse().ok()) {
+        Some(id) => id,
+        None => return Ok(None),
+    };
+
+    let participants: Option<(String, String)> = sqlx::query_as("SELECT UserA, UserB FROM DirectMessage WHERE id = ?;")
+        .bind(id)
+        .fetch_optional(db)
+        .await?;
+
+    Ok(match participants {
+        Some((user_a, user_b)) if user_a == username => Some(user_b),
+        Some((user_a, user_b)) if user_b == username => Some(user_a),
+        _ => None,
+    })
+}
+
+/// Get the direct conversation between the current user and another registered
+/// user, creating it on first use
+pub async fn direct_open(db: web::Data<Pool<Sqlite>>, req: HttpRequest, form: web::Json<OpenRequest>) -> impl Responder {
+    let (_user_id, username) = match check_auth(&req) {
+        Ok(user) => user,
+        Err(_) => return HttpResponse::Unauthorized().json("User not logged in."),
+    };
+
+    let other = form.username.trim();
+    if other == username {
+        return HttpResponse::BadRequest().json("Cannot open a conversation with yourself.");
+    }
+
+    match sqlx::query_scalar::<_, i64>("SELECT id FROM Users WHERE Username = ?;")
+        .bind(other)
+        .fetch_optional(db.get_ref())
+        .await
+    {
+        Ok(Some(_)) => {}
+        Ok(None) => return HttpResponse::NotFound().json("User not found."),
+        Err(e) => {
+            println!("Error querying database: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    // Participants are stored in order so each pair has a single conversation
+    let (user_a, user_b) = if username.as_str() < other { (username.as_str(), other) } else { (other, username.as_str()) };
+    let insert = sqlx::query("INSERT OR IGNORE INTO DirectMessage (UserA, UserB) VALUES (?, ?);")
+        .bind(user_a)
+        .bind(user_b)
+        .execute(db.get_ref())
+        .await;
+    if let Err(e) = insert {
+        println!("Error creating conversation: {}", e);
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    match sqlx::query_scalar::<_, i64>("SELECT id FROM DirectMessage WHERE UserA = ? AND UserB = ?;")
+        .bind(user_a)
+        .bind(user_b)
+        .fetch_one(db.get_ref())
+        .await
+    {
+        Ok(id) => HttpResponse::Ok().json(Conversation::new(id, other.to_string())),
+        Err(e) => {
+            println!("Error querying database: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub async fn direct_list(db: web::Data<Pool<Sqlite>>, req: HttpRequest) -> impl Responder {
+    let (_user_id, username) = match check_auth(&req) {
+        Ok(user) => user,
+        Err(_) => return HttpResponse::Unauthorized().json("User not logged in."),
+    };
+
+    let query = "SELECT id, CASE WHEN UserA = ? THEN UserB ELSE UserA END FROM DirectMessage WHERE UserA = ? OR UserB = ? ORDER BY id;";
+    let result: Result<Vec<(i64, String)>, sqlx::Error> = sqlx::query_as(query)
+        .bind(&username)
+        .bind(&username)
+        .bind(&username)
+        .fetch_all(db.get_ref())
+        .await;
+
+    match result {
+        Ok(rows) => {
+            let conversations: Vec<Conversation> = rows.into_iter().map(|(id, with)| Conversation::new(id, with)).collect();
+            HttpResponse::Ok().json(conversations)
+        }
+        Err(e) => HttpResponse::InternalServerError().json(e.to_string()),
+    }
+}
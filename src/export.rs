@@ -0,0 +1,264 @@
+use actix_web::error::ErrorInternalServerError;
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::web::Bytes;
+use actix_web::{web, Error, Responder, HttpRequest, HttpResponse};
+use futures_util::stream;
+use serde::Deserialize;
+use sqlx::{Pool, Sqlite};
+use crate::channel::{resolve_channel, ChannelRef, ChatMessage};
+use crate::database::{find_message_key_sled, get_chat_history_sled, get_replies_to_older_sled, get_thread_between_sled, time_key};
+use crate::member::check_channel_access;
+use crate::search::parse_time_filter;
+
+/// Messages read from sled per chunk of the response
+const EXPORT_BATCH_SIZE: usize = 200;
+
+#[derive(Deserialize)]
+pub struct ChannelPath {
+    name: String,
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// One JSON message per line
+    #[default]
+    Jsonl,
+    Csv,
+    Markdown,
+}
+
+impl ExportFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Jsonl => "application/jsonl; charset=utf-8",
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Markdown => "text/markdown; charset=utf-8",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Jsonl => "jsonl",
+            ExportFormat::Csv => "csv",
+            ExportFormat::Markdown => "md",
+        }
+    }
+}
+
+/// Parameters of `/channel/export`. `from` and `to` work like in search.
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    format: ExportFormat,
+    from: Option<String>,
+    to: Option<String>,
+}
+
+/// Where an export is in the channel's history
+struct ExportCursor {
+    sled_db: web::Data<sled::Db>,
+    channel: ChannelRef,
+    format: ExportFormat,
+    /// Start of the time range
+    from: Option<sled::IVec>,
+    /// Key of the last exported message, or the start of the time range
+    after: Option<sled::IVec>,
+    /// End of the time range
+    before: Option<sled::IVec>,
+    /// Threads tree key of the last exported reply to a message older than the range
+    older_thread_after: Option<sled::IVec>,
+    /// Whether the replies to messages older than the range are all exported
+    older_threads_done: bool,
+    started: bool,
+    done: bool,
+}
+
+impl ExportCursor {
+    /// Render the next batch of messages, with their thread replies after them
+    fn next_chunk(&mut self) -> Result<Option<Bytes>, sled::Error> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let mut chunk = String::new();
+        if !self.started {
+            self.started = true;
+            chunk.push_str(&export_header(self.format, &self.channel.name));
+        }
+
+        // Replies in the range whose parent is older come first, since their parent isn't exported
+        if let (Some(from), false) = (&self.from, self.older_threads_done) {
+            let replies = get_replies_to_older_sled(
+                &self.sled_db,
+                &self.channel.key,
+                from,
+                self.before.as_deref(),
+                self.older_thread_after.clone(),
+                EXPORT_BATCH_SIZE,
+            )?;
+            if replies.len() < EXPORT_BATCH_SIZE {
+                self.older_threads_done = true;
+            }
+            for (thread_key, reply) in replies {
+                chunk.push_str(&export_message(self.format, &reply));
+                self.older_thread_after = Some(thread_key);
+            }
+            if !self.older_threads_done {
+                return Ok(Some(Bytes::from(chunk)));
+            }
+        }
+
+        // History pages forward from `after`, which starts at the beginning of the channel
+        let after = Some(self.after.clone().unwrap_or_else(|| sled::IVec::from(&[][..])));
+        let messages = get_chat_history_sled(&self.sled_db, &self.channel.key, self.before.clone(), after, EXPORT_BATCH_SIZE)?;
+        if messages.len() < EXPORT_BATCH_SIZE {
+            self.done = true;
+        }
+        if let Some(last) = messages.last() {
+            match find_message_key_sled(&self.sled_db, &self.channel.key, &last.id)? {
+                Some(key) => self.after = Some(key),
+                None => self.done = true,
+            }
+        }
+
+        for message in &messages {
+            chunk.push_str(&export_message(self.format, message));
+            if message.reply_count > 0 {
+                let replies = get_thread_between_sled(
+                    &self.sled_db,
+                    &self.channel.key,
+                    &message.id,
+                    self.from.as_deref(),
+                    self.before.as_deref(),
+                )?;
+                for reply in replies {
+                    chunk.push_str(&export_message(self.format, &reply));
+                }
+            }
+        }
+
+        if chunk.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(Bytes::from(chunk)))
+        }
+    }
+}
+
+fn export_header(format: ExportFormat, channel_name: &str) -> String {
+    match format {
+        ExportFormat::Jsonl => String::new(),
+        ExportFormat::Csv => "id,timestamp,username,message,edited_at,reply_to\r\n".to_string(),
+        ExportFormat::Markdown => format!("# {}\n\n", channel_name),
+    }
+}
+
+fn export_message(format: ExportFormat, message: &ChatMessage) -> String {
+    match format {
+        ExportFormat::Jsonl => {
+            let mut line = serde_json::to_string(message).expect("Failed to serialize message");
+            line.push('\n');
+            line
+        }
+        ExportFormat::Csv => {
+            let fields = [
+                message.id.as_str(),
+                message.timestamp.as_str(),
+                message.username.as_str(),
+                message.message.as_str(),
+                message.edited_at.as_deref().unwrap_or(""),
+                message.reply_to.as_deref().unwrap_or(""),
+            ];
+            let mut row = fields.iter().map(|field| csv_field(field)).collect::<Vec<_>>().join(",");
+            row.push_str("\r\n");
+            row
+        }
+        ExportFormat::Markdown => {
+            // Replies are nested under their parent, and every line of a body stays in its list item
+            let indent = if message.reply_to.is_some() { "  " } else { "" };
+            let edited = if message.edited_at.is_some() { " (edited)" } else { "" };
+            let body = message.message.replace('\n', &format!("\n{}  ", indent));
+            format!("{}- **{}** {}{}: {}\n", indent, message.username, message.timestamp, edited, body)
+        }
+    }
+}
+
+/// Quote a CSV field if it contains a separator, quote or line break
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Download a channel's history as JSON Lines, CSV or Markdown. The transcript is
+/// streamed in batches, so large channels are never held in memory at once.
+pub async fn channel_export(
+    db: web::Data<Pool<Sqlite>>,
+    sled_db: web::Data<sled::Db>,
+    req: HttpRequest,
+    info: web::Path<ChannelPath>,
+    query: web::Query<ExportQuery>,
+) -> impl Responder {
+    if let Err(err) = check_channel_access(&db, &req, &info.name).await {
+        return err.error_response();
+    }
+    let channel = match resolve_channel(&db, &info.name).await {
+        Ok(channel) => channel,
+        Err(err) => return err.error_response(),
+    };
+
+    let after = match query.from.as_deref().map(|from| parse_time_filter(from, false)) {
+        Some(None) => return HttpResponse::BadRequest().json("Invalid `from` time."),
+        Some(Some(from)) => Some(time_key(from)),
+        None => None,
+    };
+    let before = match query.to.as_deref().map(|to| parse_time_filter(to, true)) {
+        Some(None) => return HttpResponse::BadRequest().json("Invalid `to` time."),
+        Some(Some(to)) => Some(time_key(to)),
+        None => None,
+    };
+
+    if let (Some(after), Some(before)) = (&after, &before) {
+        if after >= before {
+            return HttpResponse::BadRequest().json("`from` must be before `to`.");
+        }
+    }
+
+    let format = query.format;
+    let disposition = ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters: vec![DispositionParam::Filename(format!("{}.{}", channel.name, format.extension()))],
+    };
+    let cursor = ExportCursor {
+        sled_db,
+        channel,
+        format,
+        from: after.clone(),
+        after,
+        before,
+        older_thread_after: None,
+        older_threads_done: false,
+        started: false,
+        done: false,
+    };
+
+    let body = stream::unfold(cursor, |mut cursor| async move {
+        match cursor.next_chunk() {
+            Ok(Some(chunk)) => Some((Ok::<_, Error>(chunk), cursor)),
+            Ok(None) => None,
+            Err(err) => {
+                println!("Error exporting channel history: {:?}", err);
+                cursor.done = true;
+                Some((Err(ErrorInternalServerError("Failed to export chat history")), cursor))
+            }
+        }
+    });
+
+    HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header(disposition)
+        .streaming(body)
+}
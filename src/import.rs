@@ -0,0 +1,593 @@
+use chrono::{DateTime, TimeZone, Utc};
+use serde::Deserialize;
+use sqlx::{Pool, Sqlite};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+use crate::database::{channel_storage_key, find_storage_key, format_timestamp, import_chat_message_sled};
+use crate::direct::is_direct;
+use crate::member::MEMBER;
+use crate::record::{Attachment, MessageKind, MessageRecord};
+
+/// Password of users created by an import. It is no valid hash, so nobody can log
+/// in as them until the password is reset.
+const PLACEHOLDER_PASSWORD: &str = "!";
+
+/// Slack message subtypes that are chat messages rather than notices like channel joins
+const SLACK_MESSAGE_SUBTYPES: [&str; 4] = ["thread_broadcast", "file_share", "me_message", "bot_message"];
+
+/// Discord message types that are chat messages
+const DISCORD_MESSAGE_TYPES: [&str; 2] = ["Default", "Reply"];
+
+/// Where an export comes from
+#[derive(Clone, Copy, Debug)]
+pub enum ImportSource {
+    /// A Slack workspace export directory
+    Slack,
+    /// A JSON file, or a directory of them, written by DiscordChatExporter
+    Discord,
+}
+
+impl ImportSource {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "slack" => Some(ImportSource::Slack),
+            "discord" => Some(ImportSource::Discord),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ImportSource::Slack => "slack",
+            ImportSource::Discord => "discord",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ImportError {
+    Io(PathBuf, std::io::Error),
+    Json(PathBuf, serde_json::Error),
+    Database(sqlx::Error),
+    Storage(sled::Error),
+    /// The export can't be imported as it is
+    Invalid(String),
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportError::Io(path, err) => write!(f, "{}: {}", path.display(), err),
+            ImportError::Json(path, err) => write!(f, "{}: {}", path.display(), err),
+            ImportError::Database(err) => write!(f, "database error: {}", err),
+            ImportError::Storage(err) => write!(f, "storage error: {}", err),
+            ImportError::Invalid(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl From<sqlx::Error> for ImportError {
+    fn from(err: sqlx::Error) -> Self {
+        ImportError::Database(err)
+    }
+}
+
+impl From<sled::Error> for ImportError {
+    fn from(err: sled::Error) -> Self {
+        ImportError::Storage(err)
+    }
+}
+
+/// What an import changed
+#[derive(Debug, Default)]
+pub struct ImportSummary {
+    pub channels: usize,
+    pub users: usize,
+    pub messages: usize,
+    /// Notices, messages without an author and messages imported before
+    pub skipped: usize,
+}
+
+/// A channel read from an export, before it is written
+struct ImportedChannel {
+    /// ID of the channel in the export
+    source_id: String,
+    name: String,
+    creator: Option<String>,
+    topic: String,
+    description: String,
+    private: bool,
+    /// Usernames of the members of a private channel
+    members: Vec<String>,
+    messages: Vec<ImportedMessage>,
+}
+
+struct ImportedMessage {
+    /// ID of the message in the export
+    source_id: String,
+    /// ID in the export of the message this one replies to
+    reply_to: Option<String>,
+    author: String,
+    time: DateTime<Utc>,
+    body: String,
+    edited_at: Option<DateTime<Utc>>,
+    attachments: Vec<Attachment>,
+}
+
+/// Import the channels and messages of an export. Missing channels are created with
+/// `owner`, or else the export's channel creator or first author, as their owner.
+/// Authors get a placeholder user named after the source. Importing the same export
+/// again skips the messages already there.
+pub async fn import_history(
+    sqlite_db: &Pool<Sqlite>,
+    sled_db: &sled::Db,
+    source: ImportSource,
+    path: &Path,
+    owner: Option<&str>,
+) -> Result<ImportSummary, ImportError> {
+    let (channels, skipped) = match source {
+        ImportSource::Slack => read_slack_export(path)?,
+        ImportSource::Discord => read_discord_export(path)?,
+    };
+
+    if let Some(owner) = owner {
+        if find_user(sqlite_db, owner).await?.is_none() {
+            return Err(ImportError::Invalid(format!("Owner {} has no account.", owner)));
+        }
+    }
+
+    let mut summary = ImportSummary { skipped, ..ImportSummary::default() };
+    let mut user_ids = HashMap::new();
+    for channel in channels {
+        if is_direct(&channel.name) {
+            println!("Skipping channel {}: the name is reserved for direct messages", channel.name);
+            summary.skipped += channel.messages.len();
+            continue;
+        }
+
+        let channel_key = match find_storage_key(sqlite_db, &channel.name).await? {
+            Some(channel_key) => channel_key,
+            None => {
+                let channel_owner = match owner {
+                    Some(owner) => owner.to_string(),
+                    None => {
+                        let creator = match channel.creator.as_ref().or_else(|| channel.messages.first().map(|message| &message.author)) {
+                            Some(creator) => creator,
+                            None => continue,
+                        };
+                        let creator = imported_username(source, creator);
+                        ensure_user(sqlite_db, &creator, &mut user_ids, &mut summary).await?;
+                        creator
+                    }
+                };
+                summary.channels += 1;
+                create_channel(sqlite_db, &channel, &channel_owner).await?
+            }
+        };
+
+        if channel.private {
+            for member in &channel.members {
+                let member = imported_username(source, member);
+                ensure_user(sqlite_db, &member, &mut user_ids, &mut summary).await?;
+                add_member(sqlite_db, &channel.name, &member).await?;
+            }
+        }
+
+        // Messages dated in the future would hold back the keys of every later message
+        let now = Utc::now();
+        let thread_roots = thread_roots(&channel.messages);
+        for message in channel.messages {
+            let author = imported_username(source, &message.author);
+            let author_id = ensure_user(sqlite_db, &author, &mut user_ids, &mut summary).await?;
+            let time = message.time.min(now);
+            let id = imported_message_id(source, &channel.source_id, &message.source_id);
+            let reply_to = thread_roots
+                .get(&message.source_id)
+                .map(|root| imported_message_id(source, &channel.source_id, root));
+
+            let record = MessageRecord {
+                id,
+                timestamp: format_timestamp(time),
+                author_id: Some(author_id),
+                author,
+                body: message.body,
+                kind: MessageKind::Text,
+                edited_at: message.edited_at.map(format_timestamp),
+                deleted_by: None,
+                attachments: message.attachments,
+                reply_to,
+            };
+            let millis = time.timestamp_millis().max(0) as u64;
+            if import_chat_message_sled(sled_db, &channel_key, &record, millis)? {
+                summary.messages += 1;
+            } else {
+                summary.skipped += 1;
+            }
+        }
+    }
+
+    sled_db.flush()?;
+    Ok(summary)
+}
+
+/// Map replies to the message starting their thread. Threads are one level deep here,
+/// so replies to replies join the parent's thread. Replies to messages missing from the
+/// export become regular messages.
+fn thread_roots(messages: &[ImportedMessage]) -> HashMap<String, String> {
+    let parents: HashMap<&str, &str> = messages
+        .iter()
+        .filter_map(|message| Some((message.source_id.as_str(), message.reply_to.as_deref()?)))
+        .collect();
+    let exported: HashSet<&str> = messages.iter().map(|message| message.source_id.as_str()).collect();
+
+    let mut roots = HashMap::new();
+    for &reply in parents.keys() {
+        let mut root = reply;
+        // Bounded, in case an export has a reply loop
+        for _ in 0..messages.len() {
+            match parents.get(root) {
+                Some(&parent) if exported.contains(parent) => root = parent,
+                _ => break,
+            }
+        }
+        if root != reply {
+            roots.insert(reply.to_string(), root.to_string());
+        }
+    }
+    roots
+}
+
+/// IDs of imported messages are derived from their place in the export, so they are
+/// the same every time it is imported
+fn imported_message_id(source: ImportSource, channel_id: &str, message_id: &str) -> String {
+    let name = format!("{}:{}:{}", source.name(), channel_id, message_id);
+    Uuid::new_v5(&Uuid::NAMESPACE_OID, name.as_bytes()).to_string()
+}
+
+/// Imported users are named after their source, like "slack:alice", so they are
+/// never mixed up with local accounts
+fn imported_username(source: ImportSource, name: &str) -> String {
+    format!("{}:{}", source.name(), name)
+}
+
+async fn find_user(sqlite_db: &Pool<Sqlite>, username: &str) -> Result<Option<u32>, sqlx::Error> {
+    sqlx::query_scalar("SELECT id FROM Users WHERE Username = ?;")
+        .bind(username)
+        .fetch_optional(sqlite_db)
+        .await
+}
+
+/// Get the ID of an imported user, creating a placeholder user if there is none. Fails if
+/// someone registered the name, since their account isn't the imported author.
+async fn ensure_user(
+    sqlite_db: &Pool<Sqlite>,
+    username: &str,
+    user_ids: &mut HashMap<String, u32>,
+    summary: &mut ImportSummary,
+) -> Result<u32, ImportError> {
+    if let Some(&id) = user_ids.get(username) {
+        return Ok(id);
+    }
+
+    let user: Option<(u32, String)> = sqlx::query_as("SELECT id, Password FROM Users WHERE Username = ?;")
+        .bind(username)
+        .fetch_optional(sqlite_db)
+        .await?;
+    let id = match user {
+        Some((id, password)) if password == PLACEHOLDER_PASSWORD => id,
+        Some(_) => return Err(ImportError::Invalid(format!("{} is the name of a local account.", username))),
+        None => {
+            summary.users += 1;
+            sqlx::query("INSERT INTO Users (Username, Password) VALUES (?, ?);")
+                .bind(username)
+                .bind(PLACEHOLDER_PASSWORD)
+                .execute(sqlite_db)
+                .await?
+                .last_insert_rowid() as u32
+        }
+    };
+    user_ids.insert(username.to_string(), id);
+    Ok(id)
+}
+
+async fn create_channel(sqlite_db: &Pool<Sqlite>, channel: &ImportedChannel, owner: &str) -> Result<String, sqlx::Error> {
+    let id = sqlx::query("INSERT INTO Channel (Name, Owner, Private, Topic, Description) VALUES (?, ?, ?, ?, ?);")
+        .bind(&channel.name)
+        .bind(owner)
+        .bind(channel.private)
+        .bind(&channel.topic)
+        .bind(&channel.description)
+        .execute(sqlite_db)
+        .await?
+        .last_insert_rowid();
+    Ok(channel_storage_key(id))
+}
+
+/// Let a user into a private channel, unless they already are a member or invited
+async fn add_member(sqlite_db: &Pool<Sqlite>, channel_name: &str, username: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT OR IGNORE INTO ChannelMembers (ChannelId, Username, Status)
+        SELECT id, ?, ? FROM Channel WHERE Name = ?;")
+        .bind(username)
+        .bind(MEMBER)
+        .bind(channel_name)
+        .execute(sqlite_db)
+        .await?;
+    Ok(())
+}
+
+fn read_json<T: for<'de> Deserialize<'de>>(path: &Path) -> Result<T, ImportError> {
+    let contents = std::fs::read(path).map_err(|err| ImportError::Io(path.to_path_buf(), err))?;
+    serde_json::from_slice(&contents).map_err(|err| ImportError::Json(path.to_path_buf(), err))
+}
+
+/// The JSON files of a directory in name order, which is date order in Slack exports
+fn json_files(dir: &Path) -> Result<Vec<PathBuf>, ImportError> {
+    let entries = std::fs::read_dir(dir).map_err(|err| ImportError::Io(dir.to_path_buf(), err))?;
+    let mut files = Vec::new();
+    for entry in entries {
+        let path = entry.map_err(|err| ImportError::Io(dir.to_path_buf(), err))?.path();
+        if path.extension().is_some_and(|extension| extension == "json") {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[derive(Deserialize)]
+struct SlackChannel {
+    id: String,
+    name: String,
+    creator: Option<String>,
+    topic: Option<SlackText>,
+    purpose: Option<SlackText>,
+    /// User IDs, listed for private channels
+    #[serde(default)]
+    members: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct SlackText {
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct SlackUser {
+    id: String,
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct SlackMessage {
+    ts: String,
+    user: Option<String>,
+    /// Set instead of `user` on messages posted by bots and integrations
+    bot_id: Option<String>,
+    /// Display name of a bot message
+    username: Option<String>,
+    #[serde(default)]
+    text: String,
+    subtype: Option<String>,
+    thread_ts: Option<String>,
+    edited: Option<SlackEdited>,
+    #[serde(default)]
+    files: Vec<SlackFile>,
+}
+
+#[derive(Deserialize)]
+struct SlackEdited {
+    ts: String,
+}
+
+#[derive(Deserialize)]
+struct SlackFile {
+    name: Option<String>,
+    url_private: Option<String>,
+}
+
+/// Read the public (`channels.json`) and private (`groups.json`) channels of a Slack export
+fn read_slack_export(dir: &Path) -> Result<(Vec<ImportedChannel>, usize), ImportError> {
+    let mut listings = Vec::new();
+    for (file, private) in [("channels.json", false), ("groups.json", true)] {
+        let path = dir.join(file);
+        if path.exists() {
+            let channels: Vec<SlackChannel> = read_json(&path)?;
+            listings.extend(channels.into_iter().map(|channel| (channel, private)));
+        }
+    }
+    if listings.is_empty() {
+        return Err(ImportError::Invalid(format!("{} has no channels.json or groups.json.", dir.display())));
+    }
+
+    let users_path = dir.join("users.json");
+    let users: HashMap<String, String> = if users_path.exists() {
+        read_json::<Vec<SlackUser>>(&users_path)?.into_iter().map(|user| (user.id, user.name)).collect()
+    } else {
+        HashMap::new()
+    };
+    let username = |id: &str| users.get(id).cloned().unwrap_or_else(|| id.to_string());
+
+    let mut channels = Vec::new();
+    let mut skipped = 0;
+    for (channel, private) in listings {
+        let channel_dir = dir.join(&channel.name);
+        let mut messages = Vec::new();
+        if channel_dir.is_dir() {
+            for path in json_files(&channel_dir)? {
+                for message in read_json::<Vec<SlackMessage>>(&path)? {
+                    let is_message = message.subtype.as_deref().is_none_or(|subtype| SLACK_MESSAGE_SUBTYPES.contains(&subtype));
+                    // Bots have no user, so they go by their name, or their ID if they have none
+                    let author = match &message.user {
+                        Some(user) => Some(username(user)),
+                        None => message.username.clone().or_else(|| message.bot_id.clone()),
+                    };
+                    let (author, time) = match (author, parse_slack_ts(&message.ts)) {
+                        (Some(author), Some(time)) if is_message => (author, time),
+                        _ => {
+                            skipped += 1;
+                            continue;
+                        }
+                    };
+
+                    messages.push(ImportedMessage {
+                        reply_to: message.thread_ts.filter(|thread_ts| *thread_ts != message.ts),
+                        source_id: message.ts,
+                        author,
+                        time,
+                        body: slack_text(&message.text, &username),
+                        edited_at: message.edited.and_then(|edited| parse_slack_ts(&edited.ts)),
+                        attachments: message.files
+                            .into_iter()
+                            .filter_map(|file| Some(Attachment { name: file.name?, url: file.url_private? }))
+                            .collect(),
+                    });
+                }
+            }
+        }
+        messages.sort_by_key(|message| message.time);
+
+        channels.push(ImportedChannel {
+            source_id: channel.id,
+            name: channel.name,
+            creator: channel.creator.as_deref().map(username),
+            topic: channel.topic.map(|topic| topic.value).unwrap_or_default(),
+            description: channel.purpose.map(|purpose| purpose.value).unwrap_or_default(),
+            private,
+            members: channel.members.iter().map(|id| username(id)).collect(),
+            messages,
+        });
+    }
+    Ok((channels, skipped))
+}
+
+/// Slack timestamps are seconds since the epoch with microseconds, e.g. "1585000000.000200"
+fn parse_slack_ts(ts: &str) -> Option<DateTime<Utc>> {
+    let (seconds, micros) = ts.split_once('.').unwrap_or((ts, "0"));
+    let seconds: i64 = seconds.parse().ok()?;
+    let micros: u32 = format!("{:0<6}", micros).get(..6)?.parse().ok()?;
+    Utc.timestamp_opt(seconds, micros * 1000).single()
+}
+
+/// Turn Slack markup into plain text: mentions like `<@U123>` become `@name`,
+/// links keep their label and HTML entities are unescaped
+fn slack_text(text: &str, username: &impl Fn(&str) -> String) -> String {
+    let mut result = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        result.push_str(&rest[..start]);
+        let end = match rest[start..].find('>') {
+            Some(end) => start + end,
+            None => break,
+        };
+        let (target, label) = match rest[start + 1..end].split_once('|') {
+            Some((target, label)) => (target, Some(label)),
+            None => (&rest[start + 1..end], None),
+        };
+        match (target.strip_prefix('@'), target.strip_prefix('#')) {
+            (Some(user), _) => result.push_str(&format!("@{}", label.map_or_else(|| username(user), str::to_string))),
+            (_, Some(channel)) => result.push_str(&format!("#{}", label.unwrap_or(channel))),
+            _ => result.push_str(label.unwrap_or(target)),
+        }
+        rest = &rest[end + 1..];
+    }
+    result.push_str(rest);
+    result.replace("&lt;", "<").replace("&gt;", ">").replace("&amp;", "&")
+}
+
+#[derive(Deserialize)]
+struct DiscordExport {
+    channel: DiscordChannel,
+    messages: Vec<DiscordMessage>,
+}
+
+#[derive(Deserialize)]
+struct DiscordChannel {
+    id: String,
+    name: String,
+    topic: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DiscordMessage {
+    id: String,
+    #[serde(rename = "type")]
+    kind: String,
+    timestamp: String,
+    timestamp_edited: Option<String>,
+    #[serde(default)]
+    content: String,
+    author: DiscordAuthor,
+    #[serde(default)]
+    attachments: Vec<DiscordAttachment>,
+    reference: Option<DiscordReference>,
+}
+
+#[derive(Deserialize)]
+struct DiscordAuthor {
+    name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DiscordAttachment {
+    file_name: String,
+    url: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DiscordReference {
+    message_id: Option<String>,
+}
+
+/// Read a Discord channel dump, or every dump in a directory
+fn read_discord_export(path: &Path) -> Result<(Vec<ImportedChannel>, usize), ImportError> {
+    let files = if path.is_dir() { json_files(path)? } else { vec![path.to_path_buf()] };
+
+    let mut channels = Vec::new();
+    let mut skipped = 0;
+    for file in files {
+        let export: DiscordExport = read_json(&file)?;
+        let mut messages = Vec::new();
+        for message in export.messages {
+            let time = match DateTime::parse_from_rfc3339(&message.timestamp) {
+                Ok(time) if DISCORD_MESSAGE_TYPES.contains(&message.kind.as_str()) => time.with_timezone(&Utc),
+                _ => {
+                    skipped += 1;
+                    continue;
+                }
+            };
+
+            messages.push(ImportedMessage {
+                source_id: message.id,
+                reply_to: message.reference.and_then(|reference| reference.message_id),
+                author: message.author.name,
+                time,
+                body: message.content,
+                edited_at: message.timestamp_edited
+                    .and_then(|edited| DateTime::parse_from_rfc3339(&edited).ok())
+                    .map(|edited| edited.with_timezone(&Utc)),
+                attachments: message.attachments
+                    .into_iter()
+                    .map(|attachment| Attachment { name: attachment.file_name, url: attachment.url })
+                    .collect(),
+            });
+        }
+        messages.sort_by_key(|message| message.time);
+
+        channels.push(ImportedChannel {
+            source_id: export.channel.id,
+            name: export.channel.name,
+            creator: None,
+            topic: export.channel.topic.unwrap_or_default(),
+            description: String::new(),
+            private: false,
+            members: Vec::new(),
+            messages,
+        });
+    }
+    Ok((channels, skipped))
+}
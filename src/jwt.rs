@@ -0,0 +1,267 @@
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use jsonwebtoken::{Algorithm, Header, Validation};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite};
+use uuid::Uuid;
+use crate::config::JwtConfig;
+use crate::database::format_timestamp;
+use crate::token::{hash_token, random_secret, Credential, TokenUser};
+
+/// Refresh tokens start with this. They are opaque, only access tokens are JWTs.
+const REFRESH_TOKEN_PREFIX: &str = "chatr_";
+
+/// How often expired refresh tokens and revocation list entries are removed
+const CLEANUP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// Claims of an access token
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AccessClaims {
+    /// The user's ID
+    pub sub: u32,
+    pub name: String,
+    pub iat: i64,
+    pub exp: i64,
+    /// Token ID, the key of the revocation list
+    pub jti: String,
+}
+
+/// What `/user/login` in JWT mode and `/user/token/refresh` return
+#[derive(Serialize)]
+pub struct TokenPair {
+    access_token: String,
+    refresh_token: String,
+    token_type: &'static str,
+    /// Seconds until the access token expires
+    expires_in: i64,
+}
+
+#[derive(Deserialize)]
+pub struct RefreshRequest {
+    refresh_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct RevokeRequest {
+    refresh_token: Option<String>,
+}
+
+fn chrono_duration(duration: actix_web::cookie::time::Duration) -> chrono::Duration {
+    chrono::Duration::seconds(duration.whole_seconds())
+}
+
+/// Issue an access token and a refresh token. Refresh tokens rotated from the
+/// same login share a `family`, so reusing an old one can revoke them all.
+pub async fn issue_tokens(
+    db: &Pool<Sqlite>,
+    config: &JwtConfig,
+    user_id: u32,
+    username: &str,
+    family: Option<&str>,
+) -> Result<TokenPair, sqlx::Error> {
+    let now = Utc::now();
+    let claims = AccessClaims {
+        sub: user_id,
+        name: username.to_string(),
+        iat: now.timestamp(),
+        exp: (now + chrono_duration(config.access_ttl)).timestamp(),
+        jti: Uuid::new_v4().to_string(),
+    };
+    let access_token = jsonwebtoken::encode(&Header::new(Algorithm::HS256), &claims, &config.encoding_key)
+        .expect("Failed to sign access token");
+
+    let refresh_token = random_secret(REFRESH_TOKEN_PREFIX);
+    let family = family.map(str::to_string).unwrap_or_else(|| Uuid::new_v4().to_string());
+    sqlx::query("INSERT INTO RefreshTokens (TokenHash, Family, UserId, CreatedAt, ExpiresAt) VALUES (?, ?, ?, ?, ?);")
+        .bind(hash_token(&refresh_token))
+        .bind(family)
+        .bind(user_id)
+        .bind(format_timestamp(now))
+        .bind(format_timestamp(now + chrono_duration(config.refresh_ttl)))
+        .execute(db)
+        .await?;
+
+    Ok(TokenPair {
+        access_token,
+        refresh_token,
+        token_type: "Bearer",
+        expires_in: config.access_ttl.whole_seconds(),
+    })
+}
+
+/// The user an access token belongs to, or `None` if it is invalid, expired or revoked
+pub async fn authenticate_access_token(db: &Pool<Sqlite>, config: &JwtConfig, token: &str) -> Result<Option<TokenUser>, sqlx::Error> {
+    // Tokens are checked by the server that issued them, so there is no clock skew to allow for
+    let mut validation = Validation::new(Algorithm::HS256);
+    validation.leeway = 0;
+    let claims = match jsonwebtoken::decode::<AccessClaims>(token, &config.decoding_key, &validation) {
+        Ok(data) => data.claims,
+        Err(_) => return Ok(None),
+    };
+    let revoked: Option<String> = sqlx::query_scalar("SELECT Jti FROM RevokedTokens WHERE Jti = ?;")
+        .bind(&claims.jti)
+        .fetch_optional(db)
+        .await?;
+    if revoked.is_some() {
+        return Ok(None);
+    }
+    let valid_after: Option<Option<i64>> = sqlx::query_scalar("SELECT TokensValidAfter FROM Users WHERE id = ?;")
+        .bind(claims.sub)
+        .fetch_optional(db)
+        .await?;
+    match valid_after {
+        None => return Ok(None),
+        Some(Some(valid_after)) if claims.iat < valid_after => return Ok(None),
+        Some(_) => {}
+    }
+
+    Ok(Some(TokenUser {
+        user_id: claims.sub,
+        username: claims.name.clone(),
+        credential: Credential::Jwt(claims),
+    }))
+}
+
+/// Trade a refresh token for a new pair. Every refresh token works once: presenting
+/// one again means it was copied, so the whole family is revoked.
+async fn rotate_refresh_token(db: &Pool<Sqlite>, config: &JwtConfig, refresh_token: &str) -> Result<Option<TokenPair>, sqlx::Error> {
+    let now = format_timestamp(Utc::now());
+    let mut tx = db.begin_with("BEGIN IMMEDIATE").await?;
+    let row: Option<(String, u32, String, Option<String>)> = sqlx::query_as("SELECT r.Family, r.UserId, u.Username, r.UsedAt
+        FROM RefreshTokens r JOIN Users u ON u.id = r.UserId
+        WHERE r.TokenHash = ? AND r.ExpiresAt > ?;")
+        .bind(hash_token(refresh_token))
+        .bind(&now)
+        .fetch_optional(&mut *tx)
+        .await?;
+    let (family, user_id, username, used_at) = match row {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+
+    if used_at.is_some() {
+        sqlx::query("DELETE FROM RefreshTokens WHERE Family = ?;")
+            .bind(&family)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        println!("A refresh token of user {} was used twice, revoked the tokens of that login", username);
+        return Ok(None);
+    }
+
+    sqlx::query("UPDATE RefreshTokens SET UsedAt = ? WHERE TokenHash = ?;")
+        .bind(&now)
+        .bind(hash_token(refresh_token))
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    issue_tokens(db, config, user_id, &username, Some(&family)).await.map(Some)
+}
+
+/// Put an access token on the revocation list until it expires
+pub async fn revoke_access_token(db: &Pool<Sqlite>, claims: &AccessClaims) -> Result<(), sqlx::Error> {
+    let expires_at = DateTime::from_timestamp(claims.exp, 0).unwrap_or_else(Utc::now);
+    sqlx::query("INSERT OR IGNORE INTO RevokedTokens (Jti, ExpiresAt) VALUES (?, ?);")
+        .bind(&claims.jti)
+        .bind(format_timestamp(expires_at))
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// Revoke a refresh token and every token rotated from the same login
+async fn revoke_refresh_family(db: &Pool<Sqlite>, refresh_token: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM RefreshTokens WHERE Family IN
+        (SELECT Family FROM RefreshTokens WHERE TokenHash = ?);")
+        .bind(hash_token(refresh_token))
+        .execute(db)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Revoke every token issued to a user so far, e.g. when their password changes. Access tokens
+/// issued before the returned time are rejected from then on, since they can't be listed.
+pub async fn revoke_user_tokens(db: &Pool<Sqlite>, user_id: u32) -> Result<i64, sqlx::Error> {
+    let now = Utc::now().timestamp();
+    let mut tx = db.begin().await?;
+    sqlx::query("UPDATE Users SET TokensValidAfter = ? WHERE id = ?;")
+        .bind(now)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM RefreshTokens WHERE UserId = ?;")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(now)
+}
+
+/// Start removing expired refresh tokens and revocation list entries, now and every `CLEANUP_INTERVAL`
+pub fn spawn_jwt_cleanup_task(db: Pool<Sqlite>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+        loop {
+            interval.tick().await;
+            let now = format_timestamp(Utc::now());
+            for table in ["RefreshTokens", "RevokedTokens"] {
+                let result = sqlx::query(&format!("DELETE FROM {} WHERE ExpiresAt <= ?;", table))
+                    .bind(&now)
+                    .execute(&db)
+                    .await;
+                if let Err(e) = result {
+                    println!("Failed to remove expired tokens from {}: {}", table, e);
+                }
+            }
+        }
+    });
+}
+
+/// Get a new access token with a refresh token, which is replaced by a new one
+pub async fn jwt_refresh(
+    db: web::Data<Pool<Sqlite>>,
+    config: web::Data<JwtConfig>,
+    form: web::Json<RefreshRequest>,
+) -> impl Responder {
+    match rotate_refresh_token(&db, &config, &form.refresh_token).await {
+        Ok(Some(tokens)) => HttpResponse::Ok().json(tokens),
+        Ok(None) => HttpResponse::Unauthorized().json("Invalid refresh token."),
+        Err(e) => {
+            println!("Error refreshing tokens: {}", e);
+            HttpResponse::InternalServerError().json("Failed to refresh tokens.")
+        }
+    }
+}
+
+/// Log out of JWT mode: revoke the access token the request was made with and the given refresh token
+pub async fn jwt_revoke(
+    req: HttpRequest,
+    db: web::Data<Pool<Sqlite>>,
+    form: web::Json<RevokeRequest>,
+) -> impl Responder {
+    let claims = match req.extensions().get::<TokenUser>().map(|user| &user.credential) {
+        Some(Credential::Jwt(claims)) => Some(claims.clone()),
+        _ => None,
+    };
+    if claims.is_none() && form.refresh_token.is_none() {
+        return HttpResponse::BadRequest().json("Nothing to revoke.");
+    }
+
+    if let Some(claims) = claims {
+        if let Err(e) = revoke_access_token(&db, &claims).await {
+            println!("Error revoking access token: {}", e);
+            return HttpResponse::InternalServerError().json("Failed to revoke tokens.");
+        }
+    }
+    if let Some(refresh_token) = &form.refresh_token {
+        match revoke_refresh_family(&db, refresh_token).await {
+            Ok(true) => {}
+            Ok(false) => return HttpResponse::NotFound().json("Refresh token not found."),
+            Err(e) => {
+                println!("Error revoking refresh token: {}", e);
+                return HttpResponse::InternalServerError().json("Failed to revoke tokens.");
+            }
+        }
+    }
+    HttpResponse::Ok().json("Tokens revoked.")
+}
@@ -1,30 +1,75 @@
-use actix_web::{web, App, HttpServer, cookie::Key, Responder};
+use actix_web::{middleware, web, App, HttpServer, Responder};
 use sqlx::{Pool, Sqlite};
-use actix_session::{SessionMiddleware, storage::CookieSessionStore};
 // use actix_web::HttpResponse;
-use tokio;
 use actix_files as fs;
+mod config;
 mod database;
 mod user;
 mod channel;
 mod status;
 mod websocket;
+mod protocol;
+mod message;
+mod direct;
+mod member;
+mod record;
+mod search;
+mod export;
+mod import;
+mod retention;
+mod session;
+mod token;
+mod jwt;
+mod password;
 
+use config::{CorsConfig, JwtConfig, PasswordConfig, SessionConfig};
 use database::init_sqlite_db;
 use database::init_sled_db;
+use database::backfill_message_index;
+use database::migrate_storage_keys;
+use database::migrate_message_records;
+use database::migrate_message_keys;
+use database::strip_ping_messages;
 use user::register;
 use user::login;
 use user::logout;
+use user::change_password;
 use user::user_status;
 use channel::channel_create;
 use channel::channel_enter;
 // use channel::channel_exit;
 use channel::channel_history;
 use channel::channel_list;
+use channel::channel_rename;
+use channel::channel_topic;
+use channel::channel_archive;
+use channel::channel_delete;
+use channel::channel_retention;
+use message::message_delete;
+use message::message_edit;
+use message::message_revisions;
+use message::message_thread;
+use direct::direct_list;
+use direct::direct_open;
+use member::channel_accept;
+use member::channel_invitations;
+use member::channel_invite;
+use member::channel_kick;
+use member::channel_mute;
+use member::channel_permissions;
+use member::channel_roles;
+use member::channel_set_role;
+use search::build_search_index;
+use export::channel_export;
+use import::{import_history, ImportSource};
+use retention::spawn_retention_task;
+use session::{session_list, session_revoke, spawn_session_cleanup_task};
+use token::{token_auth, token_create, token_list, token_revoke};
+use jwt::{jwt_refresh, jwt_revoke, spawn_jwt_cleanup_task};
+use search::channel_search;
+use search::rebuild_search_index;
 use std::sync::{Arc, Mutex};
 use crate::websocket::ChatState;
-use actix_cors::Cors;
-use actix_web::http::header;
 use std::collections::HashMap;
 
 async fn login_page() -> impl Responder {
@@ -62,14 +107,71 @@ async fn main() -> std::io::Result<()> {
     let sqlite_db: Pool<Sqlite> = init_sqlite_db().await;
     // let sled_db: Db = init_sled_db().await;
     let sled_db = web::Data::new(init_sled_db().await);
-    let secret_key = Key::generate();
+    migrate_storage_keys(&sqlite_db, &sled_db).await;
+    backfill_message_index(&sqlite_db, &sled_db).await;
+    migrate_message_records(&sqlite_db, &sled_db).await;
+    migrate_message_keys(&sqlite_db, &sled_db).await;
+    build_search_index(&sqlite_db, &sled_db).await;
+
+    // Maintenance commands run against the databases instead of starting the server
+    match std::env::args().nth(1).as_deref() {
+        None => {}
+        Some("strip-pings") => {
+            let count = strip_ping_messages(&sqlite_db, &sled_db).await;
+            println!("Removed {} stored pings.", count);
+            rebuild_search_index(&sqlite_db, &sled_db).await.expect("Failed to rebuild the search index");
+            return Ok(());
+        }
+        Some("rebuild-search") => {
+            let count = rebuild_search_index(&sqlite_db, &sled_db).await.expect("Failed to rebuild the search index");
+            println!("Indexed {} messages for search.", count);
+            return Ok(());
+        }
+        Some("import") => {
+            let usage = "Usage: chat import <slack|discord> <path> [owner]";
+            let source = std::env::args().nth(2).and_then(|source| ImportSource::parse(&source));
+            let (source, path) = match (source, std::env::args().nth(3)) {
+                (Some(source), Some(path)) => (source, path),
+                _ => {
+                    eprintln!("{}", usage);
+                    std::process::exit(2);
+                }
+            };
+            let owner = std::env::args().nth(4);
+            match import_history(&sqlite_db, &sled_db, source, std::path::Path::new(&path), owner.as_deref()).await {
+                Ok(summary) => println!(
+                    "Imported {} messages. Created {} channels and {} placeholder users, skipped {} messages.",
+                    summary.messages, summary.channels, summary.users, summary.skipped,
+                ),
+                Err(err) => {
+                    eprintln!("Import failed: {}", err);
+                    std::process::exit(1);
+                }
+            }
+            rebuild_search_index(&sqlite_db, &sled_db).await.expect("Failed to rebuild the search index");
+            return Ok(());
+        }
+        Some(command) => {
+            eprintln!("Unknown command: {}. Available commands: strip-pings, rebuild-search, import", command);
+            std::process::exit(2);
+        }
+    }
+
+    spawn_retention_task(sqlite_db.clone(), sled_db.get_ref().clone());
+
+    let session_config = SessionConfig::from_env();
+    let session_store = session_config.session_store(sqlite_db.clone());
+    spawn_session_cleanup_task(session_store.clone());
+    let jwt_config = web::Data::new(JwtConfig::from_env(&session_config));
+    let password_config = web::Data::new(PasswordConfig::from_env());
+    let cors_config = CorsConfig::from_env();
+    spawn_jwt_cleanup_task(sqlite_db.clone());
     // let chat_state = web::Data::new(Arc::new(ChatState {
     //     messages: Mutex::new(Vec::new()),
     //     connected_users: Mutex::new(Vec::new()),
     //     sessions: Mutex::new(Vec::new()),  // Add this
     // }));
     let chat_state = web::Data::new(Arc::new(ChatState {
-        messages: Mutex::new(Vec::new()),
         // connected_users: Mutex::new(Vec::new()),
         sessions: Mutex::new(HashMap::new()),
     }));
@@ -79,29 +181,14 @@ async fn main() -> std::io::Result<()> {
         let chat_state = chat_state.clone();
         let sled_db = sled_db.clone();
         App::new()
-            .wrap(
-                SessionMiddleware::builder(CookieSessionStore::default(), secret_key.clone())
-                    .cookie_secure(false)
-                    .build(),
-            )
-            .wrap(
-                Cors::default()
-                    .allowed_origin("http://localhost:8081")
-                    .allowed_methods(vec!["GET", "POST"])
-                    .allowed_headers(vec![
-                        header::AUTHORIZATION,
-                        header::ACCEPT,
-                        header::CONTENT_TYPE,
-                        header::UPGRADE,
-                        header::CONNECTION,
-                        header::SEC_WEBSOCKET_KEY,
-                        header::SEC_WEBSOCKET_VERSION,
-                    ])
-                    .supports_credentials()
-                    .max_age(3600)
-            )
+            .wrap(middleware::from_fn(token_auth))
+            .wrap(session_config.session_middleware(session_store.clone()))
+            .wrap(cors_config.cors())
             .app_data(web::Data::new(sqlite_db.clone()))
             .app_data(web::Data::new(sled_db.clone()))
+            .app_data(web::Data::new(session_store.clone()))
+            .app_data(jwt_config.clone())
+            .app_data(password_config.clone())
             .route("/", web::get().to(index))
             .route("/login", web::get().to(login_page))
             .route("/register", web::get().to(register_page))
@@ -112,24 +199,58 @@ async fn main() -> std::io::Result<()> {
             .service(
                 web::scope("/user")
                     .app_data(sled_db.clone()) 
+                    .app_data(chat_state.clone())
                     .route("/register", web::post().to(register))
                     .route("/login", web::post().to(login))
                     .route("/logout", web::post().to(logout))
+                    .route("/password", web::post().to(change_password))
+                    .route("/sessions", web::get().to(session_list))
+                    .route("/sessions/revoke/{id}", web::post().to(session_revoke))
+                    .route("/tokens", web::post().to(token_create))
+                    .route("/tokens", web::get().to(token_list))
+                    .route("/tokens/revoke/{id}", web::post().to(token_revoke))
+                    .route("/token/refresh", web::post().to(jwt_refresh))
+                    .route("/token/revoke", web::post().to(jwt_revoke))
                     .route("/status/{name}", web::get().to(user_status))
             )
             .service(
                 web::scope("/channel")
                     .app_data(sled_db.clone()) 
+                    .app_data(chat_state.clone())
                     .route("/create", web::post().to(channel_create))
                     .route("/list", web::get().to(channel_list))
+                    .route("/search", web::get().to(channel_search))
                     .route("/enter/{name}", web::get().to(channel_enter))
+                    .route("/rename/{name}", web::post().to(channel_rename))
+                    .route("/topic/{name}", web::post().to(channel_topic))
+                    .route("/archive/{name}", web::post().to(channel_archive))
+                    .route("/delete/{name}", web::post().to(channel_delete))
+                    .route("/retention/{name}", web::post().to(channel_retention))
+                    .route("/invite/{name}", web::post().to(channel_invite))
+                    .route("/accept/{name}", web::post().to(channel_accept))
+                    .route("/kick/{name}", web::post().to(channel_kick))
+                    .route("/invitations", web::get().to(channel_invitations))
+                    .route("/mute/{name}", web::post().to(channel_mute))
+                    .route("/role/{name}", web::post().to(channel_set_role))
+                    .route("/roles/{name}", web::get().to(channel_roles))
+                    .route("/permissions/{name}", web::get().to(channel_permissions))
                     .route("/history/{name}", web::get().to(channel_history))
+                    .route("/export/{name}", web::get().to(channel_export))
+                    .route("/message/edit/{name}", web::post().to(message_edit))
+                    .route("/message/delete/{name}", web::post().to(message_delete))
+                    .route("/message/revisions/{name}/{id}", web::get().to(message_revisions))
+                    .route("/thread/{name}/{id}", web::get().to(message_thread))
                     .route("/ws/{channel_name}", web::get().to(
                         move |req, stream, path: web::Path<String>| {
                             websocket::chat_route(req, stream, chat_state.clone(), sled_db.clone(), path)
                         }
                     ))
                 )
+            .service(
+                web::scope("/direct")
+                    .route("/open", web::post().to(direct_open))
+                    .route("/list", web::get().to(direct_list))
+            )
             .service(fs::Files::new("/", "./static"))
     })
     .bind("127.0.0.1:8080")?;
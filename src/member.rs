@@ -0,0 +1,405 @@
+use actix_web::{web, Error, Responder, HttpRequest, HttpResponse};
+use actix_web::error::{ErrorForbidden, ErrorInternalServerError, ErrorNotFound};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{Pool, Sqlite};
+use std::sync::Arc;
+use crate::channel::ChannelRef;
+use crate::database::{channel_storage_key, direct_storage_key};
+use crate::direct::{get_other_participant, is_direct, DIRECT_PREFIX};
+use crate::user::{authorize, channel_role, check_auth, Permission, Role};
+use crate::websocket::ChatState;
+
+/// `ChannelMembers.Status` of a user who was invited but has not accepted yet
+const INVITED: &str = "invited";
+/// `ChannelMembers.Status` of a member of a private channel
+pub const MEMBER: &str = "member";
+
+#[derive(Deserialize)]
+pub struct ChannelPath {
+    name: String,
+}
+
+#[derive(Deserialize)]
+pub struct MemberRequest {
+    username: String,
+}
+
+#[derive(Deserialize)]
+pub struct MuteRequest {
+    username: String,
+    /// `false` to unmute
+    #[serde(default = "default_muted")]
+    muted: bool,
+}
+
+fn default_muted() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+pub struct RoleRequest {
+    username: String,
+    role: Role,
+}
+
+#[derive(Serialize, sqlx::FromRow, Debug)]
+struct Invitation {
+    channel: String,
+    owner: String,
+}
+
+/// Whether `username` may enter, read and post in a channel. Public channels are open
+/// to every user, private channels to their owner and members, and direct conversations
+/// to their two participants. Returns `None` if the channel does not exist.
+pub async fn can_access_channel(db: &Pool<Sqlite>, channel_name: &str, username: &str) -> Result<Option<bool>, sqlx::Error> {
+    if is_direct(channel_name) {
+        return Ok(Some(get_other_participant(db, channel_name, username).await?.is_some()));
+    }
+
+    let query = "SELECT c.Private, c.Owner = ?, EXISTS (SELECT 1 FROM ChannelMembers m WHERE m.ChannelId = c.id AND m.Username = ? AND m.Status = ?)
+        FROM Channel c WHERE c.Name = ?;";
+    let access: Option<(bool, bool, bool)> = sqlx::query_as(query)
+        .bind(username)
+        .bind(username)
+        .bind(MEMBER)
+        .bind(channel_name)
+        .fetch_optional(db)
+        .await?;
+
+    Ok(access.map(|(private, is_owner, is_member)| !private || is_owner || is_member))
+}
+
+/// Every channel and direct conversation `username` may access, as `ChannelRef`s
+pub async fn accessible_channels(db: &Pool<Sqlite>, username: &str) -> Result<Vec<ChannelRef>, sqlx::Error> {
+    let query = "SELECT c.id, c.Name FROM Channel c WHERE c.Private = 0 OR c.Owner = ?
+        OR EXISTS (SELECT 1 FROM ChannelMembers m WHERE m.ChannelId = c.id AND m.Username = ? AND m.Status = ?);";
+    let channels: Vec<(i64, String)> = sqlx::query_as(query)
+        .bind(username)
+        .bind(username)
+        .bind(MEMBER)
+        .fetch_all(db)
+        .await?;
+    let directs: Vec<i64> = sqlx::query_scalar("SELECT id FROM DirectMessage WHERE UserA = ? OR UserB = ?;")
+        .bind(username)
+        .bind(username)
+        .fetch_all(db)
+        .await?;
+
+    Ok(channels
+        .into_iter()
+        .map(|(id, name)| ChannelRef { name, key: channel_storage_key(id) })
+        .chain(directs.into_iter().map(|id| ChannelRef {
+            name: format!("{}{}", DIRECT_PREFIX, id),
+            key: direct_storage_key(id),
+        }))
+        .collect())
+}
+
+/// Check that the logged-in user may access a channel and return their username
+pub async fn check_channel_access(db: &Pool<Sqlite>, req: &HttpRequest, channel_name: &str) -> Result<String, Error> {
+    let (_user_id, username) = check_auth(req)?;
+    match can_access_channel(db, channel_name, &username).await {
+        Ok(Some(true)) => Ok(username),
+        Ok(Some(false)) => Err(ErrorForbidden("Not a member of this channel.")),
+        Ok(None) => Err(ErrorNotFound("Channel not found.")),
+        Err(e) => {
+            println!("Error querying database: {}", e);
+            Err(ErrorInternalServerError("Failed to check channel membership."))
+        }
+    }
+}
+
+/// Map a database error to a 500 response, logging it
+fn database_error(e: sqlx::Error) -> Error {
+    println!("Error querying database: {}", e);
+    ErrorInternalServerError("Failed to update channel members.")
+}
+
+/// Remove `target` from a channel on behalf of `username` and disconnect them. Members of
+/// a private channel lose their membership and role; in a public channel they can reconnect.
+pub async fn kick_member(db: &Pool<Sqlite>, state: &ChatState, channel_name: &str, username: &str, target: &str) -> Result<(), Error> {
+    authorize(db, channel_name, username, Permission::Kick, Some(target)).await?;
+
+    let private: bool = sqlx::query_scalar("SELECT Private FROM Channel WHERE Name = ?;")
+        .bind(channel_name)
+        .fetch_one(db)
+        .await
+        .map_err(database_error)?;
+    if private {
+        let removed = sqlx::query("DELETE FROM ChannelMembers WHERE Username = ? AND ChannelId = (SELECT id FROM Channel WHERE Name = ?);")
+            .bind(target)
+            .bind(channel_name)
+            .execute(db)
+            .await
+            .map_err(database_error)?;
+        if removed.rows_affected() == 0 {
+            return Err(ErrorNotFound("User is not a member."));
+        }
+        sqlx::query("DELETE FROM ChannelRoles WHERE Username = ? AND ChannelId = (SELECT id FROM Channel WHERE Name = ?);")
+            .bind(target)
+            .bind(channel_name)
+            .execute(db)
+            .await
+            .map_err(database_error)?;
+    }
+
+    state.disconnect(channel_name, Some(target), "You were removed from this channel.");
+    Ok(())
+}
+
+/// Mute or unmute `target` in a channel on behalf of `username`. Muted users can still read.
+pub async fn set_muted(
+    db: &Pool<Sqlite>,
+    state: &ChatState,
+    channel_name: &str,
+    username: &str,
+    target: &str,
+    muted: bool,
+) -> Result<(), Error> {
+    authorize(db, channel_name, username, Permission::Mute, Some(target)).await?;
+
+    let query = if muted {
+        "INSERT OR IGNORE INTO ChannelMutes (ChannelId, Username) SELECT id, ? FROM Channel WHERE Name = ?;"
+    } else {
+        "DELETE FROM ChannelMutes WHERE Username = ? AND ChannelId = (SELECT id FROM Channel WHERE Name = ?);"
+    };
+    sqlx::query(query)
+        .bind(target)
+        .bind(channel_name)
+        .execute(db)
+        .await
+        .map_err(database_error)?;
+
+    state.set_muted(channel_name, target, muted);
+    Ok(())
+}
+
+pub async fn is_muted(db: &Pool<Sqlite>, channel_name: &str, username: &str) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM ChannelMutes m JOIN Channel c ON c.id = m.ChannelId WHERE c.Name = ? AND m.Username = ?);")
+        .bind(channel_name)
+        .bind(username)
+        .fetch_one(db)
+        .await
+}
+
+/// Invite a registered user to a private channel
+pub async fn channel_invite(
+    db: web::Data<Pool<Sqlite>>,
+    req: HttpRequest,
+    info: web::Path<ChannelPath>,
+    form: web::Json<MemberRequest>,
+) -> impl Responder {
+    let (_user_id, username) = match check_auth(&req) {
+        Ok(user) => user,
+        Err(_) => return HttpResponse::Unauthorized().json("User not logged in."),
+    };
+    if let Err(err) = authorize(&db, &info.name, &username, Permission::Invite, None).await {
+        return err.error_response();
+    }
+
+    let channel: Option<(i64, bool)> = match sqlx::query_as("SELECT id, Private FROM Channel WHERE Name = ?;")
+        .bind(&info.name)
+        .fetch_optional(db.get_ref())
+        .await
+    {
+        Ok(channel) => channel,
+        Err(e) => return database_error(e).error_response(),
+    };
+    let channel_id = match channel {
+        Some((id, true)) => id,
+        Some((_, false)) => return HttpResponse::BadRequest().json("Only private channels have members."),
+        None => return HttpResponse::NotFound().json("Channel not found."),
+    };
+
+    match sqlx::query_scalar::<_, i64>("SELECT id FROM Users WHERE Username = ?;")
+        .bind(&form.username)
+        .fetch_optional(db.get_ref())
+        .await
+    {
+        Ok(Some(_)) => {}
+        Ok(None) => return HttpResponse::NotFound().json("User not found."),
+        Err(e) => return database_error(e).error_response(),
+    }
+
+    let result = sqlx::query("INSERT INTO ChannelMembers (ChannelId, Username, Status) VALUES (?, ?, ?);")
+        .bind(channel_id)
+        .bind(&form.username)
+        .bind(INVITED)
+        .execute(db.get_ref())
+        .await;
+
+    match result {
+        Ok(_) => HttpResponse::Ok().json("User invited."),
+        Err(_) => HttpResponse::Conflict().json("User is already invited or a member."),
+    }
+}
+
+/// Accept an invitation to a private channel
+pub async fn channel_accept(db: web::Data<Pool<Sqlite>>, req: HttpRequest, info: web::Path<ChannelPath>) -> impl Responder {
+    let (_user_id, username) = match check_auth(&req) {
+        Ok(user) => user,
+        Err(_) => return HttpResponse::Unauthorized().json("User not logged in."),
+    };
+
+    let result = sqlx::query("UPDATE ChannelMembers SET Status = ?
+        WHERE Username = ? AND Status = ? AND ChannelId = (SELECT id FROM Channel WHERE Name = ?);")
+        .bind(MEMBER)
+        .bind(&username)
+        .bind(INVITED)
+        .bind(&info.name)
+        .execute(db.get_ref())
+        .await;
+
+    match result {
+        Ok(done) if done.rows_affected() > 0 => HttpResponse::Ok().json("Invitation accepted."),
+        Ok(_) => HttpResponse::NotFound().json("Invitation not found."),
+        Err(e) => database_error(e).error_response(),
+    }
+}
+
+pub async fn channel_kick(
+    db: web::Data<Pool<Sqlite>>,
+    state: web::Data<Arc<ChatState>>,
+    req: HttpRequest,
+    info: web::Path<ChannelPath>,
+    form: web::Json<MemberRequest>,
+) -> impl Responder {
+    let (_user_id, username) = match check_auth(&req) {
+        Ok(user) => user,
+        Err(_) => return HttpResponse::Unauthorized().json("User not logged in."),
+    };
+
+    match kick_member(&db, &state, &info.name, &username, &form.username).await {
+        Ok(()) => HttpResponse::Ok().json("User removed."),
+        Err(err) => err.error_response(),
+    }
+}
+
+pub async fn channel_mute(
+    db: web::Data<Pool<Sqlite>>,
+    state: web::Data<Arc<ChatState>>,
+    req: HttpRequest,
+    info: web::Path<ChannelPath>,
+    form: web::Json<MuteRequest>,
+) -> impl Responder {
+    let (_user_id, username) = match check_auth(&req) {
+        Ok(user) => user,
+        Err(_) => return HttpResponse::Unauthorized().json("User not logged in."),
+    };
+
+    match set_muted(&db, &state, &info.name, &username, &form.username, form.muted).await {
+        Ok(()) if form.muted => HttpResponse::Ok().json("User muted."),
+        Ok(()) => HttpResponse::Ok().json("User unmuted."),
+        Err(err) => err.error_response(),
+    }
+}
+
+/// Give a user a role in a channel. Only the owner can do this, and ownership cannot be handed over.
+pub async fn channel_set_role(
+    db: web::Data<Pool<Sqlite>>,
+    req: HttpRequest,
+    info: web::Path<ChannelPath>,
+    form: web::Json<RoleRequest>,
+) -> impl Responder {
+    let (_user_id, username) = match check_auth(&req) {
+        Ok(user) => user,
+        Err(_) => return HttpResponse::Unauthorized().json("User not logged in."),
+    };
+    if form.role == Role::Owner {
+        return HttpResponse::BadRequest().json("A channel has exactly one owner.");
+    }
+    if let Err(err) = authorize(&db, &info.name, &username, Permission::ManageRoles, Some(&form.username)).await {
+        return err.error_response();
+    }
+
+    match sqlx::query_scalar::<_, i64>("SELECT id FROM Users WHERE Username = ?;")
+        .bind(&form.username)
+        .fetch_optional(db.get_ref())
+        .await
+    {
+        Ok(Some(_)) => {}
+        Ok(None) => return HttpResponse::NotFound().json("User not found."),
+        Err(e) => return database_error(e).error_response(),
+    }
+
+    // Members are everyone without a stored role
+    let result = if form.role == Role::Member {
+        sqlx::query("DELETE FROM ChannelRoles WHERE Username = ? AND ChannelId = (SELECT id FROM Channel WHERE Name = ?);")
+            .bind(&form.username)
+            .bind(&info.name)
+            .execute(db.get_ref())
+            .await
+    } else {
+        sqlx::query("INSERT OR REPLACE INTO ChannelRoles (ChannelId, Username, Role) SELECT id, ?, ? FROM Channel WHERE Name = ?;")
+            .bind(&form.username)
+            .bind(form.role.as_str())
+            .bind(&info.name)
+            .execute(db.get_ref())
+            .await
+    };
+
+    match result {
+        Ok(_) => HttpResponse::Ok().json("Role updated."),
+        Err(e) => database_error(e).error_response(),
+    }
+}
+
+/// List the users with a role above member in a channel, owner first
+pub async fn channel_roles(db: web::Data<Pool<Sqlite>>, req: HttpRequest, info: web::Path<ChannelPath>) -> impl Responder {
+    if let Err(err) = check_channel_access(&db, &req, &info.name).await {
+        return err.error_response();
+    }
+
+    let query = "SELECT Owner AS username, 'owner' AS role FROM Channel WHERE Name = ?
+        UNION ALL
+        SELECT r.Username, r.Role FROM ChannelRoles r JOIN Channel c ON c.id = r.ChannelId WHERE c.Name = ?;";
+    let result: Result<Vec<(String, String)>, sqlx::Error> = sqlx::query_as(query)
+        .bind(&info.name)
+        .bind(&info.name)
+        .fetch_all(db.get_ref())
+        .await;
+
+    match result {
+        Ok(rows) => {
+            let roles: Vec<_> = rows.into_iter().map(|(username, role)| json!({ "username": username, "role": role })).collect();
+            HttpResponse::Ok().json(roles)
+        }
+        Err(e) => database_error(e).error_response(),
+    }
+}
+
+/// The logged-in user's role in a channel and what it allows them to do
+pub async fn channel_permissions(db: web::Data<Pool<Sqlite>>, req: HttpRequest, info: web::Path<ChannelPath>) -> impl Responder {
+    let username = match check_channel_access(&db, &req, &info.name).await {
+        Ok(username) => username,
+        Err(err) => return err.error_response(),
+    };
+
+    match channel_role(&db, &info.name, &username).await {
+        Ok(Some(role)) => HttpResponse::Ok().json(json!({ "role": role, "permissions": role.permissions() })),
+        Ok(None) => HttpResponse::NotFound().json("Channel not found."),
+        Err(e) => database_error(e).error_response(),
+    }
+}
+
+/// List the private channels the logged-in user was invited to
+pub async fn channel_invitations(db: web::Data<Pool<Sqlite>>, req: HttpRequest) -> impl Responder {
+    let (_user_id, username) = match check_auth(&req) {
+        Ok(user) => user,
+        Err(_) => return HttpResponse::Unauthorized().json("User not logged in."),
+    };
+
+    let query = "SELECT c.Name AS channel, c.Owner AS owner FROM ChannelMembers m JOIN Channel c ON c.id = m.ChannelId
+        WHERE m.Username = ? AND m.Status = ?;";
+    let result: Result<Vec<Invitation>, sqlx::Error> = sqlx::query_as(query)
+        .bind(&username)
+        .bind(INVITED)
+        .fetch_all(db.get_ref())
+        .await;
+
+    match result {
+        Ok(invitations) => HttpResponse::Ok().json(invitations),
+        Err(e) => HttpResponse::InternalServerError().json(e.to_string()),
+    }
+}
@@ -0,0 +1,329 @@
+use actix_web::{web, Responder, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite};
+use std::fmt;
+use std::sync::Arc;
+use crate::channel::{resolve_channel, ChannelRef, ChatMessage};
+use crate::member::{check_channel_access, is_muted};
+use crate::channel::is_archived;
+use crate::database::{delete_chat_message_sled, edit_chat_message_sled, get_chat_message_sled, get_message_revisions_sled, get_thread_sled, set_reaction_sled};
+use crate::protocol::ServerEvent;
+use crate::search::{index_message, unindex_message};
+use crate::user::{authorize, check_auth, Permission};
+use crate::websocket::ChatState;
+
+/// Longest accepted reaction, in bytes. Enough for emoji built from several code points.
+const MAX_REACTION_LEN: usize = 32;
+
+/// A previous body of an edited message
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageRevision {
+    pub message: String,
+    /// When this body was replaced by an edit
+    pub replaced_at: String,
+}
+
+/// A message together with the replies in its thread
+#[derive(Serialize, Debug)]
+pub struct Thread {
+    parent: ChatMessage,
+    replies: Vec<ChatMessage>,
+}
+
+#[derive(Deserialize)]
+pub struct EditRequest {
+    id: String,
+    message: String,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteRequest {
+    id: String,
+}
+
+#[derive(Deserialize)]
+pub struct ChannelPath {
+    name: String,
+}
+
+#[derive(Deserialize)]
+pub struct MessagePath {
+    name: String,
+    id: String,
+}
+
+/// Reasons a message operation can be refused, shared by the REST and WebSocket paths
+#[derive(Debug)]
+pub enum MessageError {
+    NotFound,
+    Forbidden,
+    /// The channel is archived and read-only
+    Archived,
+    /// The user is muted in the channel
+    Muted,
+    /// Refused by the channel's permission matrix
+    Denied(actix_web::Error),
+    Empty,
+    InvalidReaction,
+    Storage(sled::Error),
+    Database(sqlx::Error),
+}
+
+impl From<sled::Error> for MessageError {
+    fn from(err: sled::Error) -> Self {
+        MessageError::Storage(err)
+    }
+}
+
+impl From<sqlx::Error> for MessageError {
+    fn from(err: sqlx::Error) -> Self {
+        MessageError::Database(err)
+    }
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageError::NotFound => write!(f, "Message not found."),
+            MessageError::Forbidden => write!(f, "You are not allowed to change this message."),
+            MessageError::Archived => write!(f, "This channel is archived and read-only."),
+            MessageError::Muted => write!(f, "You are muted in this channel."),
+            MessageError::Denied(err) => write!(f, "{}", err),
+            MessageError::Empty => write!(f, "Message is empty."),
+            MessageError::InvalidReaction => write!(f, "Invalid reaction."),
+            MessageError::Storage(_) | MessageError::Database(_) => write!(f, "Failed to update message."),
+        }
+    }
+}
+
+impl MessageError {
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            MessageError::NotFound => HttpResponse::NotFound().json(self.to_string()),
+            MessageError::Forbidden | MessageError::Archived | MessageError::Muted => HttpResponse::Forbidden().json(self.to_string()),
+            MessageError::Denied(err) => err.error_response(),
+            MessageError::Empty | MessageError::InvalidReaction => HttpResponse::BadRequest().json(self.to_string()),
+            MessageError::Storage(err) => {
+                println!("Error updating message: {:?}", err);
+                HttpResponse::InternalServerError().json(self.to_string())
+            }
+            MessageError::Database(err) => {
+                println!("Error querying database: {:?}", err);
+                HttpResponse::InternalServerError().json(self.to_string())
+            }
+        }
+    }
+}
+
+/// Refuse changes to the messages of an archived channel, and changes by users muted in it
+async fn ensure_writable(sqlite_db: &Pool<Sqlite>, channel_name: &str, username: &str) -> Result<(), MessageError> {
+    if is_archived(sqlite_db, channel_name).await? {
+        return Err(MessageError::Archived);
+    }
+    if is_muted(sqlite_db, channel_name, username).await? {
+        return Err(MessageError::Muted);
+    }
+    Ok(())
+}
+
+/// Find the thread a reply to `parent_id` belongs to. Replying to a reply
+/// continues the parent's thread, so threads are only one level deep.
+pub fn resolve_thread_root(sled_db: &sled::Db, channel: &ChannelRef, parent_id: &str) -> Result<String, MessageError> {
+    let parent = get_chat_message_sled(sled_db, &channel.key, parent_id)?.ok_or(MessageError::NotFound)?;
+    Ok(parent.reply_to.unwrap_or(parent.id))
+}
+
+/// Edit a message on behalf of its author and broadcast the new body to the channel
+pub async fn edit_message(
+    sqlite_db: &Pool<Sqlite>,
+    sled_db: &sled::Db,
+    state: &ChatState,
+    channel: &ChannelRef,
+    (user_id, username): (u32, &str),
+    message_id: &str,
+    message: &str,
+) -> Result<ChatMessage, MessageError> {
+    let message = message.trim();
+    if message.is_empty() {
+        return Err(MessageError::Empty);
+    }
+    ensure_writable(sqlite_db, &channel.name, username).await?;
+
+    let original = get_chat_message_sled(sled_db, &channel.key, message_id)?.ok_or(MessageError::NotFound)?;
+    if original.author_id != Some(user_id) {
+        return Err(MessageError::Forbidden);
+    }
+
+    let edited = edit_chat_message_sled(sled_db, &channel.key, message_id, message)?.ok_or(MessageError::NotFound)?;
+    if let Err(e) = index_message(sqlite_db, &channel.key, &edited).await {
+        println!("Failed to index edited message {}: {}", edited.id, e);
+    }
+    state.broadcast(&channel.name, &ServerEvent::Edited(edited.clone()));
+    Ok(edited)
+}
+
+/// Delete a message on behalf of `username`, who must be its author or allowed to delete
+/// other users' messages, and tell the channel about it
+pub async fn delete_message(
+    sqlite_db: &Pool<Sqlite>,
+    sled_db: &sled::Db,
+    state: &ChatState,
+    channel: &ChannelRef,
+    (user_id, username): (u32, &str),
+    message_id: &str,
+) -> Result<(), MessageError> {
+    ensure_writable(sqlite_db, &channel.name, username).await?;
+    let original = get_chat_message_sled(sled_db, &channel.key, message_id)?.ok_or(MessageError::NotFound)?;
+
+    if original.author_id != Some(user_id) {
+        authorize(sqlite_db, &channel.name, username, Permission::DeleteMessages, Some(&original.username))
+            .await
+            .map_err(MessageError::Denied)?;
+    }
+
+    let deleted = delete_chat_message_sled(sled_db, &channel.key, message_id, username)?.ok_or(MessageError::NotFound)?;
+    if let Err(e) = unindex_message(sqlite_db, &deleted.id).await {
+        println!("Failed to remove deleted message {} from the search index: {}", deleted.id, e);
+    }
+    state.broadcast(&channel.name, &ServerEvent::Deleted {
+        id: deleted.id,
+        deleted_by: username.to_string(),
+        reply_to: deleted.reply_to,
+    });
+    Ok(())
+}
+
+/// Add or remove `username`'s reaction to a message and broadcast the message's reactions
+#[allow(clippy::too_many_arguments)]
+pub async fn react_to_message(
+    sqlite_db: &Pool<Sqlite>,
+    sled_db: &sled::Db,
+    state: &ChatState,
+    channel: &ChannelRef,
+    username: &str,
+    message_id: &str,
+    emoji: &str,
+    add: bool,
+) -> Result<(), MessageError> {
+    let emoji = emoji.trim();
+    if emoji.is_empty() || emoji.len() > MAX_REACTION_LEN || emoji.contains(':') || emoji.contains(char::is_whitespace) {
+        return Err(MessageError::InvalidReaction);
+    }
+    ensure_writable(sqlite_db, &channel.name, username).await?;
+
+    let reactions = set_reaction_sled(sled_db, &channel.key, message_id, emoji, username, add)?
+        .ok_or(MessageError::NotFound)?;
+    state.broadcast(&channel.name, &ServerEvent::Reactions {
+        id: message_id.to_string(),
+        reactions,
+    });
+    Ok(())
+}
+
+pub async fn message_edit(
+    db: web::Data<Pool<Sqlite>>,
+    sled_db: web::Data<sled::Db>,
+    state: web::Data<Arc<ChatState>>,
+    req: HttpRequest,
+    info: web::Path<ChannelPath>,
+    form: web::Json<EditRequest>,
+) -> impl Responder {
+    let (user_id, username) = match check_auth(&req) {
+        Ok(user) => user,
+        Err(_) => return HttpResponse::Unauthorized().json("User not logged in."),
+    };
+    if let Err(err) = check_channel_access(&db, &req, &info.name).await {
+        return err.error_response();
+    }
+    let channel = match resolve_channel(&db, &info.name).await {
+        Ok(channel) => channel,
+        Err(err) => return err.error_response(),
+    };
+
+    match edit_message(&db, &sled_db, &state, &channel, (user_id, &username), &form.id, &form.message).await {
+        Ok(edited) => HttpResponse::Ok().json(edited),
+        Err(err) => err.error_response(),
+    }
+}
+
+pub async fn message_delete(
+    db: web::Data<Pool<Sqlite>>,
+    sled_db: web::Data<sled::Db>,
+    state: web::Data<Arc<ChatState>>,
+    req: HttpRequest,
+    info: web::Path<ChannelPath>,
+    form: web::Json<DeleteRequest>,
+) -> impl Responder {
+    let (user_id, username) = match check_auth(&req) {
+        Ok(user) => user,
+        Err(_) => return HttpResponse::Unauthorized().json("User not logged in."),
+    };
+    if let Err(err) = check_channel_access(&db, &req, &info.name).await {
+        return err.error_response();
+    }
+
+    let channel = match resolve_channel(&db, &info.name).await {
+        Ok(channel) => channel,
+        Err(err) => return err.error_response(),
+    };
+
+    match delete_message(&db, &sled_db, &state, &channel, (user_id, &username), &form.id).await {
+        Ok(()) => HttpResponse::Ok().json("Message deleted."),
+        Err(err) => err.error_response(),
+    }
+}
+
+pub async fn message_revisions(
+    db: web::Data<Pool<Sqlite>>,
+    sled_db: web::Data<sled::Db>,
+    req: HttpRequest,
+    info: web::Path<MessagePath>,
+) -> impl Responder {
+    if check_auth(&req).is_err() {
+        return HttpResponse::Unauthorized().json("User not logged in.");
+    }
+    if let Err(err) = check_channel_access(&db, &req, &info.name).await {
+        return err.error_response();
+    }
+
+    let channel = match resolve_channel(&db, &info.name).await {
+        Ok(channel) => channel,
+        Err(err) => return err.error_response(),
+    };
+
+    match get_message_revisions_sled(&sled_db, &channel.key, &info.id) {
+        Ok(revisions) => HttpResponse::Ok().json(revisions),
+        Err(err) => MessageError::Storage(err).error_response(),
+    }
+}
+
+pub async fn message_thread(
+    db: web::Data<Pool<Sqlite>>,
+    sled_db: web::Data<sled::Db>,
+    req: HttpRequest,
+    info: web::Path<MessagePath>,
+) -> impl Responder {
+    if check_auth(&req).is_err() {
+        return HttpResponse::Unauthorized().json("User not logged in.");
+    }
+    if let Err(err) = check_channel_access(&db, &req, &info.name).await {
+        return err.error_response();
+    }
+
+    let channel = match resolve_channel(&db, &info.name).await {
+        Ok(channel) => channel,
+        Err(err) => return err.error_response(),
+    };
+
+    let parent = match get_chat_message_sled(&sled_db, &channel.key, &info.id) {
+        Ok(Some(parent)) => parent,
+        Ok(None) => return MessageError::NotFound.error_response(),
+        Err(err) => return MessageError::Storage(err).error_response(),
+    };
+
+    match get_thread_sled(&sled_db, &channel.key, &parent.id) {
+        Ok(replies) => HttpResponse::Ok().json(Thread { parent, replies }),
+        Err(err) => MessageError::Storage(err).error_response(),
+    }
+}
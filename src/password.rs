@@ -0,0 +1,64 @@
+use actix_web::error::{BlockingError, ErrorInternalServerError};
+use actix_web::{web, Error};
+use argon2::{Algorithm, Params, PasswordHash, PasswordHasher, PasswordVerifier};
+use password_hash::rand_core::OsRng;
+use password_hash::SaltString;
+use pwhash::bcrypt;
+use crate::config::PasswordConfig;
+
+/// The result of checking a password against a stored hash
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    Invalid,
+    Valid,
+    /// The password is right, but the hash is bcrypt or was made with other
+    /// Argon2 parameters, so it should be replaced with `hash_password`
+    ValidNeedsRehash,
+}
+
+/// Hash a password with Argon2id, in PHC string format. Argon2 is slow on purpose,
+/// so it runs on the blocking thread pool instead of holding up other requests.
+pub async fn hash_password(config: &PasswordConfig, password: &str) -> Result<String, Error> {
+    let (config, password) = (config.clone(), password.to_string());
+    web::block(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        config.argon2().hash_password(password.as_bytes(), &salt).map(|hash| hash.to_string())
+    })
+    .await?
+    .map_err(ErrorInternalServerError)
+}
+
+/// `check_password` on the blocking thread pool
+pub async fn verify_password(config: &PasswordConfig, password: &str, hash: &str) -> Result<Verification, BlockingError> {
+    let (config, password, hash) = (config.clone(), password.to_string(), hash.to_string());
+    web::block(move || check_password(&config, &password, &hash)).await
+}
+
+/// Check a password against an Argon2 or legacy bcrypt hash. Anything else, like
+/// the `!` of imported users without a password, never matches.
+fn check_password(config: &PasswordConfig, password: &str, hash: &str) -> Verification {
+    if hash.starts_with("$2") {
+        return match bcrypt::verify(password, hash) {
+            true => Verification::ValidNeedsRehash,
+            false => Verification::Invalid,
+        };
+    }
+
+    let parsed = match PasswordHash::new(hash) {
+        Ok(parsed) => parsed,
+        Err(_) => return Verification::Invalid,
+    };
+    if config.argon2().verify_password(password.as_bytes(), &parsed).is_err() {
+        return Verification::Invalid;
+    }
+    let current = parsed.algorithm == Algorithm::Argon2id.ident()
+        && Params::try_from(&parsed).is_ok_and(|params| {
+            params.m_cost() == config.params.m_cost()
+                && params.t_cost() == config.params.t_cost()
+                && params.p_cost() == config.params.p_cost()
+        });
+    match current {
+        true => Verification::Valid,
+        false => Verification::ValidNeedsRehash,
+    }
+}
@@ -0,0 +1,125 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use crate::channel::{ChannelInfo, ChatMessage};
+use crate::user::UserStatus;
+
+/// Version of the WebSocket protocol. Bump it whenever an event changes incompatibly.
+/// The Yew frontend mirrors these types in `frontend/src/lib.rs`.
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// Every WebSocket frame is a JSON object carrying the protocol version
+/// next to the event tag, e.g. `{"v":1,"type":"message","message":"hi"}`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Envelope<T> {
+    pub v: u8,
+    #[serde(flatten)]
+    pub event: T,
+}
+
+impl<T> Envelope<T> {
+    pub fn new(event: T) -> Self {
+        Self { v: PROTOCOL_VERSION, event }
+    }
+}
+
+/// Events sent by the server to connected clients
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent {
+    /// A stored message, with its server-assigned ID and timestamp
+    Message(ChatMessage),
+    /// A message whose body was edited by its author
+    Edited(ChatMessage),
+    /// A message that was deleted by its author or a moderator
+    Deleted {
+        id: String,
+        deleted_by: String,
+        reply_to: Option<String>,
+    },
+    /// The reactions of a message after someone added or removed one
+    Reactions {
+        id: String,
+        reactions: BTreeMap<String, Vec<String>>,
+    },
+    /// The channel was renamed, archived or got a new topic
+    ChannelUpdated(ChannelInfo),
+    /// The channel was deleted; the connection is closed right after this event
+    ChannelDeleted {
+        name: String,
+    },
+    Join {
+        username: String,
+    },
+    Leave {
+        username: String,
+    },
+    Presence {
+        users: Vec<UserStatus>,
+    },
+    Typing {
+        username: String,
+    },
+    Ack {
+        client_id: Option<String>,
+        id: String,
+        timestamp: String,
+    },
+    Error {
+        message: String,
+    },
+    /// Answer to a client's `ping`
+    Pong,
+}
+
+/// Events sent by clients to the server
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientEvent {
+    Message {
+        message: String,
+        #[serde(default)]
+        client_id: Option<String>,
+        /// Post the message as a reply in the thread of this message ID
+        #[serde(default)]
+        reply_to: Option<String>,
+    },
+    Edit {
+        id: String,
+        message: String,
+    },
+    Delete {
+        id: String,
+    },
+    AddReaction {
+        id: String,
+        emoji: String,
+    },
+    RemoveReaction {
+        id: String,
+        emoji: String,
+    },
+    /// Moderation commands, checked against the sender's channel role
+    Kick {
+        username: String,
+    },
+    Mute {
+        username: String,
+        /// `false` to unmute
+        #[serde(default = "default_muted")]
+        muted: bool,
+    },
+    Typing,
+    /// Application-level keepalive, answered with `pong`. Nothing is stored.
+    Ping,
+}
+
+fn default_muted() -> bool {
+    true
+}
+
+impl ServerEvent {
+    /// Serialize the event into a versioned JSON frame
+    pub fn to_frame(&self) -> String {
+        serde_json::to_string(&Envelope::new(self)).unwrap_or_default()
+    }
+}
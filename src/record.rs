@@ -0,0 +1,76 @@
+use serde::{Deserialize, Serialize};
+
+/// Version of the message record layout, stored as the first byte of every message value.
+/// Bump it and keep decoding the older layouts whenever the record changes.
+pub const RECORD_VERSION: u8 = 1;
+
+/// What a stored message is
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    Text,
+}
+
+/// A file attached to a message
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Attachment {
+    pub name: String,
+    pub url: String,
+}
+
+/// A chat message as stored in sled
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MessageRecord {
+    pub id: String,
+    pub timestamp: String,
+    /// `Users.id` of the author, unknown for migrated messages of deleted users
+    pub author_id: Option<u32>,
+    pub author: String,
+    pub body: String,
+    pub kind: MessageKind,
+    /// Time of the latest edit
+    pub edited_at: Option<String>,
+    /// Who deleted the message. Deleted messages keep their record without a body.
+    pub deleted_by: Option<String>,
+    pub attachments: Vec<Attachment>,
+    /// ID of the thread's parent message
+    pub reply_to: Option<String>,
+}
+
+/// Why a stored value could not be decoded
+#[derive(Debug)]
+pub enum RecordError {
+    /// The value is empty or written by a newer version
+    UnknownVersion(Option<u8>),
+    Malformed(bincode::Error),
+}
+
+impl std::fmt::Display for RecordError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RecordError::UnknownVersion(Some(version)) => write!(f, "unknown record version {}", version),
+            RecordError::UnknownVersion(None) => write!(f, "empty record"),
+            RecordError::Malformed(err) => write!(f, "malformed record: {}", err),
+        }
+    }
+}
+
+impl MessageRecord {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![RECORD_VERSION];
+        // Serializing into a Vec only fails for types bincode can't represent
+        bincode::serialize_into(&mut bytes, self).expect("Failed to encode message record");
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, RecordError> {
+        match bytes.split_first() {
+            Some((&RECORD_VERSION, record)) => bincode::deserialize(record).map_err(RecordError::Malformed),
+            Some((&version, _)) => Err(RecordError::UnknownVersion(Some(version))),
+            None => Err(RecordError::UnknownVersion(None)),
+        }
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        self.deleted_by.is_some()
+    }
+}
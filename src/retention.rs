@@ -0,0 +1,80 @@
+use chrono::{TimeDelta, Utc};
+use sqlx::{Pool, Sqlite};
+use crate::database::{channel_storage_key, count_messages_sled, prune_channel_sled};
+use crate::search::unindex_messages;
+
+/// How often channels are pruned
+const PRUNE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// Messages removed at a time, so a large backlog doesn't hold up the server
+const PRUNE_BATCH_SIZE: usize = 500;
+
+/// A channel with retention limits, see `Channel.RetentionDays` and `Channel.RetentionCount`
+#[derive(sqlx::FromRow)]
+struct RetentionPolicy {
+    id: i64,
+    name: String,
+    retention_days: Option<i64>,
+    retention_count: Option<i64>,
+}
+
+/// Start pruning channels with retention limits, now and every `PRUNE_INTERVAL`
+pub fn spawn_retention_task(sqlite_db: Pool<Sqlite>, sled_db: sled::Db) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+            prune_channels(&sqlite_db, &sled_db).await;
+        }
+    });
+}
+
+async fn prune_channels(sqlite_db: &Pool<Sqlite>, sled_db: &sled::Db) {
+    let policies = sqlx::query_as::<_, RetentionPolicy>("SELECT id, Name AS name, RetentionDays AS retention_days, RetentionCount AS retention_count
+        FROM Channel WHERE RetentionDays IS NOT NULL OR RetentionCount IS NOT NULL;")
+        .fetch_all(sqlite_db)
+        .await;
+    let policies = match policies {
+        Ok(policies) => policies,
+        Err(e) => {
+            println!("Failed to load retention policies: {}", e);
+            return;
+        }
+    };
+
+    for policy in policies {
+        match prune_channel(sqlite_db, sled_db, &policy).await {
+            Ok(0) => {}
+            Ok(count) => println!("Retention removed {} messages from channel {}", count, policy.name),
+            Err(e) => println!("Failed to prune channel {}: {}", policy.name, e),
+        }
+    }
+}
+
+/// Remove a channel's messages beyond its limits in batches. Returns how many were removed.
+async fn prune_channel(sqlite_db: &Pool<Sqlite>, sled_db: &sled::Db, policy: &RetentionPolicy) -> Result<usize, sled::Error> {
+    let channel_key = channel_storage_key(policy.id);
+    // Limits stored before they were bounded may not fit in a time, keep those messages by age
+    let older_than = policy.retention_days
+        .and_then(TimeDelta::try_days)
+        .and_then(|age| Utc::now().checked_sub_signed(age));
+    let mut excess = match policy.retention_count {
+        Some(count) => count_messages_sled(sled_db, &channel_key)?.saturating_sub(count.max(0) as usize),
+        None => 0,
+    };
+
+    let mut total = 0;
+    loop {
+        let removed = prune_channel_sled(sled_db, &channel_key, older_than, excess, PRUNE_BATCH_SIZE)?;
+        if removed.is_empty() {
+            return Ok(total);
+        }
+        total += removed.len();
+        excess = excess.saturating_sub(removed.len());
+        if let Err(e) = unindex_messages(sqlite_db, &removed).await {
+            println!("Failed to remove pruned messages from the search index: {}", e);
+        }
+        // Let requests waiting on the databases through between batches
+        tokio::task::yield_now().await;
+    }
+}
@@ -0,0 +1,235 @@
+use actix_web::{web, Responder, HttpRequest, HttpResponse};
+use chrono::{DateTime, Duration, NaiveDate, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, QueryBuilder, Sqlite};
+use std::collections::HashMap;
+use crate::channel::ChatMessage;
+use crate::database::{list_messages_sled, list_storage_keys};
+use crate::member::{accessible_channels, check_channel_access};
+use crate::user::check_auth;
+
+const DEFAULT_SEARCH_LIMIT: usize = 50;
+const MAX_SEARCH_LIMIT: usize = 200;
+
+/// Parameters of `/channel/search`. `from` and `to` are RFC 3339 times or dates;
+/// a date in `to` includes that whole day.
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    q: String,
+    author: Option<String>,
+    channel: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    limit: Option<usize>,
+}
+
+/// A message matching a search, with the channel it was posted in
+#[derive(Serialize, sqlx::FromRow, Debug)]
+pub struct SearchResult {
+    channel: String,
+    id: String,
+    timestamp: String,
+    username: String,
+    message: String,
+}
+
+/// Add a message to the search index, replacing an older version of it
+pub async fn index_message(db: &Pool<Sqlite>, channel_key: &str, message: &ChatMessage) -> Result<(), sqlx::Error> {
+    // Take the write lock up front. Messages are indexed concurrently, and deferred
+    // transactions upgrading to writers would fail with "database is locked".
+    let mut tx = db.begin_with("BEGIN IMMEDIATE").await?;
+    sqlx::query("DELETE FROM MessageSearch WHERE MessageId = ?;")
+        .bind(&message.id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("INSERT INTO MessageSearch (Body, MessageId, ChannelKey, Author, Timestamp) VALUES (?, ?, ?, ?, ?);")
+        .bind(&message.message)
+        .bind(&message.id)
+        .bind(channel_key)
+        .bind(&message.username)
+        .bind(&message.timestamp)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await
+}
+
+pub async fn unindex_message(db: &Pool<Sqlite>, message_id: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM MessageSearch WHERE MessageId = ?;")
+        .bind(message_id)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+pub async fn unindex_messages(db: &Pool<Sqlite>, message_ids: &[String]) -> Result<(), sqlx::Error> {
+    let mut tx = db.begin_with("BEGIN IMMEDIATE").await?;
+    for message_id in message_ids {
+        sqlx::query("DELETE FROM MessageSearch WHERE MessageId = ?;")
+            .bind(message_id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await
+}
+
+pub async fn unindex_channel(db: &Pool<Sqlite>, channel_key: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM MessageSearch WHERE ChannelKey = ?;")
+        .bind(channel_key)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// Index the messages of every channel from scratch. Returns how many were indexed.
+pub async fn rebuild_search_index(db: &Pool<Sqlite>, sled_db: &sled::Db) -> Result<usize, sqlx::Error> {
+    let mut tx = db.begin().await?;
+    sqlx::query("DELETE FROM MessageSearch;").execute(&mut *tx).await?;
+
+    let mut count = 0;
+    for channel_key in list_storage_keys(db).await {
+        let messages = match list_messages_sled(sled_db, &channel_key) {
+            Ok(messages) => messages,
+            Err(e) => {
+                println!("Failed to read messages of channel {}: {}", channel_key, e);
+                continue;
+            }
+        };
+        for message in messages {
+            sqlx::query("INSERT INTO MessageSearch (Body, MessageId, ChannelKey, Author, Timestamp) VALUES (?, ?, ?, ?, ?);")
+                .bind(&message.message)
+                .bind(&message.id)
+                .bind(&channel_key)
+                .bind(&message.username)
+                .bind(&message.timestamp)
+                .execute(&mut *tx)
+                .await?;
+            count += 1;
+        }
+    }
+
+    tx.commit().await?;
+    Ok(count)
+}
+
+/// Build the index once for messages stored before search existed
+pub async fn build_search_index(db: &Pool<Sqlite>, sled_db: &sled::Db) {
+    let meta = sled_db.open_tree("__meta").expect("Failed to open meta tree");
+    if let Ok(Some(_)) = meta.get("search_indexed") {
+        return;
+    }
+
+    match rebuild_search_index(db, sled_db).await {
+        Ok(count) => println!("Indexed {} messages for search", count),
+        Err(e) => panic!("Failed to build the search index: {}", e),
+    }
+    meta.insert("search_indexed", "1").expect("Failed to update meta tree");
+}
+
+/// Turn user input into an FTS5 query matching messages that contain every word,
+/// so operators and quotes in the input are searched for instead of interpreted
+fn match_expression(query: &str) -> String {
+    query
+        .split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Parse a `from` or `to` filter. Dates start at midnight UTC, or end there with `end_of_day`.
+pub fn parse_time_filter(value: &str, end_of_day: bool) -> Option<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Some(time.with_timezone(&Utc));
+    }
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()?;
+    let date = if end_of_day { date + Duration::days(1) } else { date };
+    Some(date.and_hms_opt(0, 0, 0)?.and_utc())
+}
+
+/// Format a time filter like stored timestamps, so they compare as strings
+fn format_time_filter(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// Search the messages of every channel the user may access, best matches first
+pub async fn channel_search(
+    db: web::Data<Pool<Sqlite>>,
+    req: HttpRequest,
+    query: web::Query<SearchQuery>,
+) -> impl Responder {
+    let (_user_id, username) = match check_auth(&req) {
+        Ok(user) => user,
+        Err(_) => return HttpResponse::Unauthorized().json("User not logged in."),
+    };
+
+    let expression = match_expression(&query.q);
+    if expression.is_empty() {
+        return HttpResponse::BadRequest().json("Search query is empty.");
+    }
+    let from = match query.from.as_deref().map(|from| parse_time_filter(from, false).map(format_time_filter)) {
+        Some(None) => return HttpResponse::BadRequest().json("Invalid `from` time."),
+        Some(from) => from,
+        None => None,
+    };
+    let to = match query.to.as_deref().map(|to| parse_time_filter(to, true).map(format_time_filter)) {
+        Some(None) => return HttpResponse::BadRequest().json("Invalid `to` time."),
+        Some(to) => to,
+        None => None,
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_SEARCH_LIMIT).clamp(1, MAX_SEARCH_LIMIT);
+
+    let mut channels = match accessible_channels(&db, &username).await {
+        Ok(channels) => channels,
+        Err(e) => {
+            println!("Error querying database: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    if let Some(channel_name) = &query.channel {
+        if let Err(err) = check_channel_access(&db, &req, channel_name).await {
+            return err.error_response();
+        }
+        channels.retain(|channel| &channel.name == channel_name);
+    }
+    if channels.is_empty() {
+        return HttpResponse::Ok().json(Vec::<SearchResult>::new());
+    }
+    let names: HashMap<String, String> = channels.into_iter().map(|channel| (channel.key, channel.name)).collect();
+
+    let mut builder = QueryBuilder::<Sqlite>::new(
+        "SELECT ChannelKey AS channel, MessageId AS id, Timestamp AS timestamp, Author AS username, Body AS message
+        FROM MessageSearch WHERE MessageSearch MATCH ",
+    );
+    builder.push_bind(expression);
+    builder.push(" AND ChannelKey IN (");
+    let mut keys = builder.separated(", ");
+    for key in names.keys() {
+        keys.push_bind(key.clone());
+    }
+    builder.push(")");
+    if let Some(author) = &query.author {
+        builder.push(" AND Author = ").push_bind(author.clone());
+    }
+    if let Some(from) = from {
+        builder.push(" AND Timestamp >= ").push_bind(from);
+    }
+    if let Some(to) = to {
+        builder.push(" AND Timestamp < ").push_bind(to);
+    }
+    builder.push(" ORDER BY rank LIMIT ").push_bind(limit as i64);
+
+    match builder.build_query_as::<SearchResult>().fetch_all(db.get_ref()).await {
+        Ok(mut results) => {
+            // Results carry channel names, not storage keys
+            for result in &mut results {
+                if let Some(name) = names.get(&result.channel) {
+                    result.channel = name.clone();
+                }
+            }
+            HttpResponse::Ok().json(results)
+        }
+        Err(e) => {
+            println!("Error searching messages: {}", e);
+            HttpResponse::InternalServerError().json("Failed to search messages.")
+        }
+    }
+}
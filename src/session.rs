@@ -0,0 +1,282 @@
+use actix_session::storage::{LoadError, SaveError, SessionKey, SessionStore, UpdateError};
+use actix_session::Session;
+use actix_web::cookie::time::Duration;
+use actix_web::{web, HttpResponse, Responder};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite};
+use std::collections::HashMap;
+use std::sync::Arc;
+use crate::database::format_timestamp;
+use crate::token::random_secret;
+use crate::user::check_session_auth;
+use crate::websocket::ChatState;
+
+/// How often expired sessions are removed
+const CLEANUP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// `LastSeenAt` is only written when it is older than this, so reads don't turn into writes
+const TOUCH_INTERVAL: Duration = Duration::minutes(1);
+
+/// Sessions kept in the `Sessions` table, so they can be listed and revoked.
+/// The cookie only carries a random key. A session ends when it reaches its
+/// expiry time or goes unused for longer than `idle_timeout`.
+#[derive(Clone)]
+pub struct SqliteSessionStore {
+    db: Pool<Sqlite>,
+    idle_timeout: Duration,
+}
+
+/// A session of the logged in user, as listed by `/user/sessions`
+#[derive(Serialize, sqlx::FromRow)]
+pub struct SessionInfo {
+    id: String,
+    user_agent: Option<String>,
+    created_at: String,
+    last_seen_at: String,
+    #[sqlx(default)]
+    current: bool,
+}
+
+#[derive(Deserialize)]
+pub struct SessionPath {
+    id: String,
+}
+
+/// The fields of a session's state that get their own columns
+struct SessionColumns {
+    user_id: Option<u32>,
+    session_id: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl SessionColumns {
+    /// Session values are stored as JSON, like `Session::insert` serializes them
+    fn from_state(state: &HashMap<String, String>) -> Self {
+        fn value<T: serde::de::DeserializeOwned>(state: &HashMap<String, String>, key: &str) -> Option<T> {
+            state.get(key).and_then(|value| serde_json::from_str(value).ok())
+        }
+        SessionColumns {
+            user_id: value(state, "user_id"),
+            session_id: value(state, "session_id"),
+            user_agent: value(state, "user_agent"),
+        }
+    }
+}
+
+fn expires_at(ttl: &Duration) -> String {
+    format_timestamp(Utc::now() + chrono::Duration::seconds(ttl.whole_seconds()))
+}
+
+#[async_trait::async_trait(?Send)]
+impl SessionStore for SqliteSessionStore {
+    async fn load(&self, session_key: &SessionKey) -> Result<Option<HashMap<String, String>>, LoadError> {
+        let now = Utc::now();
+        let row: Option<(String, String)> = sqlx::query_as("SELECT State, LastSeenAt FROM Sessions
+            WHERE Key = ? AND ExpiresAt > ? AND LastSeenAt > ?;")
+            .bind(session_key.as_ref())
+            .bind(format_timestamp(now))
+            .bind(self.idle_since())
+            .fetch_optional(&self.db)
+            .await
+            .map_err(|e| LoadError::Other(e.into()))?;
+        let (state, last_seen_at) = match row {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+
+        let touch_before = format_timestamp(now - chrono::Duration::seconds(TOUCH_INTERVAL.whole_seconds()));
+        if last_seen_at < touch_before {
+            sqlx::query("UPDATE Sessions SET LastSeenAt = ? WHERE Key = ?;")
+                .bind(format_timestamp(now))
+                .bind(session_key.as_ref())
+                .execute(&self.db)
+                .await
+                .map_err(|e| LoadError::Other(e.into()))?;
+        }
+
+        serde_json::from_str(&state)
+            .map(Some)
+            .map_err(|e| LoadError::Deserialization(e.into()))
+    }
+
+    async fn save(&self, session_state: HashMap<String, String>, ttl: &Duration) -> Result<SessionKey, SaveError> {
+        let state = serde_json::to_string(&session_state).map_err(|e| SaveError::Serialization(e.into()))?;
+        let columns = SessionColumns::from_state(&session_state);
+        let key = random_secret("");
+        let now = format_timestamp(Utc::now());
+
+        sqlx::query("INSERT INTO Sessions (Key, SessionId, UserId, UserAgent, State, CreatedAt, LastSeenAt, ExpiresAt)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?);")
+            .bind(&key)
+            .bind(&columns.session_id)
+            .bind(columns.user_id)
+            .bind(&columns.user_agent)
+            .bind(state)
+            .bind(&now)
+            .bind(&now)
+            .bind(expires_at(ttl))
+            .execute(&self.db)
+            .await
+            .map_err(|e| SaveError::Other(e.into()))?;
+
+        SessionKey::try_from(key).map_err(|e| SaveError::Other(e.into()))
+    }
+
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: HashMap<String, String>,
+        ttl: &Duration,
+    ) -> Result<SessionKey, UpdateError> {
+        let state = serde_json::to_string(&session_state).map_err(|e| UpdateError::Serialization(e.into()))?;
+        let columns = SessionColumns::from_state(&session_state);
+
+        // A session revoked while the request was running stays revoked
+        sqlx::query("UPDATE Sessions SET SessionId = ?, UserId = ?, UserAgent = ?, State = ?, LastSeenAt = ?, ExpiresAt = ?
+            WHERE Key = ?;")
+            .bind(&columns.session_id)
+            .bind(columns.user_id)
+            .bind(&columns.user_agent)
+            .bind(state)
+            .bind(format_timestamp(Utc::now()))
+            .bind(expires_at(ttl))
+            .bind(session_key.as_ref())
+            .execute(&self.db)
+            .await
+            .map_err(|e| UpdateError::Other(e.into()))?;
+
+        Ok(session_key)
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> Result<(), anyhow::Error> {
+        sqlx::query("DELETE FROM Sessions WHERE Key = ?;")
+            .bind(session_key.as_ref())
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+}
+
+impl SqliteSessionStore {
+    pub fn new(db: Pool<Sqlite>, idle_timeout: Duration) -> Self {
+        SqliteSessionStore { db, idle_timeout }
+    }
+
+    /// Sessions not used since this time have expired
+    fn idle_since(&self) -> String {
+        format_timestamp(Utc::now() - chrono::Duration::seconds(self.idle_timeout.whole_seconds()))
+    }
+
+    /// A user's sessions that are still valid, most recently used first
+    pub async fn user_sessions(&self, user_id: u32) -> Result<Vec<SessionInfo>, sqlx::Error> {
+        sqlx::query_as::<_, SessionInfo>("SELECT SessionId AS id, UserAgent AS user_agent,
+            CreatedAt AS created_at, LastSeenAt AS last_seen_at FROM Sessions
+            WHERE UserId = ? AND SessionId IS NOT NULL AND ExpiresAt > ? AND LastSeenAt > ?
+            ORDER BY LastSeenAt DESC;")
+            .bind(user_id)
+            .bind(format_timestamp(Utc::now()))
+            .bind(self.idle_since())
+            .fetch_all(&self.db)
+            .await
+    }
+
+    /// End one of a user's sessions. Returns whether it existed.
+    pub async fn revoke(&self, user_id: u32, session_id: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM Sessions WHERE UserId = ? AND SessionId = ?;")
+            .bind(user_id)
+            .bind(session_id)
+            .execute(&self.db)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// End every session of a user except `keep`. Returns the IDs of the revoked sessions.
+    pub async fn revoke_all(&self, user_id: u32, keep: Option<&str>) -> Result<Vec<String>, sqlx::Error> {
+        sqlx::query_scalar("DELETE FROM Sessions
+            WHERE UserId = ? AND SessionId IS NOT NULL AND SessionId IS NOT ? RETURNING SessionId;")
+            .bind(user_id)
+            .bind(keep)
+            .fetch_all(&self.db)
+            .await
+    }
+
+    async fn remove_expired(&self) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM Sessions WHERE ExpiresAt <= ? OR LastSeenAt <= ?;")
+            .bind(format_timestamp(Utc::now()))
+            .bind(self.idle_since())
+            .execute(&self.db)
+            .await?;
+        Ok(result.rows_affected())
+    }
+}
+
+/// Start removing expired and idle sessions, now and every `CLEANUP_INTERVAL`
+pub fn spawn_session_cleanup_task(store: SqliteSessionStore) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+        loop {
+            interval.tick().await;
+            match store.remove_expired().await {
+                Ok(0) => {}
+                Ok(count) => println!("Removed {} expired sessions", count),
+                Err(e) => println!("Failed to remove expired sessions: {}", e),
+            }
+        }
+    });
+}
+
+/// The public ID of the current session, set at login
+pub fn current_session_id(session: &Session) -> Option<String> {
+    session.get::<String>("session_id").ok().flatten()
+}
+
+/// List the logged in user's sessions
+pub async fn session_list(store: web::Data<SqliteSessionStore>, session: Session) -> impl Responder {
+    let (user_id, _username) = match check_session_auth(&session) {
+        Ok(user) => user,
+        Err(_) => return HttpResponse::Unauthorized().json("User not logged in."),
+    };
+
+    match store.user_sessions(user_id).await {
+        Ok(mut sessions) => {
+            let current = current_session_id(&session);
+            for info in &mut sessions {
+                info.current = current.as_deref() == Some(info.id.as_str());
+            }
+            HttpResponse::Ok().json(sessions)
+        }
+        Err(e) => {
+            println!("Error querying database: {}", e);
+            HttpResponse::InternalServerError().json("Failed to list sessions.")
+        }
+    }
+}
+
+/// Log one of the user's sessions out, closing its websocket connections
+pub async fn session_revoke(
+    store: web::Data<SqliteSessionStore>,
+    chat_state: web::Data<Arc<ChatState>>,
+    session: Session,
+    info: web::Path<SessionPath>,
+) -> impl Responder {
+    let (user_id, _username) = match check_session_auth(&session) {
+        Ok(user) => user,
+        Err(_) => return HttpResponse::Unauthorized().json("User not logged in."),
+    };
+
+    match store.revoke(user_id, &info.id).await {
+        Ok(true) => {
+            chat_state.end_sessions(std::slice::from_ref(&info.id), "Your session has been revoked.");
+            if current_session_id(&session).as_deref() == Some(info.id.as_str()) {
+                session.purge();
+            }
+            HttpResponse::Ok().json("Session revoked.")
+        }
+        Ok(false) => HttpResponse::NotFound().json("Session not found."),
+        Err(e) => {
+            println!("Error revoking session: {}", e);
+            HttpResponse::InternalServerError().json("Failed to revoke session.")
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use actix_session::Session;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::{ErrorInternalServerError, ErrorUnauthorized};
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, HttpResponse, Responder};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Sqlite};
+use std::sync::Arc;
+use uuid::Uuid;
+use crate::config::JwtConfig;
+use crate::database::format_timestamp;
+use crate::jwt::{authenticate_access_token, AccessClaims};
+use crate::user::check_session_auth;
+use crate::websocket::ChatState;
+
+/// Tokens start with this, so they are easy to recognize, e.g. by secret scanners
+const TOKEN_PREFIX: &str = "chat_";
+
+/// Longest token name accepted
+const MAX_TOKEN_NAME_LEN: usize = 100;
+
+/// The user a Bearer token belongs to, stored in the request extensions by `token_auth`
+#[derive(Clone)]
+pub struct TokenUser {
+    pub user_id: u32,
+    pub username: String,
+    pub credential: Credential,
+}
+
+/// The kind of Bearer token a request was made with
+#[derive(Clone)]
+pub enum Credential {
+    /// A personal API token, by ID
+    ApiToken(i64),
+    /// A JWT access token
+    Jwt(AccessClaims),
+}
+
+/// An API token as listed by `/user/tokens`. The token itself is only shown once.
+#[derive(Serialize, sqlx::FromRow)]
+pub struct ApiToken {
+    id: i64,
+    name: String,
+    created_at: String,
+    last_used_at: Option<String>,
+}
+
+/// A new token, with the secret the client authenticates with
+#[derive(Serialize)]
+pub struct CreatedToken {
+    id: i64,
+    name: String,
+    token: String,
+    created_at: String,
+}
+
+#[derive(Deserialize)]
+pub struct TokenRequest {
+    name: String,
+}
+
+#[derive(Deserialize)]
+pub struct TokenPath {
+    id: i64,
+}
+
+/// `prefix` followed by 244 random bits, from two v4 UUIDs
+pub fn random_secret(prefix: &str) -> String {
+    format!("{}{}{}", prefix, Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+/// Tokens are stored as their SHA-256, so a leaked database doesn't leak working tokens.
+/// They are random enough that a slow password hash isn't needed.
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The user an API token belongs to, or `None` if it doesn't exist or was revoked
+async fn authenticate_token(db: &Pool<Sqlite>, token: &str) -> Result<Option<TokenUser>, sqlx::Error> {
+    let row: Option<(i64, u32, String)> = sqlx::query_as("SELECT t.id, t.UserId, u.Username FROM ApiTokens t
+        JOIN Users u ON u.id = t.UserId
+        WHERE t.TokenHash = ?;")
+        .bind(hash_token(token))
+        .fetch_optional(db)
+        .await?;
+    let (token_id, user_id, username) = match row {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+
+    sqlx::query("UPDATE ApiTokens SET LastUsedAt = ? WHERE id = ?;")
+        .bind(format_timestamp(Utc::now()))
+        .bind(token_id)
+        .execute(db)
+        .await?;
+    Ok(Some(TokenUser { user_id, username, credential: Credential::ApiToken(token_id) }))
+}
+
+/// The user a Bearer token belongs to. API tokens are recognized by their prefix, anything else must be a JWT.
+pub async fn authenticate_bearer(db: &Pool<Sqlite>, jwt_config: &JwtConfig, token: &str) -> Result<Option<TokenUser>, sqlx::Error> {
+    if token.starts_with(TOKEN_PREFIX) {
+        authenticate_token(db, token).await
+    } else {
+        authenticate_access_token(db, jwt_config, token).await
+    }
+}
+
+/// Authenticate requests with an API token or JWT access token in an `Authorization: Bearer`
+/// header. Requests with an invalid token are rejected instead of falling back to the session.
+pub async fn token_auth(req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let token = req.headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string());
+    let token = match token {
+        Some(token) => token,
+        None => return next.call(req).await,
+    };
+
+    let (db, jwt_config) = match (req.app_data::<web::Data<Pool<Sqlite>>>(), req.app_data::<web::Data<JwtConfig>>()) {
+        (Some(db), Some(jwt_config)) => (db.clone(), jwt_config.clone()),
+        _ => return Err(ErrorInternalServerError("Database not available")),
+    };
+    match authenticate_bearer(&db, &jwt_config, &token).await {
+        Ok(Some(user)) => {
+            req.extensions_mut().insert(user);
+            next.call(req).await
+        }
+        Ok(None) => Err(ErrorUnauthorized("Invalid or expired token.")),
+        Err(e) => {
+            println!("Error checking API token: {}", e);
+            Err(ErrorInternalServerError("Failed to check API token."))
+        }
+    }
+}
+
+/// Create a token for the logged in user. Tokens can't create tokens, so this needs a session.
+pub async fn token_create(db: web::Data<Pool<Sqlite>>, session: Session, form: web::Json<TokenRequest>) -> impl Responder {
+    let (user_id, _username) = match check_session_auth(&session) {
+        Ok(user) => user,
+        Err(_) => return HttpResponse::Unauthorized().json("User not logged in."),
+    };
+    let name = form.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_TOKEN_NAME_LEN {
+        return HttpResponse::BadRequest().json(format!("Token names must be 1 to {} characters long.", MAX_TOKEN_NAME_LEN));
+    }
+
+    let token = random_secret(TOKEN_PREFIX);
+    let created_at = format_timestamp(Utc::now());
+    let result = sqlx::query("INSERT INTO ApiTokens (UserId, Name, TokenHash, CreatedAt) VALUES (?, ?, ?, ?);")
+        .bind(user_id)
+        .bind(name)
+        .bind(hash_token(&token))
+        .bind(&created_at)
+        .execute(db.get_ref())
+        .await;
+
+    match result {
+        Ok(result) => HttpResponse::Ok().json(CreatedToken {
+            id: result.last_insert_rowid(),
+            name: name.to_string(),
+            token,
+            created_at,
+        }),
+        Err(e) => {
+            println!("Error creating API token: {}", e);
+            HttpResponse::InternalServerError().json("Failed to create token.")
+        }
+    }
+}
+
+/// List the logged in user's tokens, newest first
+pub async fn token_list(db: web::Data<Pool<Sqlite>>, session: Session) -> impl Responder {
+    let (user_id, _username) = match check_session_auth(&session) {
+        Ok(user) => user,
+        Err(_) => return HttpResponse::Unauthorized().json("User not logged in."),
+    };
+
+    let tokens = sqlx::query_as::<_, ApiToken>("SELECT id, Name AS name, CreatedAt AS created_at, LastUsedAt AS last_used_at
+        FROM ApiTokens WHERE UserId = ? ORDER BY id DESC;")
+        .bind(user_id)
+        .fetch_all(db.get_ref())
+        .await;
+
+    match tokens {
+        Ok(tokens) => HttpResponse::Ok().json(tokens),
+        Err(e) => {
+            println!("Error querying database: {}", e);
+            HttpResponse::InternalServerError().json("Failed to list tokens.")
+        }
+    }
+}
+
+/// Delete one of the user's tokens, closing the websocket connections opened with it
+pub async fn token_revoke(
+    db: web::Data<Pool<Sqlite>>,
+    chat_state: web::Data<Arc<ChatState>>,
+    session: Session,
+    info: web::Path<TokenPath>,
+) -> impl Responder {
+    let (user_id, _username) = match check_session_auth(&session) {
+        Ok(user) => user,
+        Err(_) => return HttpResponse::Unauthorized().json("User not logged in."),
+    };
+
+    let result = sqlx::query("DELETE FROM ApiTokens WHERE id = ? AND UserId = ?;")
+        .bind(info.id)
+        .bind(user_id)
+        .execute(db.get_ref())
+        .await;
+
+    match result {
+        Ok(result) if result.rows_affected() > 0 => {
+            chat_state.end_token(info.id, "This API token has been revoked.");
+            HttpResponse::Ok().json("Token revoked.")
+        }
+        Ok(_) => HttpResponse::NotFound().json("Token not found."),
+        Err(e) => {
+            println!("Error revoking API token: {}", e);
+            HttpResponse::InternalServerError().json("Failed to revoke token.")
+        }
+    }
+}
@@ -1,10 +1,19 @@
-use actix_web::{web, Responder, HttpResponse, Error};
+use actix_web::{web, HttpMessage, Responder, HttpRequest, HttpResponse, Error};
 use sqlx::{Pool, Sqlite};
-use actix_session::Session;
+use actix_session::{Session, SessionExt};
 use serde::{Deserialize, Serialize};
-use actix_web::error::ErrorUnauthorized;
-use pwhash::bcrypt;
+use actix_web::error::{ErrorForbidden, ErrorInternalServerError, ErrorNotFound, ErrorUnauthorized};
 use crate::database::get_user_status_sled;
+use crate::member::check_channel_access;
+use crate::channel::resolve_channel;
+use crate::session::{current_session_id, SqliteSessionStore};
+use crate::config::{JwtConfig, PasswordConfig};
+use crate::jwt::{issue_tokens, revoke_access_token, revoke_user_tokens};
+use crate::password::{hash_password, verify_password, Verification};
+use crate::token::{Credential, TokenUser};
+use crate::websocket::ChatState;
+use std::sync::Arc;
+use uuid::Uuid;
 
 #[derive(Deserialize)]
 pub struct RegisterRequest {
@@ -16,6 +25,24 @@ pub struct RegisterRequest {
 pub struct LoginRequest {
     username: String,
     password: String,
+    #[serde(default)]
+    mode: LoginMode,
+}
+
+/// How a login is kept: in the session cookie, or as JWT access and refresh tokens
+/// for clients that don't use cookies
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LoginMode {
+    #[default]
+    Cookie,
+    Jwt,
+}
+
+#[derive(Deserialize)]
+pub struct PasswordRequest {
+    current_password: String,
+    new_password: String,
 }
 
 #[derive(Deserialize)]
@@ -23,14 +50,23 @@ pub struct StatusRequest {
     name: String,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct UserStatus {
     pub username: String,
     pub status: String,
     pub timestamp: String,
 }
 
-pub fn check_auth(session: &Session) -> Result<(u32, String), Error> {
+/// The user making the request, from an API token or else the session
+pub fn check_auth(req: &HttpRequest) -> Result<(u32, String), Error> {
+    if let Some(user) = req.extensions().get::<TokenUser>() {
+        return Ok((user.user_id, user.username.clone()));
+    }
+    check_session_auth(&req.get_session())
+}
+
+/// The user logged in with the session. Managing the account needs this, API tokens aren't enough.
+pub fn check_session_auth(session: &Session) -> Result<(u32, String), Error> {
     let user_id = match session.get::<u32>("user_id")? {
         Some(id) => id,
         None => return Err(ErrorUnauthorized("User ID not found in session")),
@@ -44,8 +80,154 @@ pub fn check_auth(session: &Session) -> Result<(u32, String), Error> {
     Ok((user_id, user_username))
 }
 
-pub async fn register(db: web::Data<Pool<Sqlite>>, form: web::Json<RegisterRequest>) -> impl Responder {
-    let hashed_password: String = bcrypt::hash(&form.password).unwrap();
+/// A user's role in a channel. Roles are ordered, so `Owner > Admin > Moderator > Member`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Member,
+    Moderator,
+    Admin,
+    Owner,
+}
+
+/// Actions that need more than plain membership of a channel
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    /// Delete messages written by other users
+    DeleteMessages,
+    Kick,
+    Mute,
+    Invite,
+    ChangeTopic,
+    Rename,
+    Archive,
+    ManageRoles,
+    DeleteChannel,
+    /// Change how long messages are kept
+    SetRetention,
+}
+
+impl Permission {
+    pub const ALL: [Permission; 10] = [
+        Permission::DeleteMessages,
+        Permission::Kick,
+        Permission::Mute,
+        Permission::Invite,
+        Permission::ChangeTopic,
+        Permission::Rename,
+        Permission::Archive,
+        Permission::ManageRoles,
+        Permission::DeleteChannel,
+        Permission::SetRetention,
+    ];
+}
+
+impl Role {
+    /// The permission matrix:
+    ///
+    /// | permission      | member | moderator | admin | owner |
+    /// |-----------------|--------|-----------|-------|-------|
+    /// | delete messages |        | x         | x     | x     |
+    /// | kick, mute      |        | x         | x     | x     |
+    /// | invite          |        |           | x     | x     |
+    /// | change topic    |        |           | x     | x     |
+    /// | rename, archive |        |           |       | x     |
+    /// | manage roles    |        |           |       | x     |
+    /// | delete channel  |        |           |       | x     |
+    /// | set retention   |        |           |       | x     |
+    pub fn can(self, permission: Permission) -> bool {
+        let required = match permission {
+            Permission::DeleteMessages | Permission::Kick | Permission::Mute => Role::Moderator,
+            Permission::Invite | Permission::ChangeTopic => Role::Admin,
+            Permission::Rename | Permission::Archive | Permission::ManageRoles | Permission::DeleteChannel
+                | Permission::SetRetention => Role::Owner,
+        };
+        self >= required
+    }
+
+    /// Name of the role as stored in `ChannelRoles.Role`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Member => "member",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+            Role::Owner => "owner",
+        }
+    }
+
+    pub fn permissions(self) -> Vec<Permission> {
+        Permission::ALL.into_iter().filter(|permission| self.can(*permission)).collect()
+    }
+}
+
+/// Get a user's role in a channel, or `None` if the channel does not exist.
+/// The channel's `Owner` is always its owner; everyone without a stored role is a member.
+pub async fn channel_role(db: &Pool<Sqlite>, channel_name: &str, username: &str) -> Result<Option<Role>, sqlx::Error> {
+    let row: Option<(String, Option<String>)> = sqlx::query_as("SELECT c.Owner, r.Role FROM Channel c
+        LEFT JOIN ChannelRoles r ON r.ChannelId = c.id AND r.Username = ?
+        WHERE c.Name = ?;")
+        .bind(username)
+        .bind(channel_name)
+        .fetch_optional(db)
+        .await?;
+
+    Ok(row.map(|(owner, role)| {
+        if owner == username {
+            return Role::Owner;
+        }
+        match role.as_deref() {
+            Some("admin") => Role::Admin,
+            Some("moderator") => Role::Moderator,
+            _ => Role::Member,
+        }
+    }))
+}
+
+/// Check that `username` holds `permission` in a channel. When the action targets
+/// another user, the acting user must also outrank them. Returns the acting user's role.
+pub async fn authorize(
+    db: &Pool<Sqlite>,
+    channel_name: &str,
+    username: &str,
+    permission: Permission,
+    target: Option<&str>,
+) -> Result<Role, Error> {
+    let lookup = |username| async move {
+        channel_role(db, channel_name, username).await.map_err(|e| {
+            println!("Error querying database: {}", e);
+            ErrorInternalServerError("Failed to check permissions.")
+        })
+    };
+
+    let role = match lookup(username).await? {
+        Some(role) => role,
+        None => return Err(ErrorNotFound("Channel not found.")),
+    };
+    if !role.can(permission) {
+        return Err(ErrorForbidden("You do not have permission to do that in this channel."));
+    }
+
+    if let Some(target) = target {
+        if lookup(target).await?.is_some_and(|target_role| target_role >= role) {
+            return Err(ErrorForbidden("You can only do that to users with a lower role."));
+        }
+    }
+    Ok(role)
+}
+
+pub async fn register(
+    db: web::Data<Pool<Sqlite>>,
+    password_config: web::Data<PasswordConfig>,
+    form: web::Json<RegisterRequest>,
+) -> impl Responder {
+    let hashed_password: String = match hash_password(&password_config, &form.password).await {
+        Ok(hash) => hash,
+        Err(e) => {
+            println!("Error hashing password: {}", e);
+            return HttpResponse::InternalServerError().json("Failed to register user.");
+        }
+    };
 
     let result: Result<sqlx::sqlite::SqliteQueryResult, sqlx::Error> = sqlx::query("INSERT INTO Users (username, password) VALUES (?, ?)")
         .bind(&form.username)
@@ -59,8 +241,15 @@ pub async fn register(db: web::Data<Pool<Sqlite>>, form: web::Json<RegisterReque
     }
 }
 
-pub async fn login(db: web::Data<Pool<Sqlite>>, session: Session, form: web::Json<LoginRequest>) -> impl Responder {
-    if check_auth(&session).is_ok() {
+pub async fn login(
+    req: HttpRequest,
+    db: web::Data<Pool<Sqlite>>,
+    password_config: web::Data<PasswordConfig>,
+    jwt_config: web::Data<JwtConfig>,
+    session: Session,
+    form: web::Json<LoginRequest>,
+) -> impl Responder {
+    if form.mode == LoginMode::Cookie && check_session_auth(&session).is_ok() {
         return HttpResponse::BadRequest().body("Already logged in.");
     }
 
@@ -76,33 +265,186 @@ pub async fn login(db: web::Data<Pool<Sqlite>>, session: Session, form: web::Jso
         Ok(None) => return HttpResponse::Unauthorized().json("Invalid username or password.")
     };
 
-    if bcrypt::verify(&form.password, &user.1) {
-        let id_set = session.insert("user_id", &user.0);
-        let username_set = session.insert("user_username", &form.username);
-
-        if id_set.is_ok() && username_set.is_ok() {
-            session.renew();
-            HttpResponse::Ok().json(format!("Login successful, {}", &form.username))
-        } else {
-            HttpResponse::InternalServerError().body("Error setting session data")
+    match verify_password(&password_config, &form.password, &user.1).await {
+        Ok(Verification::Invalid) => return HttpResponse::Unauthorized().json("Invalid username or password."),
+        Ok(Verification::Valid) => {}
+        Ok(Verification::ValidNeedsRehash) => rehash_password(&db, &password_config, user.0, &form.password, &user.1).await,
+        Err(e) => {
+            println!("Error checking password: {}", e);
+            return HttpResponse::InternalServerError().json("Failed to log in.");
         }
+    }
+
+    if form.mode == LoginMode::Jwt {
+        return match issue_tokens(&db, &jwt_config, user.0, &form.username, None).await {
+            Ok(tokens) => HttpResponse::Ok().json(tokens),
+            Err(e) => {
+                println!("Error issuing tokens: {}", e);
+                HttpResponse::InternalServerError().json("Failed to issue tokens.")
+            }
+        };
+    }
+
+    let id_set = session.insert("user_id", user.0);
+    let username_set = session.insert("user_username", &form.username);
+    // Public ID of the session, and what it was opened with, for `/user/sessions`
+    let session_id_set = session.insert("session_id", Uuid::new_v4().to_string());
+    let user_agent = req.headers().get(actix_web::http::header::USER_AGENT).and_then(|value| value.to_str().ok());
+    let user_agent_set = session.insert("user_agent", user_agent);
+
+    if id_set.is_ok() && username_set.is_ok() && session_id_set.is_ok() && user_agent_set.is_ok() {
+        session.renew();
+        HttpResponse::Ok().json(format!("Login successful, {}", &form.username))
     } else {
-        HttpResponse::Unauthorized().json("Invalid username or password.")
+        HttpResponse::InternalServerError().body("Error setting session data")
     }
 }
 
-pub async fn logout(session: Session) -> impl Responder {
+/// Replace a user's bcrypt or outdated Argon2 hash now that we know their password.
+/// Failing only means it is tried again on the next login.
+async fn rehash_password(db: &Pool<Sqlite>, password_config: &PasswordConfig, user_id: u32, password: &str, old_hash: &str) {
+    let hash = match hash_password(password_config, password).await {
+        Ok(hash) => hash,
+        Err(e) => {
+            println!("Error hashing password: {}", e);
+            return;
+        }
+    };
+    // Leave the hash alone if the password was changed in the meantime
+    let result = sqlx::query("UPDATE Users SET Password = ? WHERE id = ? AND Password = ?")
+        .bind(&hash)
+        .bind(user_id)
+        .bind(old_hash)
+        .execute(db)
+        .await;
+    if let Err(e) = result {
+        println!("Error updating password hash: {}", e);
+    }
+}
+
+pub async fn logout(session: Session, chat_state: web::Data<Arc<ChatState>>) -> impl Responder {
     // println!("{:?}", session.entries());
-    if check_auth(&session).is_err() {
+    if check_session_auth(&session).is_err() {
         return HttpResponse::NotFound().body("No user logged in.");
     }
-    session.clear();
+    if let Some(session_id) = current_session_id(&session) {
+        chat_state.end_sessions(&[session_id], "You have logged out.");
+    }
+    // Removes the session from the store, so the cookie stops working even if it was copied
+    session.purge();
     HttpResponse::Ok().json("Logout successful")
 }
 
-pub async fn user_status(sled_db: web::Data<sled::Db>, info: web::Path<StatusRequest>) -> impl Responder {
-    let channel_name = &info.name;
-    match get_user_status_sled(&sled_db, channel_name) {
+/// Change the password of the user logged in with a session or JWT. Every other session
+/// of the user is logged out, and every JWT access and refresh token is revoked.
+pub async fn change_password(
+    req: HttpRequest,
+    db: web::Data<Pool<Sqlite>>,
+    password_config: web::Data<PasswordConfig>,
+    store: web::Data<SqliteSessionStore>,
+    chat_state: web::Data<Arc<ChatState>>,
+    session: Session,
+    form: web::Json<PasswordRequest>,
+) -> impl Responder {
+    let credential = req.extensions().get::<TokenUser>().map(|user| user.credential.clone());
+    if let Some(Credential::ApiToken(_)) = credential {
+        return HttpResponse::Forbidden().json("API tokens can't change the password.");
+    }
+    let (user_id, username) = match check_auth(&req) {
+        Ok(user) => user,
+        Err(_) => return HttpResponse::Unauthorized().json("User not logged in."),
+    };
+    if form.new_password.is_empty() {
+        return HttpResponse::BadRequest().json("New password is empty.");
+    }
+
+    let password: Result<Option<String>, sqlx::Error> = sqlx::query_scalar("SELECT Password FROM Users WHERE id = ?")
+        .bind(user_id)
+        .fetch_optional(db.get_ref())
+        .await;
+    let hash = match password {
+        Ok(Some(hash)) => hash,
+        Ok(None) => return HttpResponse::Unauthorized().json("Current password is incorrect."),
+        Err(e) => {
+            println!("Error querying database: {}", e);
+            return HttpResponse::InternalServerError().json("Failed to change password.");
+        }
+    };
+    match verify_password(&password_config, &form.current_password, &hash).await {
+        Ok(Verification::Invalid) => return HttpResponse::Unauthorized().json("Current password is incorrect."),
+        Ok(_) => {}
+        Err(e) => {
+            println!("Error checking password: {}", e);
+            return HttpResponse::InternalServerError().json("Failed to change password.");
+        }
+    }
+
+    let hashed_password: String = match hash_password(&password_config, &form.new_password).await {
+        Ok(hash) => hash,
+        Err(e) => {
+            println!("Error hashing password: {}", e);
+            return HttpResponse::InternalServerError().json("Failed to change password.");
+        }
+    };
+    if let Err(e) = sqlx::query("UPDATE Users SET Password = ? WHERE id = ?")
+        .bind(&hashed_password)
+        .bind(user_id)
+        .execute(db.get_ref())
+        .await
+    {
+        println!("Error updating password: {}", e);
+        return HttpResponse::InternalServerError().json("Failed to change password.");
+    }
+
+    let revoked_before = match revoke_user_tokens(&db, user_id).await {
+        Ok(revoked_before) => revoked_before,
+        Err(e) => {
+            println!("Error revoking tokens: {}", e);
+            return HttpResponse::InternalServerError().json("Password changed, but other sessions could not be logged out.");
+        }
+    };
+    // Access tokens are revoked by their issue time in whole seconds, so revoke the one used here by its ID as well
+    if let Some(Credential::Jwt(claims)) = &credential {
+        if let Err(e) = revoke_access_token(&db, claims).await {
+            println!("Error revoking access token: {}", e);
+        }
+    }
+    chat_state.end_access_tokens(user_id, revoked_before, "Your password was changed, please log in again.");
+    let current = match credential {
+        None => current_session_id(&session),
+        Some(_) => None,
+    };
+    match store.revoke_all(user_id, current.as_deref()).await {
+        Ok(revoked) => {
+            chat_state.end_sessions(&revoked, "Your password was changed, please log in again.");
+            println!("User {} changed their password, {} other sessions revoked", username, revoked.len());
+        }
+        Err(e) => {
+            println!("Error revoking sessions: {}", e);
+            return HttpResponse::InternalServerError().json("Password changed, but other sessions could not be logged out.");
+        }
+    }
+    // A new key for the current session too, in case the old one leaked
+    if current.is_some() {
+        session.renew();
+    }
+    HttpResponse::Ok().json("Password changed.")
+}
+
+pub async fn user_status(
+    db: web::Data<Pool<Sqlite>>,
+    sled_db: web::Data<sled::Db>,
+    req: HttpRequest,
+    info: web::Path<StatusRequest>,
+) -> impl Responder {
+    if let Err(err) = check_channel_access(&db, &req, &info.name).await {
+        return err.error_response();
+    }
+    let channel = match resolve_channel(&db, &info.name).await {
+        Ok(channel) => channel,
+        Err(err) => return err.error_response(),
+    };
+    match get_user_status_sled(&sled_db, &channel.key) {
         Ok(user_statuses) => HttpResponse::Ok().json(user_statuses),
         Err(e) => HttpResponse::InternalServerError().body(format!("Internal server error: {}", e)),
     }
@@ -1,16 +1,25 @@
 use actix::prelude::*;
-use actix_web::{web, Error, HttpRequest, HttpResponse, Result};
+use actix_web::{web, Error, HttpMessage, HttpRequest, HttpResponse, Result};
 use actix_web_actors::ws;
-use chrono::Utc;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use actix_session::SessionExt;
 use crate::Sqlite;
 use crate::Pool;
 use crate::user;
+use crate::session;
+use crate::config::JwtConfig;
+use crate::token::{self, Credential, TokenUser};
+use crate::message;
+use crate::member;
+use crate::channel::{self, ChannelInfo, ChannelRef};
 use crate::database::append_chat_message_sled;
+use crate::search::index_message;
 use crate::database::append_user_status_sled;
+use crate::database::get_user_status_sled;
+use crate::protocol::{ClientEvent, Envelope, ServerEvent, PROTOCOL_VERSION};
 use std::collections::HashMap;
+use serde::Deserialize;
 
 #[derive(Message)]
 #[rtype(result = "()")]
@@ -27,50 +36,322 @@ impl Handler<ChatMessage> for ChatSession {
     }
 }
 
+/// Close a session, or only the sessions of one user, with a final error event
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Disconnect {
+    username: Option<String>,
+    reason: String,
+}
+
+impl Handler<Disconnect> for ChatSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: Disconnect, ctx: &mut Self::Context) {
+        if msg.username.as_deref().is_some_and(|username| username != self.user_name) {
+            return;
+        }
+        self.send_event(ServerEvent::Error { message: msg.reason.clone() }, ctx);
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Policy,
+            description: Some(msg.reason),
+        }));
+        ctx.stop();
+    }
+}
+
+/// Close the connections opened through any of the given login sessions
+#[derive(Message)]
+#[rtype(result = "()")]
+struct EndSessions {
+    session_ids: Arc<Vec<String>>,
+    reason: String,
+}
+
+impl Handler<EndSessions> for ChatSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: EndSessions, ctx: &mut Self::Context) {
+        if self.session_id.as_ref().is_some_and(|id| msg.session_ids.contains(id)) {
+            ctx.notify(Disconnect { username: None, reason: msg.reason });
+        }
+    }
+}
+
+/// Close the connections opened with an API token
+#[derive(Message)]
+#[rtype(result = "()")]
+struct EndToken {
+    token_id: i64,
+    reason: String,
+}
+
+impl Handler<EndToken> for ChatSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: EndToken, ctx: &mut Self::Context) {
+        if self.token_id == Some(msg.token_id) {
+            ctx.notify(Disconnect { username: None, reason: msg.reason });
+        }
+    }
+}
+
+/// Close a user's connections opened with a JWT access token issued before a time
+#[derive(Message)]
+#[rtype(result = "()")]
+struct EndAccessTokens {
+    user_id: u32,
+    issued_before: i64,
+    reason: String,
+}
+
+impl Handler<EndAccessTokens> for ChatSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: EndAccessTokens, ctx: &mut Self::Context) {
+        if self.user_id == msg.user_id && self.jwt_issued_at.is_some_and(|iat| iat < msg.issued_before) {
+            ctx.notify(Disconnect { username: None, reason: msg.reason });
+        }
+    }
+}
+
+/// Move a session to a renamed channel and update whether it is read-only
+#[derive(Message)]
+#[rtype(result = "()")]
+struct ChannelChanged {
+    name: String,
+    archived: bool,
+}
+
+impl Handler<ChannelChanged> for ChatSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: ChannelChanged, _ctx: &mut Self::Context) {
+        self.channel.name = msg.name;
+        self.archived = msg.archived;
+    }
+}
+
+/// Tell a session its channel was deleted and close it
+#[derive(Message)]
+#[rtype(result = "()")]
+struct ChannelRemoved;
+
+impl Handler<ChannelRemoved> for ChatSession {
+    type Result = ();
+
+    fn handle(&mut self, _msg: ChannelRemoved, ctx: &mut Self::Context) {
+        self.deleted = true;
+        self.send_event(ServerEvent::ChannelDeleted { name: self.channel.name.clone() }, ctx);
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Away,
+            description: Some("Channel deleted".to_string()),
+        }));
+        ctx.stop();
+    }
+}
+
+/// Mute or unmute the sessions of one user
+#[derive(Message)]
+#[rtype(result = "()")]
+struct SetMuted {
+    username: String,
+    muted: bool,
+}
+
+impl Handler<SetMuted> for ChatSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetMuted, _ctx: &mut Self::Context) {
+        if msg.username == self.user_name {
+            self.muted = msg.muted;
+        }
+    }
+}
+
 /// Define interval for ping messages
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
 
-/// Shared state for storing messages and managing connected users
+/// Shared state for managing connected users
 // pub struct ChatState {
 //     pub messages: Mutex<Vec<(String, String, String)>>, // (timestamp, user, message)
 //     pub connected_users: Mutex<Vec<String>>,           // List of connected users
 //     pub sessions: Mutex<Vec<Addr<ChatSession>>>,
 // }
-
 pub struct ChatState {
-    pub messages: Mutex<Vec<(String, String, String)>>, // (timestamp, user, message)
     // pub connected_users: Mutex<Vec<String>>,           // List of connected users
     pub sessions: Mutex<HashMap<String, Vec<Addr<ChatSession>>>>, // Map of channel to sessions
 }
 
+impl ChatState {
+    /// Send an event to every session connected to a channel
+    pub fn broadcast(&self, channel_name: &str, event: &ServerEvent) {
+        self.broadcast_except(channel_name, event, None);
+    }
+
+    /// Close the sessions of `username` (or of everyone) connected to a channel
+    pub fn disconnect(&self, channel_name: &str, username: Option<&str>, reason: &str) {
+        if let Ok(sessions_map) = self.sessions.lock() {
+            if let Some(sessions) = sessions_map.get(channel_name) {
+                for session in sessions {
+                    session.do_send(Disconnect {
+                        username: username.map(str::to_string),
+                        reason: reason.to_string(),
+                    });
+                }
+            }
+        }
+    }
+
+    /// Close the connections of revoked login sessions in every channel
+    pub fn end_sessions(&self, session_ids: &[String], reason: &str) {
+        if session_ids.is_empty() {
+            return;
+        }
+        let session_ids = Arc::new(session_ids.to_vec());
+        if let Ok(sessions_map) = self.sessions.lock() {
+            for session in sessions_map.values().flatten() {
+                session.do_send(EndSessions { session_ids: session_ids.clone(), reason: reason.to_string() });
+            }
+        }
+    }
+
+    /// Close the connections opened with a revoked API token in every channel
+    pub fn end_token(&self, token_id: i64, reason: &str) {
+        if let Ok(sessions_map) = self.sessions.lock() {
+            for session in sessions_map.values().flatten() {
+                session.do_send(EndToken { token_id, reason: reason.to_string() });
+            }
+        }
+    }
+
+    /// Close the connections a user opened with JWT access tokens issued before `issued_before`
+    pub fn end_access_tokens(&self, user_id: u32, issued_before: i64, reason: &str) {
+        if let Ok(sessions_map) = self.sessions.lock() {
+            for session in sessions_map.values().flatten() {
+                session.do_send(EndAccessTokens { user_id, issued_before, reason: reason.to_string() });
+            }
+        }
+    }
+
+    /// Apply new channel settings to the connected sessions, moving them over if the channel was renamed
+    pub fn update_channel(&self, channel_name: &str, info: &ChannelInfo) {
+        if let Ok(mut sessions_map) = self.sessions.lock() {
+            let sessions = match sessions_map.remove(channel_name) {
+                Some(sessions) => sessions,
+                None => return,
+            };
+            for session in &sessions {
+                session.do_send(ChannelChanged { name: info.name.clone(), archived: info.archived });
+            }
+            sessions_map.entry(info.name.clone()).or_default().extend(sessions);
+        }
+    }
+
+    /// Close every session of a deleted channel
+    pub async fn close_channel(&self, channel_name: &str) {
+        let sessions = match self.sessions.lock() {
+            Ok(mut sessions_map) => sessions_map.remove(channel_name).unwrap_or_default(),
+            Err(_) => return,
+        };
+        // Wait until each session has handled it, so none writes to the channel afterwards
+        for session in sessions {
+            let _ = session.send(ChannelRemoved).await;
+        }
+    }
+
+    /// Update whether `username` may post in a channel for their open sessions
+    pub fn set_muted(&self, channel_name: &str, username: &str, muted: bool) {
+        if let Ok(sessions_map) = self.sessions.lock() {
+            if let Some(sessions) = sessions_map.get(channel_name) {
+                for session in sessions {
+                    session.do_send(SetMuted { username: username.to_string(), muted });
+                }
+            }
+        }
+    }
+
+    fn broadcast_except(&self, channel_name: &str, event: &ServerEvent, except: Option<&Addr<ChatSession>>) {
+        let msg = event.to_frame();
+
+        if let Ok(sessions_map) = self.sessions.lock() {
+            if let Some(sessions) = sessions_map.get(channel_name) {
+                for session in sessions {
+                    if Some(session) == except {
+                        continue;
+                    }
+                    session.do_send(ChatMessage { msg: msg.clone() });
+                }
+            }
+        }
+    }
+}
+
 /// Define the WebSocket connection structure
 pub struct ChatSession {
     hb: Instant,              // Client's last heartbeat
+    user_id: u32,             // ID of the user
     user_name: String,        // Name of the user
-    channel_name: String,     // Channel name
+    session_id: Option<String>, // Login session the connection was opened with
+    token_id: Option<i64>,    // API token the connection was opened with
+    jwt_issued_at: Option<i64>, // Issue time of the JWT access token the connection was opened with
+    channel: ChannelRef,      // Channel name and storage key
     state: Arc<ChatState>,    // Shared state across sessions
     sled_db: web::Data<sled::Db>, // Sled database instance
+    sqlite_db: web::Data<Pool<Sqlite>>, // SQLite database instance
+    muted: bool,              // Muted users can read but not post
+    archived: bool,           // Archived channels are read-only
+    deleted: bool,            // The channel is gone, so leave nothing behind when stopping
 }
 
 
 impl ChatSession {
     /// Create a new instance of the chat session
     pub fn new(
-        user_name: String,
-        channel_name: String,
+        (user_id, user_name): (u32, String),
+        channel: ChannelRef,
         state: Arc<ChatState>,
         sled_db: web::Data<sled::Db>,
+        sqlite_db: web::Data<Pool<Sqlite>>,
+        muted: bool,
+        archived: bool,
     ) -> Self {
         Self {
             hb: Instant::now(),
+            user_id,
             user_name,
-            channel_name,
+            session_id: None,
+            token_id: None,
+            jwt_issued_at: None,
+            channel,
             state,
             sled_db,
+            sqlite_db,
+            muted,
+            archived,
+            deleted: false,
         }
     }
 
+    /// Tie the connection to a login session, so revoking it closes the connection
+    pub fn with_session_id(mut self, session_id: Option<String>) -> Self {
+        self.session_id = session_id;
+        self
+    }
+
+    /// Tie the connection to an API token, so revoking it closes the connection
+    pub fn with_token_id(mut self, token_id: Option<i64>) -> Self {
+        self.token_id = token_id;
+        self
+    }
+
+    /// Tie the connection to a JWT access token, so revoking the user's tokens closes the connection
+    pub fn with_jwt_issued_at(mut self, issued_at: Option<i64>) -> Self {
+        self.jwt_issued_at = issued_at;
+        self
+    }
+
     /// Handle the WebSocket heartbeat
     fn hb(&self, ctx: &mut ws::WebsocketContext<Self>) {
         ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
@@ -86,35 +367,181 @@ impl ChatSession {
         });
     }
 
-    /// Broadcast a message to all connected clients
-    // fn broadcast_message(&self, message: &str, _ctx: &mut ws::WebsocketContext<Self>) {
-    //     // Format the message
-    //     let msg = format!("{}: {}", self.user_name, message);
-        
-    //     // Get all sessions and broadcast to them
-    //     if let Ok(sessions) = self.state.sessions.lock() {
-    //         for session in sessions.iter() {
-    //             session.do_send(ChatMessage { msg: msg.clone() });
-    //         }
-    //     }
-    // }
+    /// Send an event to this client only
+    fn send_event(&self, event: ServerEvent, ctx: &mut ws::WebsocketContext<Self>) {
+        ctx.text(event.to_frame());
+    }
 
-    fn broadcast_message(&self, message: &str, _ctx: &mut ws::WebsocketContext<Self>) {
-        let msg = format!("{}: {}", self.user_name.trim(), message.trim());
-        
-        // Get sessions for the current channel
-        if let Ok(sessions_map) = self.state.sessions.lock() {
-            if let Some(sessions) = sessions_map.get(&self.channel_name) {
-                for session in sessions {
-                    session.do_send(ChatMessage { msg: msg.clone() });
-                    // println!("Broadcasting message: {}", msg);
+    /// Broadcast an event to all clients connected to the current channel,
+    /// optionally skipping one session (e.g. the sender of a typing event)
+    fn broadcast_event(&self, event: ServerEvent, except: Option<&Addr<ChatSession>>) {
+        self.state.broadcast_except(&self.channel.name, &event, except);
+    }
+
+    /// Add or remove the user's reaction to a message
+    fn react(&self, id: String, emoji: String, add: bool, ctx: &mut ws::WebsocketContext<Self>) {
+        let sqlite_db = self.sqlite_db.clone();
+        let sled_db = self.sled_db.clone();
+        let state = self.state.clone();
+        let channel = self.channel.clone();
+        let user_name = self.user_name.clone();
+
+        let fut = async move {
+            message::react_to_message(&sqlite_db, &sled_db, &state, &channel, &user_name, &id, &emoji, add).await
+        };
+        ctx.spawn(fut.into_actor(self).map(|result, act, ctx| {
+            if let Err(err) = result {
+                act.send_event(ServerEvent::Error { message: err.to_string() }, ctx);
+            }
+        }));
+    }
+
+    /// Handle a decoded event sent by the client
+    fn handle_client_event(&mut self, event: ClientEvent, ctx: &mut ws::WebsocketContext<Self>) {
+        let changes_messages = matches!(event,
+            ClientEvent::Message { .. } | ClientEvent::Edit { .. } | ClientEvent::Delete { .. }
+                | ClientEvent::AddReaction { .. } | ClientEvent::RemoveReaction { .. });
+        if self.archived && changes_messages {
+            self.send_event(ServerEvent::Error { message: "This channel is archived and read-only.".to_string() }, ctx);
+            return;
+        }
+        if self.muted && changes_messages {
+            self.send_event(ServerEvent::Error { message: "You are muted in this channel.".to_string() }, ctx);
+            return;
+        }
+
+        match event {
+            ClientEvent::Message { message, client_id, reply_to } => {
+                let message = message.trim().to_string();
+                if message.is_empty() {
+                    self.send_event(ServerEvent::Error { message: "Message is empty.".to_string() }, ctx);
+                    return;
                 }
+
+                let reply_to = match reply_to {
+                    Some(parent_id) => match message::resolve_thread_root(&self.sled_db, &self.channel, &parent_id) {
+                        Ok(root_id) => Some(root_id),
+                        Err(err) => {
+                            self.send_event(ServerEvent::Error { message: err.to_string() }, ctx);
+                            return;
+                        }
+                    },
+                    None => None,
+                };
+
+                // Append the message to the Sled database using `self.sled_db`,
+                // which assigns its ID and timestamp
+                let chat_message = match append_chat_message_sled(
+                    &self.sled_db,
+                    &self.channel.key,
+                    self.user_id,
+                    self.user_name.trim(),
+                    &message,
+                    reply_to.as_deref(),
+                ) {
+                    Ok(chat_message) => chat_message,
+                    Err(err) => {
+                        println!("Failed to store chat message in Sled: {}", err);
+                        self.send_event(ServerEvent::Error { message: "Failed to store message.".to_string() }, ctx);
+                        return;
+                    }
+                };
+
+                // Make the message searchable before anyone learns its ID, so an edit's
+                // re-index can't be overwritten by this one. `wait` holds back this
+                // connection's next frames until then.
+                let sqlite_db = self.sqlite_db.clone();
+                let channel_key = self.channel.key.clone();
+                let indexed = chat_message.clone();
+                let fut = async move { index_message(&sqlite_db, &channel_key, &indexed).await };
+                ctx.wait(fut.into_actor(self).map(move |result, act, ctx| {
+                    if let Err(e) = result {
+                        println!("Failed to index chat message: {}", e);
+                    }
+
+                    act.send_event(ServerEvent::Ack {
+                        client_id,
+                        id: chat_message.id.clone(),
+                        timestamp: chat_message.timestamp.clone(),
+                    }, ctx);
+
+                    // Broadcast the message to all clients
+                    act.broadcast_event(ServerEvent::Message(chat_message), None);
+                }));
+            }
+            ClientEvent::Edit { id, message } => {
+                let sqlite_db = self.sqlite_db.clone();
+                let sled_db = self.sled_db.clone();
+                let state = self.state.clone();
+                let channel = self.channel.clone();
+                let (user_id, user_name) = (self.user_id, self.user_name.clone());
+
+                let fut = async move {
+                    message::edit_message(&sqlite_db, &sled_db, &state, &channel, (user_id, &user_name), &id, &message).await
+                };
+                ctx.spawn(fut.into_actor(self).map(|result, act, ctx| {
+                    if let Err(err) = result {
+                        act.send_event(ServerEvent::Error { message: err.to_string() }, ctx);
+                    }
+                }));
+            }
+            ClientEvent::Delete { id } => {
+                let sqlite_db = self.sqlite_db.clone();
+                let sled_db = self.sled_db.clone();
+                let state = self.state.clone();
+                let channel = self.channel.clone();
+                let (user_id, user_name) = (self.user_id, self.user_name.clone());
+
+                let fut = async move {
+                    message::delete_message(&sqlite_db, &sled_db, &state, &channel, (user_id, &user_name), &id).await
+                };
+                ctx.spawn(fut.into_actor(self).map(|result, act, ctx| {
+                    if let Err(err) = result {
+                        act.send_event(ServerEvent::Error { message: err.to_string() }, ctx);
+                    }
+                }));
+            }
+            ClientEvent::AddReaction { id, emoji } => self.react(id, emoji, true, ctx),
+            ClientEvent::RemoveReaction { id, emoji } => self.react(id, emoji, false, ctx),
+            ClientEvent::Kick { username } => {
+                let sqlite_db = self.sqlite_db.clone();
+                let state = self.state.clone();
+                let channel_name = self.channel.name.clone();
+                let user_name = self.user_name.clone();
+
+                let fut = async move {
+                    member::kick_member(&sqlite_db, &state, &channel_name, &user_name, &username).await
+                };
+                ctx.spawn(fut.into_actor(self).map(|result, act, ctx| {
+                    if let Err(err) = result {
+                        act.send_event(ServerEvent::Error { message: err.to_string() }, ctx);
+                    }
+                }));
+            }
+            ClientEvent::Mute { username, muted } => {
+                let sqlite_db = self.sqlite_db.clone();
+                let state = self.state.clone();
+                let channel_name = self.channel.name.clone();
+                let user_name = self.user_name.clone();
+
+                let fut = async move {
+                    member::set_muted(&sqlite_db, &state, &channel_name, &user_name, &username, muted).await
+                };
+                ctx.spawn(fut.into_actor(self).map(|result, act, ctx| {
+                    if let Err(err) = result {
+                        act.send_event(ServerEvent::Error { message: err.to_string() }, ctx);
+                    }
+                }));
+            }
+            ClientEvent::Typing => {
+                self.broadcast_event(ServerEvent::Typing { username: self.user_name.clone() }, Some(&ctx.address()));
+            }
+            ClientEvent::Ping => {
+                self.hb = Instant::now();
+                self.send_event(ServerEvent::Pong, ctx);
             }
         }
     }
-    
-    
-    
 }
 
 /// WebSocket message handler implementation for `ChatSession`
@@ -161,43 +588,50 @@ impl Actor for ChatSession {
     
         if let Ok(mut sessions_map) = self.state.sessions.lock() {
             sessions_map
-                .entry(self.channel_name.clone())
+                .entry(self.channel.name.clone())
                 .or_insert_with(Vec::new)
                 .push(ctx.address());
         }
     
-        let join_message = format!("{} joined the chat", self.user_name);
         if let Err(err) = append_user_status_sled(
             &self.sled_db,
-            &self.channel_name, 
+            &self.channel.key,
             &self.user_name,
             true
         ) {
             println!("Failed to store user status in Sled: {}", err);
         }
-        self.broadcast_message(&join_message, ctx);
+
+        // Give the new client a snapshot of the channel's presence list
+        match get_user_status_sled(&self.sled_db, &self.channel.key) {
+            Ok(users) => self.send_event(ServerEvent::Presence { users }, ctx),
+            Err(err) => println!("Failed to read user status from Sled: {}", err),
+        }
+        self.broadcast_event(ServerEvent::Join { username: self.user_name.clone() }, None);
     }
     
     fn stopped(&mut self, ctx: &mut Self::Context) {
         if let Ok(mut sessions_map) = self.state.sessions.lock() {
-            if let Some(sessions) = sessions_map.get_mut(&self.channel_name) {
+            if let Some(sessions) = sessions_map.get_mut(&self.channel.name) {
                 sessions.retain(|addr| addr != &ctx.address());
                 if sessions.is_empty() {
-                    sessions_map.remove(&self.channel_name);
+                    sessions_map.remove(&self.channel.name);
                 }
             }
         }
+        if self.deleted {
+            return;
+        }
     
-        let quit_message = format!("{} left the chat", self.user_name);
         if let Err(err) = append_user_status_sled(
             &self.sled_db,
-            &self.channel_name, 
+            &self.channel.key,
             &self.user_name,
             false
         ) {
             println!("Failed to store user status in Sled: {}", err);
         }
-        self.broadcast_message(&quit_message, ctx);
+        self.broadcast_event(ServerEvent::Leave { username: self.user_name.clone() }, None);
     }
     
 }
@@ -214,26 +648,17 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ChatSession {
                 self.hb = Instant::now();
             }
             Ok(ws::Message::Text(text)) => {
-                let timestamp = Utc::now().to_rfc3339();
-
-                // Store the message in the shared state
-                {
-                    let mut messages = self.state.messages.lock().unwrap();
-                    messages.push((timestamp.clone(), self.user_name.clone(), text.to_string()));
+                match serde_json::from_str::<Envelope<ClientEvent>>(&text) {
+                    Ok(envelope) if envelope.v != PROTOCOL_VERSION => {
+                        self.send_event(ServerEvent::Error {
+                            message: format!("Unsupported protocol version {}, expected {}", envelope.v, PROTOCOL_VERSION),
+                        }, ctx);
+                    }
+                    Ok(envelope) => self.handle_client_event(envelope.event, ctx),
+                    Err(err) => {
+                        self.send_event(ServerEvent::Error { message: format!("Invalid frame: {}", err) }, ctx);
+                    }
                 }
-
-                // Append the message to the Sled database using `self.sled_db`
-                if let Err(err) = append_chat_message_sled(
-                    &self.sled_db, // Access sled_db from the struct
-                    &self.channel_name, 
-                    &self.user_name,
-                    &text,
-                ) {
-                    println!("Failed to store chat message in Sled: {}", err);
-                }
-
-                // Broadcast the message to all clients
-                self.broadcast_message(&text, ctx);
             }
 
             Ok(ws::Message::Binary(bin)) => {
@@ -249,6 +674,12 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ChatSession {
 }
 
 
+/// Parameters of the WebSocket upgrade request
+#[derive(Deserialize)]
+pub struct WsQuery {
+    access_token: Option<String>,
+}
+
 /// WebSocket handler function
 pub async fn chat_route(
     req: HttpRequest,
@@ -258,48 +689,91 @@ pub async fn chat_route(
     channel_name: web::Path<String>,
 ) -> Result<HttpResponse, Error> {
     // println!("WebSocket connection attempt for channel: {}", channel_name);
-    // Get session from request
-    let session = req.get_session();
-    
-    // Check authentication
-    let (_user_id, username) = match user::check_auth(&session) {
-        Ok((id, name)) => (id, name),
-        Err(_) => return Ok(HttpResponse::Unauthorized().finish()),
-    };
-
     // Get database connection from app data
     let db = match req.app_data::<web::Data<Pool<Sqlite>>>() {
         Some(db) => db,
         None => return Ok(HttpResponse::InternalServerError().finish()),
     };
 
+    let jwt_config = match req.app_data::<web::Data<JwtConfig>>() {
+        Some(jwt_config) => jwt_config,
+        None => return Ok(HttpResponse::InternalServerError().finish()),
+    };
+
+    // Check authentication. Clients that can't set headers on the upgrade request, like
+    // browsers, may pass an API token or JWT as `access_token` in the query string.
+    let query_token = web::Query::<WsQuery>::from_query(req.query_string())
+        .ok()
+        .and_then(|query| query.into_inner().access_token);
+    let token_user = match query_token {
+        Some(token) => match token::authenticate_bearer(db, jwt_config, &token).await {
+            Ok(Some(user)) => Some(user),
+            Ok(None) => return Ok(HttpResponse::Unauthorized().finish()),
+            Err(e) => {
+                println!("Database error: {}", e);
+                return Ok(HttpResponse::InternalServerError().finish());
+            }
+        },
+        None => req.extensions().get::<TokenUser>().cloned(),
+    };
+    let (user_id, username) = match &token_user {
+        Some(user) => (user.user_id, user.username.clone()),
+        None => match user::check_auth(&req) {
+            Ok((id, name)) => (id, name),
+            Err(_) => return Ok(HttpResponse::Unauthorized().finish()),
+        },
+    };
+    // Remember what the connection was opened with, so revoking it closes the connection
+    let (session_id, token_id, jwt_issued_at) = match &token_user {
+        Some(TokenUser { credential: Credential::ApiToken(token_id), .. }) => (None, Some(*token_id), None),
+        Some(TokenUser { credential: Credential::Jwt(claims), .. }) => (None, None, Some(claims.iat)),
+        None => (session::current_session_id(&req.get_session()), None, None),
+    };
+
     // Get database reference first
     let db_ref = db.get_ref();
 
-    // Execute query and handle the result
-    let channel_name_ref = channel_name.as_ref();
-    let query = sqlx::query("SELECT * FROM Channel WHERE Name = ?").bind(channel_name_ref);
-    let channel_exists = query.fetch_optional(db_ref).await.map_err(|e| {
+    // Check that the channel exists and the user may join it: private channels
+    // only admit members and direct conversations their two participants
+    let access = member::can_access_channel(db_ref, &channel_name, &username).await.map_err(|e| {
         println!("Database error: {}", e);
         actix_web::error::ErrorInternalServerError(e)
     })?;
-
-    // Check if channel exists
-    if channel_exists.is_none() {
-        println!("Channel not found: {}", channel_name);
-        return Ok(HttpResponse::NotFound().finish());
+    match access {
+        Some(true) => {}
+        Some(false) => {
+            println!("User {} may not join channel {}", username, channel_name);
+            return Ok(HttpResponse::Forbidden().finish());
+        }
+        None => {
+            println!("Channel not found: {}", channel_name);
+            return Ok(HttpResponse::NotFound().finish());
+        }
     }
 
+    let channel = channel::resolve_channel(db_ref, &channel_name).await?;
+    let muted = member::is_muted(db_ref, &channel_name, &username).await.map_err(|e| {
+        println!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError(e)
+    })?;
+    let archived = channel::is_archived(db_ref, &channel_name).await.map_err(|e| {
+        println!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError(e)
+    })?;
+
     // Start WebSocket connection
     println!("Starting WebSocket connection for user {} in channel {}", username, channel_name);
     
     let resp = ws::start(
         ChatSession::new(
-            username,
-            channel_name.to_string(),
+            (user_id, username),
+            channel,
             state.get_ref().clone(),
             sled_db.clone(),
-        ),
+            db.clone(),
+            muted,
+            archived,
+        ).with_session_id(session_id).with_token_id(token_id).with_jwt_issued_at(jwt_issued_at),
         &req,
         stream,
     )?;